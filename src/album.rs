@@ -0,0 +1,376 @@
+//! Albums, which group images into an ordered collection with its own title, cover and visibility.
+
+use chrono::Utc;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    images::{self, Image, Visibility},
+    Database,
+};
+
+/// Separates the owner from the album ID in `user_albums` keys.  Usernames can't contain it, so one user's keys never
+/// overlap another's.
+const KEY_SEPARATOR: char = '\0';
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Album {
+    pub id: String,
+    pub owner: String,
+    pub title: String,
+    pub description: String,
+    pub cover_image_id: Option<String>,
+    pub visibility: Visibility,
+    /// In display order.
+    pub image_ids: Vec<String>,
+    /// Unix timestamp.
+    pub created: i64,
+    /// Unix timestamp.
+    pub updated: i64,
+}
+
+impl Album {
+    /// Whether an album can be shown to a viewer, who is `None` if not logged in.
+    pub fn is_viewable_by(&self, viewer: Option<&str>) -> bool {
+        self.visibility != Visibility::Private || viewer == Some(self.owner.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewAlbum {
+    title: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    visibility: Visibility,
+    cover_image_id: Option<String>,
+}
+
+/// Changes to an album's details.  Fields that aren't set are left as they are.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumUpdate {
+    title: Option<String>,
+    description: Option<String>,
+    visibility: Option<Visibility>,
+    cover_image_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumImageAddition {
+    image_id: String,
+    /// Where to insert the image.  Appended to the end if not set.
+    position: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumOrder {
+    image_ids: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum AlbumError {
+    #[error("Could not find album")]
+    UnknownAlbum,
+    #[error("Could not find image")]
+    UnknownImage,
+    #[error("Image is already in the album")]
+    DuplicateImage,
+    #[error("Image is not in the album")]
+    ImageNotInAlbum,
+    #[error("The new order must contain exactly the images already in the album")]
+    InvalidOrder,
+}
+
+pub fn create_album(owner: &str, new_album: NewAlbum, db: &Database) -> anyhow::Result<Album> {
+    let now = Utc::now().timestamp();
+    let album = Album {
+        id: nanoid!(11),
+        owner: owner.to_string(),
+        title: new_album.title,
+        description: new_album.description,
+        cover_image_id: None,
+        visibility: new_album.visibility,
+        image_ids: vec![],
+        created: now,
+        updated: now,
+    };
+
+    // Covers have to be in the album, so one given up front is added as the first image.
+    let album = match new_album.cover_image_id {
+        Some(cover_image_id) => {
+            check_image(&cover_image_id, owner, db)?;
+            Album {
+                cover_image_id: Some(cover_image_id.clone()),
+                image_ids: vec![cover_image_id],
+                ..album
+            }
+        }
+        None => album,
+    };
+
+    save_album(&album, db)?;
+    db.user_albums.insert(
+        user_album_key(owner, &album.id).as_bytes(),
+        album.id.clone(),
+    )?;
+
+    Ok(album)
+}
+
+/// Gets an album, if the viewer is allowed to see it.
+pub fn get_album(id: &str, viewer: Option<&str>, db: &Database) -> anyhow::Result<Album> {
+    Ok(db
+        .albums
+        .get(id.as_bytes())?
+        .filter(|album| album.is_viewable_by(viewer))
+        .ok_or(AlbumError::UnknownAlbum)?)
+}
+
+/// Gets the images in an album in order, leaving out any the viewer isn't allowed to see, and showing previews of
+/// any they haven't bought.
+pub fn album_images(
+    album: &Album,
+    viewer: Option<&str>,
+    db: &Database,
+) -> anyhow::Result<Vec<Image>> {
+    let mut images = vec![];
+
+    for image_id in &album.image_ids {
+        if let Some(image) = db.images.get(image_id.as_bytes())? {
+            if image.is_viewable_by(viewer) {
+                images.push(image);
+            }
+        }
+    }
+
+    Ok(images::protect_originals(images, viewer))
+}
+
+/// Lists a user's albums, newest first.
+pub fn user_albums(owner: &str, db: &Database) -> anyhow::Result<Vec<Album>> {
+    let mut albums = vec![];
+
+    for entry in db
+        .user_albums
+        .scan_prefix(user_album_key(owner, "").as_bytes())
+    {
+        let (_, id) = entry?;
+        if let Some(album) = db.albums.get(id.as_bytes())? {
+            albums.push(album);
+        }
+    }
+
+    albums.sort_by(|a, b| b.created.cmp(&a.created));
+
+    Ok(albums)
+}
+
+pub fn update_album(
+    id: &str,
+    owner: &str,
+    update: AlbumUpdate,
+    db: &Database,
+) -> anyhow::Result<Album> {
+    let mut album = owned_album(id, owner, db)?;
+
+    if let Some(title) = update.title {
+        album.title = title;
+    }
+    if let Some(description) = update.description {
+        album.description = description;
+    }
+    if let Some(visibility) = update.visibility {
+        album.visibility = visibility;
+    }
+    if let Some(cover_image_id) = update.cover_image_id {
+        if !album.image_ids.contains(&cover_image_id) {
+            Err(AlbumError::ImageNotInAlbum)?
+        }
+        album.cover_image_id = Some(cover_image_id);
+    }
+
+    album.updated = Utc::now().timestamp();
+    save_album(&album, db)?;
+
+    Ok(album)
+}
+
+pub fn delete_album(id: &str, owner: &str, db: &Database) -> anyhow::Result<()> {
+    let album = owned_album(id, owner, db)?;
+
+    db.albums.remove(id.as_bytes())?;
+    db.user_albums
+        .remove(user_album_key(owner, id).as_bytes())?;
+    for image_id in &album.image_ids {
+        update_image_albums(image_id, db, |album_ids| {
+            album_ids.retain(|album_id| album_id != id)
+        })?;
+    }
+
+    Ok(())
+}
+
+pub fn add_album_image(
+    id: &str,
+    owner: &str,
+    addition: AlbumImageAddition,
+    db: &Database,
+) -> anyhow::Result<Album> {
+    let mut album = owned_album(id, owner, db)?;
+
+    if album.image_ids.contains(&addition.image_id) {
+        Err(AlbumError::DuplicateImage)?
+    }
+    check_image(&addition.image_id, owner, db)?;
+
+    let position = addition
+        .position
+        .unwrap_or(album.image_ids.len())
+        .min(album.image_ids.len());
+    album.image_ids.insert(position, addition.image_id);
+
+    album.updated = Utc::now().timestamp();
+    save_album(&album, db)?;
+
+    Ok(album)
+}
+
+pub fn remove_album_image(
+    id: &str,
+    owner: &str,
+    image_id: &str,
+    db: &Database,
+) -> anyhow::Result<Album> {
+    let mut album = owned_album(id, owner, db)?;
+
+    if !album.image_ids.iter().any(|other| other == image_id) {
+        Err(AlbumError::ImageNotInAlbum)?
+    }
+    remove_image(&mut album, image_id);
+
+    album.updated = Utc::now().timestamp();
+    save_album(&album, db)?;
+    update_image_albums(image_id, db, |album_ids| {
+        album_ids.retain(|album_id| album_id != id)
+    })?;
+
+    Ok(album)
+}
+
+/// Reorders an album's images.  The new order has to contain every image in the album exactly once.
+pub fn reorder_album(
+    id: &str,
+    owner: &str,
+    order: AlbumOrder,
+    db: &Database,
+) -> anyhow::Result<Album> {
+    let mut album = owned_album(id, owner, db)?;
+
+    let mut current = album.image_ids.clone();
+    let mut new = order.image_ids.clone();
+    current.sort();
+    new.sort();
+    if current != new {
+        Err(AlbumError::InvalidOrder)?
+    }
+
+    album.image_ids = order.image_ids;
+    album.updated = Utc::now().timestamp();
+    save_album(&album, db)?;
+
+    Ok(album)
+}
+
+/// Deletes all of a user's albums, leaving the images in them alone.
+pub fn delete_user_albums(owner: &str, db: &Database) -> anyhow::Result<()> {
+    for album in user_albums(owner, db)? {
+        delete_album(&album.id, owner, db)?;
+    }
+
+    Ok(())
+}
+
+/// Removes a deleted image from every album it was in.
+pub fn remove_image_from_albums(image_id: &str, db: &Database) -> anyhow::Result<()> {
+    let album_ids = db
+        .image_albums
+        .remove(image_id.as_bytes())?
+        .unwrap_or_default();
+
+    for album_id in album_ids {
+        if let Some(mut album) = db.albums.get(album_id.as_bytes())? {
+            remove_image(&mut album, image_id);
+            db.albums.insert(album.id.as_bytes(), album)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn user_album_key(owner: &str, id: &str) -> String {
+    format!("{}{}{}", owner, KEY_SEPARATOR, id)
+}
+
+fn remove_image(album: &mut Album, image_id: &str) {
+    album.image_ids.retain(|other| other != image_id);
+    if album.cover_image_id.as_deref() == Some(image_id) {
+        album.cover_image_id = None;
+    }
+}
+
+fn owned_album(id: &str, owner: &str, db: &Database) -> anyhow::Result<Album> {
+    Ok(db
+        .albums
+        .get(id.as_bytes())?
+        .filter(|album| album.owner == owner)
+        .ok_or(AlbumError::UnknownAlbum)?)
+}
+
+/// Albums can hold the owner's images, and anyone else's that aren't private.
+fn check_image(image_id: &str, owner: &str, db: &Database) -> anyhow::Result<()> {
+    match db.images.get(image_id.as_bytes())? {
+        Some(image) if image.is_viewable_by(Some(owner)) => Ok(()),
+        _ => Err(AlbumError::UnknownImage)?,
+    }
+}
+
+/// Stores an album, and indexes it under each of its images so deleting an image can find it.
+fn save_album(album: &Album, db: &Database) -> anyhow::Result<()> {
+    db.albums.insert(album.id.as_bytes(), album.clone())?;
+
+    for image_id in &album.image_ids {
+        update_image_albums(image_id, db, |album_ids| {
+            if !album_ids.contains(&album.id) {
+                album_ids.push(album.id.clone());
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+fn update_image_albums<F>(image_id: &str, db: &Database, update: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut Vec<String>),
+{
+    let mut album_ids = db
+        .image_albums
+        .get(image_id.as_bytes())?
+        .unwrap_or_default();
+
+    update(&mut album_ids);
+
+    if album_ids.is_empty() {
+        db.image_albums.remove(image_id.as_bytes())?;
+    } else {
+        db.image_albums.insert(image_id.as_bytes(), album_ids)?;
+    }
+
+    Ok(())
+}
@@ -1,9 +1,43 @@
+pub mod account;
+pub mod album;
+pub mod comment;
+pub mod discount;
+pub mod favorite;
+pub mod feed;
+pub mod image;
+pub mod invite;
 pub mod login;
+pub mod market;
+pub mod oidc;
+pub mod password_reset;
+pub mod profile;
+pub mod quota;
 pub mod register;
 pub mod search;
+pub mod session;
+pub mod share;
+pub mod suspension;
+pub mod two_factor;
 pub mod upload;
 
+pub use account::*;
+pub use album::*;
+pub use comment::*;
+pub use discount::*;
+pub use favorite::*;
+pub use feed::*;
+pub use image::*;
+pub use invite::*;
 pub use login::*;
+pub use market::*;
+pub use oidc::*;
+pub use password_reset::*;
+pub use profile::*;
+pub use quota::*;
 pub use register::*;
 pub use search::*;
+pub use session::*;
+pub use share::*;
+pub use suspension::*;
+pub use two_factor::*;
 pub use upload::*;
@@ -0,0 +1,129 @@
+use rocket::{
+    http::{CookieJar, Status},
+    State,
+};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::{remove_session_cookies, set_session_cookies, Username},
+    consts::{ACCOUNT_POLICY, USER_DATABASE_CONFIG},
+    policy::PolicyError,
+    response::ApiResponse,
+    session::Device,
+    user::{change_password, delete_user, AccountDeletion, AccountError, PasswordChange},
+    Database,
+};
+
+/// Maps an error from changing or deleting an account to a response.
+pub(super) fn account_error_response(err: anyhow::Error) -> ApiResponse {
+    if let Some(err) = err.downcast_ref::<PolicyError>() {
+        return ApiResponse {
+            json: json!({
+                "message": err.to_string(),
+                "field": err.field()
+            }),
+            status: Status::BadRequest,
+        };
+    }
+
+    match err.downcast_ref::<AccountError>() {
+        Some(err) => ApiResponse {
+            json: json!({
+                "message": err.to_string()
+            }),
+            status: Status::BadRequest,
+        },
+        None => {
+            println!("Error while updating account: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to update account, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+#[post("/0/account/password", format = "json", data = "<change>")]
+pub fn password(
+    db: State<Database>,
+    change: Json<PasswordChange>,
+    user_id: Username,
+    device: Device,
+    cookies: &CookieJar<'_>,
+) -> ApiResponse {
+    match change_password(
+        &user_id.username,
+        change.0,
+        &device,
+        &USER_DATABASE_CONFIG,
+        &ACCOUNT_POLICY,
+        &db,
+    ) {
+        Ok(token) => {
+            set_session_cookies(cookies, &token);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Successfully changed password",
+                    "token": token
+                }),
+                status: Status::Ok,
+            }
+        }
+        Err(err) => account_error_response(err),
+    }
+}
+
+#[post("/0/account/password", rank = 2)]
+pub fn password_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/account", format = "json", data = "<deletion>")]
+pub async fn delete(
+    db: State<'_, Database>,
+    deletion: Json<AccountDeletion>,
+    user_id: Username,
+    s3_client: State<'_, rusoto_s3::S3Client>,
+    cookies: &CookieJar<'_>,
+) -> ApiResponse {
+    match delete_user(
+        &user_id.username,
+        deletion.0,
+        &USER_DATABASE_CONFIG,
+        &db,
+        &s3_client,
+    )
+    .await
+    {
+        Ok(_) => {
+            remove_session_cookies(cookies);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Successfully deleted account"
+                }),
+                status: Status::Ok,
+            }
+        }
+        Err(err) => account_error_response(err),
+    }
+}
+
+#[delete("/0/account", rank = 2)]
+pub fn delete_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
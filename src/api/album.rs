@@ -0,0 +1,233 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    album::{
+        add_album_image, album_images, create_album, delete_album, get_album, remove_album_image,
+        reorder_album, update_album, user_albums, Album, AlbumError, AlbumImageAddition,
+        AlbumOrder, AlbumUpdate, NewAlbum,
+    },
+    auth::Username,
+    response::ApiResponse,
+    Database,
+};
+
+/// Maps an error from managing an album to a response.
+fn album_error_response(err: anyhow::Error) -> ApiResponse {
+    match err.downcast_ref::<AlbumError>() {
+        Some(AlbumError::UnknownAlbum) => ApiResponse {
+            json: json!({
+                "message": AlbumError::UnknownAlbum.to_string()
+            }),
+            status: Status::NotFound,
+        },
+        Some(err) => ApiResponse {
+            json: json!({
+                "message": err.to_string()
+            }),
+            status: Status::BadRequest,
+        },
+        None => {
+            println!("Error while managing album: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to update album, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+fn album_response(message: &str, result: anyhow::Result<Album>) -> ApiResponse {
+    match result {
+        Ok(album) => ApiResponse {
+            json: json!({
+                "message": message,
+                "album": album
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => album_error_response(err),
+    }
+}
+
+#[post("/0/albums", format = "json", data = "<new_album>")]
+pub fn album_create(
+    db: State<Database>,
+    new_album: Json<NewAlbum>,
+    user_id: Username,
+) -> ApiResponse {
+    album_response(
+        "Successfully created album",
+        create_album(&user_id.username, new_album.0, &db),
+    )
+}
+
+#[post("/0/albums", rank = 2)]
+pub fn album_create_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Lists the logged in user's albums, including unlisted and private ones.
+#[get("/0/albums")]
+pub fn albums(db: State<Database>, user_id: Username) -> ApiResponse {
+    match user_albums(&user_id.username, &db) {
+        Ok(albums) => ApiResponse {
+            json: json!({ "albums": albums }),
+            status: Status::Ok,
+        },
+        Err(err) => album_error_response(err),
+    }
+}
+
+#[get("/0/albums", rank = 2)]
+pub fn albums_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Gets an album and its images.  Private albums are only shown to their owner, and private images in an album
+/// only to the images' owners.
+#[get("/0/albums/<id>")]
+pub fn album(db: State<Database>, id: String, user_id: Option<Username>) -> ApiResponse {
+    let viewer = user_id.map(|user_id| user_id.username);
+
+    match get_album(&id, viewer.as_deref(), &db)
+        .and_then(|album| Ok((album_images(&album, viewer.as_deref(), &db)?, album)))
+    {
+        Ok((images, album)) => ApiResponse {
+            json: json!({
+                "album": album,
+                "images": images
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => album_error_response(err),
+    }
+}
+
+#[patch("/0/albums/<id>", format = "json", data = "<update>")]
+pub fn album_update(
+    db: State<Database>,
+    id: String,
+    update: Json<AlbumUpdate>,
+    user_id: Username,
+) -> ApiResponse {
+    album_response(
+        "Successfully updated album",
+        update_album(&id, &user_id.username, update.0, &db),
+    )
+}
+
+#[patch("/0/albums/<_id>", rank = 2)]
+pub fn album_update_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/albums/<id>")]
+pub fn album_delete(db: State<Database>, id: String, user_id: Username) -> ApiResponse {
+    match delete_album(&id, &user_id.username, &db) {
+        Ok(_) => ApiResponse {
+            json: json!({
+                "message": "Successfully deleted album"
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => album_error_response(err),
+    }
+}
+
+#[delete("/0/albums/<_id>", rank = 2)]
+pub fn album_delete_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[post("/0/albums/<id>/images", format = "json", data = "<addition>")]
+pub fn album_add_image(
+    db: State<Database>,
+    id: String,
+    addition: Json<AlbumImageAddition>,
+    user_id: Username,
+) -> ApiResponse {
+    album_response(
+        "Successfully added image to album",
+        add_album_image(&id, &user_id.username, addition.0, &db),
+    )
+}
+
+#[post("/0/albums/<_id>/images", rank = 2)]
+pub fn album_add_image_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[put("/0/albums/<id>/images", format = "json", data = "<order>")]
+pub fn album_reorder(
+    db: State<Database>,
+    id: String,
+    order: Json<AlbumOrder>,
+    user_id: Username,
+) -> ApiResponse {
+    album_response(
+        "Successfully reordered album",
+        reorder_album(&id, &user_id.username, order.0, &db),
+    )
+}
+
+#[put("/0/albums/<_id>/images", rank = 2)]
+pub fn album_reorder_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/albums/<id>/images/<image_id>")]
+pub fn album_remove_image(
+    db: State<Database>,
+    id: String,
+    image_id: String,
+    user_id: Username,
+) -> ApiResponse {
+    album_response(
+        "Successfully removed image from album",
+        remove_album_image(&id, &user_id.username, &image_id, &db),
+    )
+}
+
+#[delete("/0/albums/<_id>/images/<_image_id>", rank = 2)]
+pub fn album_remove_image_no_auth(_id: String, _image_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
@@ -0,0 +1,156 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::Username,
+    comment::{
+        create_comment, delete_comment, edit_comment, list_comments, CommentEdit, CommentError,
+        NewComment, DEFAULT_PAGE_SIZE,
+    },
+    response::ApiResponse,
+    Database,
+};
+
+/// Maps an error from managing a comment to a response.
+fn comment_error_response(err: anyhow::Error) -> ApiResponse {
+    let status = match err.downcast_ref::<CommentError>() {
+        Some(CommentError::UnknownImage) | Some(CommentError::UnknownComment) => Status::NotFound,
+        Some(CommentError::NotAllowed) => Status::Forbidden,
+        Some(_) => Status::BadRequest,
+        None => {
+            println!("Error while managing comment: {:?}", err);
+
+            return ApiResponse {
+                json: json!({
+                    "message": "Failed to update comments, please try again"
+                }),
+                status: Status::InternalServerError,
+            };
+        }
+    };
+
+    ApiResponse {
+        json: json!({
+            "message": err.to_string()
+        }),
+        status,
+    }
+}
+
+#[post("/0/image/<id>/comments", format = "json", data = "<new_comment>")]
+pub fn comment_create(
+    db: State<Database>,
+    id: String,
+    new_comment: Json<NewComment>,
+    user_id: Username,
+) -> ApiResponse {
+    match create_comment(&id, &user_id.username, new_comment.0, &db) {
+        Ok(comment) => ApiResponse {
+            json: json!({
+                "message": "Successfully added comment",
+                "comment": comment
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => comment_error_response(err),
+    }
+}
+
+#[post("/0/image/<_id>/comments", rank = 2)]
+pub fn comment_create_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Lists a page of an image's comment threads.  A JWT token is only needed for comments on your own private images.
+#[get("/0/image/<id>/comments?<offset>&<limit>")]
+pub fn comments(
+    db: State<Database>,
+    id: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    user_id: Option<Username>,
+) -> ApiResponse {
+    let viewer = user_id.map(|user_id| user_id.username);
+
+    match list_comments(
+        &id,
+        viewer.as_deref(),
+        offset.unwrap_or(0),
+        limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        &db,
+    ) {
+        Ok(page) => ApiResponse {
+            json: json!(page),
+            status: Status::Ok,
+        },
+        Err(err) => comment_error_response(err),
+    }
+}
+
+#[patch(
+    "/0/image/<id>/comments/<comment_id>",
+    format = "json",
+    data = "<edit>"
+)]
+pub fn comment_edit(
+    db: State<Database>,
+    id: String,
+    comment_id: String,
+    edit: Json<CommentEdit>,
+    user_id: Username,
+) -> ApiResponse {
+    match edit_comment(&id, &comment_id, &user_id.username, edit.0, &db) {
+        Ok(comment) => ApiResponse {
+            json: json!({
+                "message": "Successfully edited comment",
+                "comment": comment
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => comment_error_response(err),
+    }
+}
+
+#[patch("/0/image/<_id>/comments/<_comment_id>", rank = 2)]
+pub fn comment_edit_no_auth(_id: String, _comment_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Deletes a comment and its replies.  Authors can delete their own comments, and moderators anyone's.
+#[delete("/0/image/<id>/comments/<comment_id>")]
+pub fn comment_delete(
+    db: State<Database>,
+    id: String,
+    comment_id: String,
+    user_id: Username,
+) -> ApiResponse {
+    match delete_comment(&id, &comment_id, &user_id.username, &db) {
+        Ok(_) => ApiResponse {
+            json: json!({
+                "message": "Successfully deleted comment"
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => comment_error_response(err),
+    }
+}
+
+#[delete("/0/image/<_id>/comments/<_comment_id>", rank = 2)]
+pub fn comment_delete_no_auth(_id: String, _comment_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
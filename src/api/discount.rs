@@ -0,0 +1,110 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::Username,
+    discount::{create_discount, delete_discount, list_discounts, DiscountError, NewDiscount},
+    response::ApiResponse,
+    Database,
+};
+
+/// Maps an error from managing a discount to a response.
+fn discount_error_response(err: anyhow::Error) -> ApiResponse {
+    match err.downcast_ref::<DiscountError>() {
+        Some(DiscountError::UnknownDiscount) => ApiResponse {
+            json: json!({
+                "message": DiscountError::UnknownDiscount.to_string()
+            }),
+            status: Status::NotFound,
+        },
+        Some(err) => ApiResponse {
+            json: json!({
+                "message": err.to_string()
+            }),
+            status: Status::BadRequest,
+        },
+        None => {
+            println!("Error while managing discount: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to update discount, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+#[post("/0/discounts", format = "json", data = "<new_discount>")]
+pub fn discount_create(
+    db: State<Database>,
+    new_discount: Json<NewDiscount>,
+    user_id: Username,
+) -> ApiResponse {
+    match create_discount(&user_id.username, new_discount.0, &db) {
+        Ok(discount) => ApiResponse {
+            json: json!({
+                "message": "Successfully created discount",
+                "discount": discount
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => discount_error_response(err),
+    }
+}
+
+#[post("/0/discounts", rank = 2)]
+pub fn discount_create_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Lists the logged in user's discounts, with how many times each has been used.
+#[get("/0/discounts")]
+pub fn discounts(db: State<Database>, user_id: Username) -> ApiResponse {
+    match list_discounts(&user_id.username, &db) {
+        Ok(discounts) => ApiResponse {
+            json: json!({ "discounts": discounts }),
+            status: Status::Ok,
+        },
+        Err(err) => discount_error_response(err),
+    }
+}
+
+#[get("/0/discounts", rank = 2)]
+pub fn discounts_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/discounts/<id>")]
+pub fn discount_delete(db: State<Database>, id: String, user_id: Username) -> ApiResponse {
+    match delete_discount(&id, &user_id.username, &db) {
+        Ok(_) => ApiResponse {
+            json: json!({
+                "message": "Successfully deleted discount"
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => discount_error_response(err),
+    }
+}
+
+#[delete("/0/discounts/<_id>", rank = 2)]
+pub fn discount_delete_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
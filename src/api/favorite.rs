@@ -0,0 +1,92 @@
+use rocket::{http::Status, State};
+
+use crate::{
+    auth::Username,
+    favorite::{like_image, unlike_image, user_favorites, with_likes, FavoriteError},
+    response::ApiResponse,
+    Database,
+};
+
+/// Maps an error from liking an image to a response.
+fn favorite_error_response(err: anyhow::Error) -> ApiResponse {
+    match err.downcast_ref::<FavoriteError>() {
+        Some(FavoriteError::UnknownImage) => ApiResponse {
+            json: json!({
+                "message": FavoriteError::UnknownImage.to_string()
+            }),
+            status: Status::NotFound,
+        },
+        None => {
+            println!("Error while updating favorites: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to update favorites, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+fn likes_response(result: anyhow::Result<u64>) -> ApiResponse {
+    match result {
+        Ok(likes) => ApiResponse {
+            json: json!({ "likes": likes }),
+            status: Status::Ok,
+        },
+        Err(err) => favorite_error_response(err),
+    }
+}
+
+#[put("/0/image/<id>/like")]
+pub fn like(db: State<Database>, id: String, user_id: Username) -> ApiResponse {
+    likes_response(like_image(&user_id.username, &id, &db))
+}
+
+#[put("/0/image/<_id>/like", rank = 2)]
+pub fn like_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/image/<id>/like")]
+pub fn unlike(db: State<Database>, id: String, user_id: Username) -> ApiResponse {
+    likes_response(unlike_image(&user_id.username, &id, &db))
+}
+
+#[delete("/0/image/<_id>/like", rank = 2)]
+pub fn unlike_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Lists the images the logged in user has liked, most recently liked first.
+#[get("/0/account/favorites")]
+pub fn favorites(db: State<Database>, user_id: Username) -> ApiResponse {
+    match user_favorites(&user_id.username, &db).and_then(|images| with_likes(images, &db)) {
+        Ok(images) => ApiResponse {
+            json: json!({ "favorites": images }),
+            status: Status::Ok,
+        },
+        Err(err) => favorite_error_response(err),
+    }
+}
+
+#[get("/0/account/favorites", rank = 2)]
+pub fn favorites_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
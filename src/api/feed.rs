@@ -0,0 +1,136 @@
+use rocket::{http::Status, State};
+
+use crate::{
+    auth::Username,
+    feed::{
+        feed as build_feed, follow_user, following, unfollow_user, FeedError, DEFAULT_PAGE_SIZE,
+    },
+    response::ApiResponse,
+    Database,
+};
+
+/// Maps an error from following a user or reading a feed to a response.
+fn feed_error_response(err: anyhow::Error) -> ApiResponse {
+    let status = match err.downcast_ref::<FeedError>() {
+        Some(FeedError::UnknownUser) => Status::NotFound,
+        Some(_) => Status::BadRequest,
+        None => {
+            println!("Error while managing follows: {:?}", err);
+
+            return ApiResponse {
+                json: json!({
+                    "message": "Failed to load feed, please try again"
+                }),
+                status: Status::InternalServerError,
+            };
+        }
+    };
+
+    ApiResponse {
+        json: json!({
+            "message": err.to_string()
+        }),
+        status,
+    }
+}
+
+#[put("/0/users/<username>/follow")]
+pub fn follow(db: State<Database>, username: String, user_id: Username) -> ApiResponse {
+    match follow_user(&user_id.username, &username, &db) {
+        Ok(follow) => ApiResponse {
+            json: json!({
+                "message": "Successfully followed user",
+                "follow": follow
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => feed_error_response(err),
+    }
+}
+
+#[put("/0/users/<_username>/follow", rank = 2)]
+pub fn follow_no_auth(_username: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/users/<username>/follow")]
+pub fn unfollow(db: State<Database>, username: String, user_id: Username) -> ApiResponse {
+    match unfollow_user(&user_id.username, &username, &db) {
+        Ok(()) => ApiResponse {
+            json: json!({
+                "message": "Successfully unfollowed user"
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => feed_error_response(err),
+    }
+}
+
+#[delete("/0/users/<_username>/follow", rank = 2)]
+pub fn unfollow_no_auth(_username: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Lists the users the logged in user follows, most recently followed first.
+#[get("/0/account/following")]
+pub fn follows(db: State<Database>, user_id: Username) -> ApiResponse {
+    match following(&user_id.username, &db) {
+        Ok(follows) => ApiResponse {
+            json: json!({ "following": follows }),
+            status: Status::Ok,
+        },
+        Err(err) => feed_error_response(err),
+    }
+}
+
+#[get("/0/account/following", rank = 2)]
+pub fn follows_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Gets a page of recent public uploads from the users the logged in user follows, newest first.
+#[get("/0/feed?<cursor>&<limit>")]
+pub fn feed(
+    db: State<Database>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+    user_id: Username,
+) -> ApiResponse {
+    match build_feed(
+        &user_id.username,
+        cursor.as_deref(),
+        limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        &db,
+    ) {
+        Ok(page) => ApiResponse {
+            json: json!(page),
+            status: Status::Ok,
+        },
+        Err(err) => feed_error_response(err),
+    }
+}
+
+#[get("/0/feed", rank = 2)]
+pub fn feed_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
@@ -0,0 +1,46 @@
+use rocket::{http::Status, State};
+
+use crate::{
+    auth::Username,
+    favorite::with_like_count,
+    images::{get_image, protect_original},
+    response::ApiResponse,
+    Database,
+};
+
+/// Gets an image by ID.  Unlisted images can be fetched by anyone who knows the ID, and private ones only by the
+/// owner.  Private images and images for sale are shown as a watermarked preview, even to the owner and buyers.
+#[get("/0/image/<id>")]
+pub fn image(db: State<Database>, id: String, user_id: Option<Username>) -> ApiResponse {
+    let viewer = user_id.map(|user_id| user_id.username);
+
+    let image = get_image(&id, viewer.as_deref(), &db).and_then(|image| {
+        image
+            .map(|image| with_like_count(protect_original(image, viewer.as_deref()), &db))
+            .transpose()
+    });
+
+    match image {
+        Ok(Some(image)) => ApiResponse {
+            json: json!({ "image": image }),
+            status: Status::Ok,
+        },
+        // Private images look the same as missing ones, so their IDs aren't confirmed to exist.
+        Ok(None) => ApiResponse {
+            json: json!({
+                "message": "Could not find image"
+            }),
+            status: Status::NotFound,
+        },
+        Err(err) => {
+            println!("Error while getting image: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to get image, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
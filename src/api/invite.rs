@@ -0,0 +1,48 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::Admin,
+    invite::{create_invite, InviteRequest},
+    response::ApiResponse,
+    Database,
+};
+
+#[post("/0/admin/invites", format = "json", data = "<request>")]
+pub fn invite_create(
+    db: State<Database>,
+    request: Json<InviteRequest>,
+    admin: Admin,
+) -> ApiResponse {
+    match create_invite(request.0, &admin.username, &db) {
+        Ok((code, invite)) => ApiResponse {
+            json: json!({
+                "message": "Successfully created invite code",
+                "code": code,
+                "uses": invite.uses_remaining,
+                "expires": invite.expires
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => {
+            println!("Error while creating invite: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to create invite code, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+#[post("/0/admin/invites", rank = 2)]
+pub fn invite_create_not_admin() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "only admins can create invite codes"
+        }),
+        status: Status::Forbidden,
+    }
+}
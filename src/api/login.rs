@@ -1,28 +1,196 @@
-use rocket::{http::Status, State};
+use rocket::http::{ContentType, CookieJar, Header, Status};
+use rocket::request::Request;
+use rocket::response::{Responder, Response};
+use rocket::State;
 use rocket_contrib::json::Json;
+use thiserror::Error;
 
 use crate::{
-    consts::USER_DATABASE_CONFIG,
+    auth::{remove_session_cookies, set_session_cookies, verify_challenge_jwt, Username},
+    consts::{LOGIN_THROTTLE, USER_DATABASE_CONFIG},
+    policy::username_key,
     response::ApiResponse,
-    user::{verify_user, Credentials},
+    session::{revoke_session, Device},
+    throttle::{check_throttle, clear_failures, record_failure},
+    two_factor::TwoFactorLogin,
+    user::{verify_two_factor, verify_user, Credentials, LoginResult, SuspendedError},
     Database,
 };
 
+use super::suspension::suspended_response;
+
+#[derive(Error, Debug)]
+pub enum LoginError {
+    #[error("Too many failed login attempts")]
+    Throttled { retry_after: i64 },
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for LoginError {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        match self {
+            LoginError::Throttled { retry_after } => Response::build_from(
+                json!({
+                    "message": "Too many failed login attempts, please try again later"
+                })
+                .respond_to(&req)
+                .unwrap(),
+            )
+            .status(Status::TooManyRequests)
+            .header(ContentType::JSON)
+            .header(Header::new("Retry-After", retry_after.to_string()))
+            .ok(),
+        }
+    }
+}
+
 #[post("/0/login", format = "json", data = "<credentials>")]
-pub fn login(db: State<Database>, credentials: Json<Credentials>) -> ApiResponse {
-    match verify_user(credentials.0, &USER_DATABASE_CONFIG, &db) {
-        Ok(token) => ApiResponse {
+pub fn login(
+    db: State<Database>,
+    credentials: Json<Credentials>,
+    device: Device,
+    cookies: &CookieJar<'_>,
+) -> Result<ApiResponse, LoginError> {
+    // Throttle on the normalized name, so that varying its case doesn't get around the limit.
+    let username = username_key(credentials.username());
+
+    // Check this before verifying, so throttled attempts don't cost us a password hash.
+    match check_throttle(&username, device.ip, &db) {
+        Ok(Some(retry_after)) => return Err(LoginError::Throttled { retry_after }),
+        Ok(None) => {}
+        Err(err) => println!("Failed to check login throttle: {:?}", err),
+    }
+
+    match verify_user(credentials.0, &device, &USER_DATABASE_CONFIG, &db) {
+        // Failures aren't cleared yet, otherwise alternating between this and guessing codes would avoid the limit.
+        Ok(LoginResult::TwoFactorChallenge(challenge)) => Ok(ApiResponse {
             json: json!({
-                "message": "Successfully logged in",
-                "token": token
+                "message": "Two-factor authentication required",
+                "challenge": challenge
             }),
             status: Status::Ok,
-        },
-        Err(_err) => ApiResponse {
-            json: json!({
-                "message": "Wrong username or password, please try again"
-            }),
-            status: Status::BadRequest,
-        },
+        }),
+        Ok(LoginResult::Token(token)) => {
+            if let Err(err) = clear_failures(&username, &db) {
+                println!("Failed to clear login failures: {:?}", err);
+            }
+
+            set_session_cookies(cookies, &token);
+
+            Ok(ApiResponse {
+                json: json!({
+                    "message": "Successfully logged in",
+                    "token": token
+                }),
+                status: Status::Ok,
+            })
+        }
+        Err(err) if err.downcast_ref::<SuspendedError>().is_some() => {
+            // The password was right, so this isn't counted as a failure.
+            Ok(suspended_response(
+                err.downcast_ref::<SuspendedError>().unwrap(),
+            ))
+        }
+        Err(_err) => {
+            if let Err(err) = record_failure(&username, device.ip, &LOGIN_THROTTLE, &db) {
+                println!("Failed to record login failure: {:?}", err);
+            }
+
+            Ok(ApiResponse {
+                json: json!({
+                    "message": "Wrong username or password, please try again"
+                }),
+                status: Status::BadRequest,
+            })
+        }
+    }
+}
+
+#[post("/0/login/2fa", format = "json", data = "<attempt>")]
+pub fn login_two_factor(
+    db: State<Database>,
+    attempt: Json<TwoFactorLogin>,
+    device: Device,
+    cookies: &CookieJar<'_>,
+) -> Result<ApiResponse, LoginError> {
+    let username = match verify_challenge_jwt(&attempt.challenge, &db) {
+        Ok(username) => username,
+        Err(_err) => {
+            return Ok(ApiResponse {
+                json: json!({
+                    "message": "Invalid or expired login, please log in again"
+                }),
+                status: Status::BadRequest,
+            })
+        }
+    };
+
+    // Codes are short enough to guess, so they share the password throttle.
+    match check_throttle(&username_key(&username), device.ip, &db) {
+        Ok(Some(retry_after)) => return Err(LoginError::Throttled { retry_after }),
+        Ok(None) => {}
+        Err(err) => println!("Failed to check login throttle: {:?}", err),
+    }
+
+    match verify_two_factor(&username, &attempt.code, &device, &db) {
+        Ok(token) => {
+            if let Err(err) = clear_failures(&username_key(&username), &db) {
+                println!("Failed to clear login failures: {:?}", err);
+            }
+
+            set_session_cookies(cookies, &token);
+
+            Ok(ApiResponse {
+                json: json!({
+                    "message": "Successfully logged in",
+                    "token": token
+                }),
+                status: Status::Ok,
+            })
+        }
+        Err(err) if err.downcast_ref::<SuspendedError>().is_some() => Ok(suspended_response(
+            err.downcast_ref::<SuspendedError>().unwrap(),
+        )),
+        Err(_err) => {
+            if let Err(err) =
+                record_failure(&username_key(&username), device.ip, &LOGIN_THROTTLE, &db)
+            {
+                println!("Failed to record login failure: {:?}", err);
+            }
+
+            Ok(ApiResponse {
+                json: json!({
+                    "message": "Invalid two-factor code, please try again"
+                }),
+                status: Status::BadRequest,
+            })
+        }
+    }
+}
+
+#[post("/0/logout")]
+pub fn logout(
+    db: State<Database>,
+    user_id: Option<Username>,
+    cookies: &CookieJar<'_>,
+) -> ApiResponse {
+    // Sign out the session too, so the token stops working even if it was copied out of the cookie.
+    if let Some(Username {
+        username,
+        session_id,
+    }) = user_id
+    {
+        if let Err(err) = revoke_session(&session_id, &username, &db) {
+            println!("Failed to revoke session: {:?}", err);
+        }
+    }
+
+    remove_session_cookies(cookies);
+
+    ApiResponse {
+        json: json!({
+            "message": "Successfully logged out"
+        }),
+        status: Status::Ok,
     }
 }
@@ -0,0 +1,221 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::{Admin, Username},
+    images::{get_image, original_url, protect_original, sync_protection},
+    market::{
+        balances, deposit, has_purchased, purchase_image, quote, set_listing, transactions,
+        Deposit, Listing, MarketError,
+    },
+    response::ApiResponse,
+    Database,
+};
+
+/// Maps an error from buying or selling an image to a response.
+fn market_error_response(err: anyhow::Error) -> ApiResponse {
+    let status = match err.downcast_ref::<MarketError>() {
+        Some(MarketError::UnknownImage) | Some(MarketError::UnknownUser) => Status::NotFound,
+        Some(MarketError::InsufficientFunds) => Status::PaymentRequired,
+        Some(MarketError::SoldOut) | Some(MarketError::AlreadyPurchased) => Status::Conflict,
+        Some(_) => Status::BadRequest,
+        None => {
+            println!("Error while trading image: {:?}", err);
+
+            return ApiResponse {
+                json: json!({
+                    "message": "Failed to complete the request, please try again"
+                }),
+                status: Status::InternalServerError,
+            };
+        }
+    };
+
+    ApiResponse {
+        json: json!({
+            "message": err.to_string()
+        }),
+        status,
+    }
+}
+
+/// Puts one of the logged in user's images up for sale, or takes it off sale if no price is given.
+#[put("/0/image/<id>/listing", format = "json", data = "<listing>")]
+pub async fn listing_update(
+    db: State<'_, Database>,
+    s3_client: State<'_, rusoto_s3::S3Client>,
+    id: String,
+    listing: Json<Listing>,
+    user_id: Username,
+) -> ApiResponse {
+    let image = match set_listing(&id, &user_id.username, listing.0, &db) {
+        Ok(image) => image,
+        Err(err) => return market_error_response(err),
+    };
+
+    match sync_protection(image, &s3_client, &db).await {
+        Ok(image) => ApiResponse {
+            json: json!({
+                "message": "Successfully updated listing",
+                "image": protect_original(image, Some(&user_id.username))
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => market_error_response(err),
+    }
+}
+
+#[put("/0/image/<_id>/listing", rank = 2)]
+pub fn listing_update_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[post("/0/image/<id>/purchase")]
+pub fn purchase(db: State<Database>, id: String, user_id: Username) -> ApiResponse {
+    match purchase_image(&user_id.username, &id, &db) {
+        Ok((transaction, quote)) => ApiResponse {
+            json: json!({
+                "message": "Successfully bought image",
+                "transaction": transaction,
+                "quote": quote
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => market_error_response(err),
+    }
+}
+
+#[post("/0/image/<_id>/purchase", rank = 2)]
+pub fn purchase_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Gets what an image sells for right now, after any discount.
+#[get("/0/image/<id>/price")]
+pub fn price(db: State<Database>, id: String, user_id: Option<Username>) -> ApiResponse {
+    let viewer = user_id.map(|user_id| user_id.username);
+
+    match get_image(&id, viewer.as_deref(), &db) {
+        Ok(Some(image)) => match quote(&image, &db) {
+            Ok(quote) => ApiResponse {
+                json: json!({ "quote": quote }),
+                status: Status::Ok,
+            },
+            Err(err) => market_error_response(err),
+        },
+        Ok(None) => market_error_response(MarketError::UnknownImage.into()),
+        Err(err) => market_error_response(err),
+    }
+}
+
+/// Gets a link to an image's full resolution original.  Images that are for sale are only unlocked for their owner
+/// and for users who have bought them.
+#[get("/0/image/<id>/original")]
+pub async fn original(db: State<'_, Database>, id: String, user_id: Username) -> ApiResponse {
+    let username = user_id.username;
+
+    let image = match get_image(&id, Some(&username), &db) {
+        Ok(Some(image)) => image,
+        Ok(None) => return market_error_response(MarketError::UnknownImage.into()),
+        Err(err) => return market_error_response(err),
+    };
+
+    let unlocked = match image.listing().price {
+        Some(_) if !image.is_owned_by(&username) => match has_purchased(&username, &id, &db) {
+            Ok(purchased) => purchased,
+            Err(err) => return market_error_response(err),
+        },
+        _ => true,
+    };
+    if !unlocked {
+        return ApiResponse {
+            json: json!({
+                "message": "Buy this image to download the original"
+            }),
+            status: Status::Forbidden,
+        };
+    }
+
+    match original_url(&image).await {
+        Ok(url) => ApiResponse {
+            json: json!({ "url": url }),
+            status: Status::Ok,
+        },
+        Err(err) => market_error_response(err),
+    }
+}
+
+#[get("/0/image/<_id>/original", rank = 2)]
+pub fn original_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Gets the logged in user's balance in each currency, and the transactions that changed them, newest first.
+#[get("/0/account/balance")]
+pub fn balance(db: State<Database>, user_id: Username) -> ApiResponse {
+    match balances(&user_id.username, &db)
+        .and_then(|balances| Ok((balances, transactions(&user_id.username, &db)?)))
+    {
+        Ok((balances, transactions)) => ApiResponse {
+            json: json!({
+                "balances": balances
+                    .into_iter()
+                    .map(|(currency, amount)| json!({ "currency": currency, "amount": amount }))
+                    .collect::<Vec<_>>(),
+                "transactions": transactions
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => market_error_response(err),
+    }
+}
+
+#[get("/0/account/balance", rank = 2)]
+pub fn balance_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Credits a user's balance with money paid in outside of foto.
+#[post("/0/admin/ledger/deposits", format = "json", data = "<request>")]
+pub fn deposit_create(db: State<Database>, request: Json<Deposit>, _admin: Admin) -> ApiResponse {
+    match deposit(request.0, &db) {
+        Ok(transaction) => ApiResponse {
+            json: json!({
+                "message": "Successfully recorded deposit",
+                "transaction": transaction
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => market_error_response(err),
+    }
+}
+
+#[post("/0/admin/ledger/deposits", rank = 2)]
+pub fn deposit_create_not_admin() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "only admins can record deposits"
+        }),
+        status: Status::Forbidden,
+    }
+}
@@ -0,0 +1,140 @@
+use rocket::{
+    http::{Cookie, CookieJar, SameSite, Status},
+    response::Redirect,
+    State,
+};
+
+use crate::{
+    auth::{set_session_cookies, Username},
+    consts::{ACCOUNT_POLICY, REGISTRATION_MODE, SECURE_COOKIES},
+    oidc::{begin_login, finish_login, OidcError, OidcProvider, STATE_COOKIE},
+    response::ApiResponse,
+    session::Device,
+    user::{start_login, LoginResult, SuspendedError},
+    Database,
+};
+
+use super::suspension::suspended_response;
+
+/// Maps an error from logging in through OpenID Connect to a response.
+fn oidc_error_response(err: anyhow::Error) -> ApiResponse {
+    if let Some(err) = err.downcast_ref::<SuspendedError>() {
+        return suspended_response(err);
+    }
+
+    match err.downcast_ref::<OidcError>() {
+        Some(OidcError::NotConfigured) => ApiResponse {
+            json: json!({
+                "message": OidcError::NotConfigured.to_string()
+            }),
+            status: Status::NotFound,
+        },
+        Some(OidcError::RegistrationClosed) => ApiResponse {
+            json: json!({
+                "message": OidcError::RegistrationClosed.to_string()
+            }),
+            status: Status::Forbidden,
+        },
+        Some(err) => ApiResponse {
+            json: json!({
+                "message": err.to_string()
+            }),
+            status: Status::BadRequest,
+        },
+        None => {
+            println!("Error while logging in through OpenID Connect: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to log in, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+/// Sends the user to the provider to log in.  If they are already logged in, the provider account is linked to them.
+#[get("/0/oidc/login")]
+pub async fn oidc_login(
+    db: State<'_, Database>,
+    provider: State<'_, OidcProvider>,
+    user_id: Option<Username>,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, ApiResponse> {
+    let config = match provider.config.as_ref() {
+        Some(config) => config,
+        None => return Err(oidc_error_response(OidcError::NotConfigured.into())),
+    };
+
+    match begin_login(config, user_id.map(|user_id| user_id.username), &db).await {
+        Ok((url, state)) => {
+            // Lax, since the provider sends the browser back from another site.
+            cookies.add(
+                Cookie::build(STATE_COOKIE, state)
+                    .path("/api/0/oidc")
+                    .http_only(true)
+                    .secure(*SECURE_COOKIES)
+                    .same_site(SameSite::Lax)
+                    .finish(),
+            );
+
+            Ok(Redirect::to(url))
+        }
+        Err(err) => Err(oidc_error_response(err)),
+    }
+}
+
+#[get("/0/oidc/callback?<code>&<state>")]
+pub async fn oidc_callback(
+    db: State<'_, Database>,
+    provider: State<'_, OidcProvider>,
+    code: String,
+    state: String,
+    device: Device,
+    cookies: &CookieJar<'_>,
+) -> ApiResponse {
+    let config = match provider.config.as_ref() {
+        Some(config) => config,
+        None => return oidc_error_response(OidcError::NotConfigured.into()),
+    };
+
+    let state_cookie = cookies
+        .get(STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    cookies.remove(Cookie::build(STATE_COOKIE, "").path("/api/0/oidc").finish());
+
+    // Users with two-factor authentication still have to enter a code, the same as with a password.
+    match finish_login(
+        config,
+        &code,
+        &state,
+        state_cookie.as_deref(),
+        *REGISTRATION_MODE,
+        &ACCOUNT_POLICY,
+        &db,
+    )
+    .await
+    .and_then(|user| start_login(&user, &device, &db))
+    {
+        Ok(LoginResult::TwoFactorChallenge(challenge)) => ApiResponse {
+            json: json!({
+                "message": "Two-factor authentication required",
+                "challenge": challenge
+            }),
+            status: Status::Ok,
+        },
+        Ok(LoginResult::Token(token)) => {
+            set_session_cookies(cookies, &token);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Successfully logged in",
+                    "token": token
+                }),
+                status: Status::Ok,
+            }
+        }
+        Err(err) => oidc_error_response(err),
+    }
+}
@@ -0,0 +1,54 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::Admin,
+    consts::{ACCOUNT_POLICY, USER_DATABASE_CONFIG},
+    response::ApiResponse,
+    user::{create_password_reset, reset_password, PasswordReset, PasswordResetRequest},
+    Database,
+};
+
+use super::account::account_error_response;
+
+#[post("/0/admin/password-reset", format = "json", data = "<request>")]
+pub fn password_reset_create(
+    db: State<Database>,
+    request: Json<PasswordResetRequest>,
+    admin: Admin,
+) -> ApiResponse {
+    match create_password_reset(request.0, &admin.username, &db) {
+        Ok((code, expires)) => ApiResponse {
+            json: json!({
+                "message": "Successfully created reset code",
+                "code": code,
+                "expires": expires
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => account_error_response(err),
+    }
+}
+
+#[post("/0/admin/password-reset", rank = 2)]
+pub fn password_reset_create_not_admin() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "only admins can issue reset codes"
+        }),
+        status: Status::Forbidden,
+    }
+}
+
+#[post("/0/password/reset", format = "json", data = "<reset>")]
+pub fn password_reset(db: State<Database>, reset: Json<PasswordReset>) -> ApiResponse {
+    match reset_password(reset.0, &USER_DATABASE_CONFIG, &ACCOUNT_POLICY, &db) {
+        Ok(_) => ApiResponse {
+            json: json!({
+                "message": "Successfully reset password, please log in again"
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => account_error_response(err),
+    }
+}
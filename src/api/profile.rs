@@ -0,0 +1,104 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::Username,
+    profile::{
+        get_profile, update_profile, user_images, ProfileError, ProfileUpdate, DEFAULT_PAGE_SIZE,
+    },
+    response::ApiResponse,
+    Database,
+};
+
+/// Maps an error from viewing or updating a profile to a response.
+fn profile_error_response(err: anyhow::Error) -> ApiResponse {
+    let status = match err.downcast_ref::<ProfileError>() {
+        Some(ProfileError::UnknownUser) => Status::NotFound,
+        Some(_) => Status::BadRequest,
+        None => {
+            println!("Error while managing profile: {:?}", err);
+
+            return ApiResponse {
+                json: json!({
+                    "message": "Failed to load profile, please try again"
+                }),
+                status: Status::InternalServerError,
+            };
+        }
+    };
+
+    ApiResponse {
+        json: json!({
+            "message": err.to_string()
+        }),
+        status,
+    }
+}
+
+/// Gets a user's profile and stats.  A JWT token is only needed to count your own private images.
+#[get("/0/users/<username>")]
+pub fn profile(db: State<Database>, username: String, user_id: Option<Username>) -> ApiResponse {
+    let viewer = user_id.map(|user_id| user_id.username);
+
+    match get_profile(&username, viewer.as_deref(), &db) {
+        Ok(profile) => ApiResponse {
+            json: json!({ "profile": profile }),
+            status: Status::Ok,
+        },
+        Err(err) => profile_error_response(err),
+    }
+}
+
+/// Lists a page of a user's images, newest first.  A JWT token is only needed to see your own private images.
+#[get("/0/users/<username>/images?<offset>&<limit>")]
+pub fn profile_images(
+    db: State<Database>,
+    username: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    user_id: Option<Username>,
+) -> ApiResponse {
+    let viewer = user_id.map(|user_id| user_id.username);
+
+    match user_images(
+        &username,
+        viewer.as_deref(),
+        offset.unwrap_or(0),
+        limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        &db,
+    ) {
+        Ok(page) => ApiResponse {
+            json: json!(page),
+            status: Status::Ok,
+        },
+        Err(err) => profile_error_response(err),
+    }
+}
+
+#[patch("/0/account/profile", format = "json", data = "<update>")]
+pub fn profile_update(
+    db: State<Database>,
+    update: Json<ProfileUpdate>,
+    user_id: Username,
+) -> ApiResponse {
+    match update_profile(&user_id.username, update.0, &db) {
+        Ok(profile) => ApiResponse {
+            json: json!({
+                "message": "Successfully updated profile",
+                "profile": profile
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => profile_error_response(err),
+    }
+}
+
+#[patch("/0/account/profile", rank = 2)]
+pub fn profile_update_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
@@ -0,0 +1,42 @@
+use rocket::{http::Status, State};
+
+use crate::{
+    auth::Username,
+    quota::{limits, usage},
+    response::ApiResponse,
+    Database,
+};
+
+/// Gets how much the logged in user has uploaded, and their limits.
+#[get("/0/account/usage")]
+pub fn account_usage(db: State<Database>, user_id: Username) -> ApiResponse {
+    match usage(&user_id.username, &db) {
+        Ok(usage) => ApiResponse {
+            json: json!({
+                "usage": usage,
+                "limits": limits(&user_id.username)
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => {
+            println!("Error while getting usage: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to get usage, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+#[get("/0/account/usage", rank = 2)]
+pub fn account_usage_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
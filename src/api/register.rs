@@ -2,7 +2,9 @@ use rocket::{http::Status, State};
 use rocket_contrib::json::Json;
 
 use crate::{
-    consts::USER_DATABASE_CONFIG,
+    consts::{ACCOUNT_POLICY, REGISTRATION_MODE, USER_DATABASE_CONFIG},
+    invite::RegistrationError,
+    policy::PolicyError,
     response::ApiResponse,
     user::{add_user, Credentials},
     Database,
@@ -10,18 +12,53 @@ use crate::{
 
 #[post("/0/register", format = "json", data = "<credentials>")]
 pub fn register(db: State<Database>, credentials: Json<Credentials>) -> ApiResponse {
-    match add_user(credentials.0, &USER_DATABASE_CONFIG, &db) {
+    match add_user(
+        credentials.0,
+        *REGISTRATION_MODE,
+        &USER_DATABASE_CONFIG,
+        &ACCOUNT_POLICY,
+        &db,
+    ) {
         Ok(_) => ApiResponse {
             json: json!({
                 "message": "Successfully created a new user"
             }),
             status: Status::Ok,
         },
-        Err(_err) => ApiResponse {
-            json: json!({
-                "message": "Could not create a new user, please try again" // TODO: Make this more descriptive.
-            }),
-            status: Status::BadRequest,
-        },
+        Err(err) => {
+            if let Some(err) = err.downcast_ref::<RegistrationError>() {
+                return match err {
+                    RegistrationError::Closed => ApiResponse {
+                        json: json!({
+                            "message": err.to_string()
+                        }),
+                        status: Status::Forbidden,
+                    },
+                    RegistrationError::InvalidInvite => ApiResponse {
+                        json: json!({
+                            "message": err.to_string(),
+                            "field": "inviteCode"
+                        }),
+                        status: Status::BadRequest,
+                    },
+                };
+            }
+
+            match err.downcast_ref::<PolicyError>() {
+                Some(err) => ApiResponse {
+                    json: json!({
+                        "message": err.to_string(),
+                        "field": err.field()
+                    }),
+                    status: Status::BadRequest,
+                },
+                None => ApiResponse {
+                    json: json!({
+                        "message": "Could not create a new user, please try again"
+                    }),
+                    status: Status::BadRequest,
+                },
+            }
+        }
     }
 }
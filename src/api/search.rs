@@ -10,7 +10,10 @@ use rocket::{http::ContentType, Data};
 use rocket_contrib::json::JsonValue;
 use thiserror::Error;
 
-use crate::{consts::HAMMING_DISTANCE, images::*, response::ApiResponse, Database};
+use crate::{
+    auth::Username, consts::HAMMING_DISTANCE, favorite::with_likes, images::*,
+    response::ApiResponse, Database,
+};
 
 use super::upload::Boundary;
 
@@ -18,6 +21,8 @@ use super::upload::Boundary;
 pub enum SearchError {
     #[error("Failed to parse field")]
     ParseError(#[from] ImageUploadTypeError),
+    #[error("Invalid {0} field")]
+    InvalidField(&'static str),
     #[error("Failed to read multipart form properly")]
     MultipartError(#[from] multer::Error),
     #[error("Missing fields")]
@@ -29,18 +34,30 @@ pub enum SearchError {
 #[rocket::async_trait]
 impl<'r> Responder<'r, 'static> for SearchError {
     fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
-        println!("Error while searching: {:?}", self);
+        let (json, status) = match &self {
+            SearchError::InvalidField(field) => (
+                json!({
+                    "message": self.to_string(),
+                    "field": field
+                }),
+                Status::BadRequest,
+            ),
+            _ => {
+                println!("Error while searching: {:?}", self);
+
+                (
+                    json!({
+                        "message": "Failed to search for image"
+                    }),
+                    Status::InternalServerError,
+                )
+            }
+        };
 
-        Response::build_from(
-            json!({
-                "message": "Failed to search for image"
-            })
-            .respond_to(&req)
-            .unwrap(),
-        )
-        .status(Status::InternalServerError)
-        .header(ContentType::JSON)
-        .ok()
+        Response::build_from(json.respond_to(&req).unwrap())
+            .status(status)
+            .header(ContentType::JSON)
+            .ok()
     }
 }
 
@@ -49,19 +66,31 @@ pub async fn search(
     db: State<'_, Database>,
     data: Data,
     boundary: Boundary,
+    user_id: Option<Username>,
 ) -> Result<JsonValue, SearchError> {
     use futures::stream::once;
 
     let limit: ByteUnit = 15.mebibytes();
     let constraints = Constraints::new()
-        .allowed_fields(vec!["similar_image", "similar_image_type"])
+        .allowed_fields(vec![
+            "similar_image",
+            "similar_image_type",
+            "uploaded_after",
+            "uploaded_before",
+            "captured_after",
+            "captured_before",
+        ])
         .size_limit(
             SizeLimit::new()
                 // Set 15mb as size limit for the whole stream body.
                 .whole_stream(15 * 1024 * 1024)
                 // Set 10mb as size limit for all fields.
                 .per_field(10 * 1024 * 1024)
-                .for_field("similar_image_type", 100),
+                .for_field("similar_image_type", 100)
+                .for_field("uploaded_after", 100)
+                .for_field("uploaded_before", 100)
+                .for_field("captured_after", 100)
+                .for_field("captured_before", 100),
         );
 
     let reader = once(async move { data.open(limit).stream_to_vec().await });
@@ -69,6 +98,7 @@ pub async fn search(
 
     let mut image: Option<Vec<u8>> = None;
     let mut image_type: Option<ImageUploadType> = None;
+    let mut date_filter = DateFilter::default();
 
     while let Ok(Some(field)) = multipart.next_field().await {
         if let Some(field_name) = field.name() {
@@ -79,15 +109,54 @@ pub async fn search(
                 "similar_image_type" => {
                     image_type = Some(field.text().await?.parse::<ImageUploadType>()?);
                 }
+                "uploaded_after" => {
+                    date_filter.uploaded_after = Some(
+                        field
+                            .text()
+                            .await?
+                            .parse::<i64>()
+                            .map_err(|_| SearchError::InvalidField("uploaded_after"))?,
+                    );
+                }
+                "uploaded_before" => {
+                    date_filter.uploaded_before = Some(
+                        field
+                            .text()
+                            .await?
+                            .parse::<i64>()
+                            .map_err(|_| SearchError::InvalidField("uploaded_before"))?,
+                    );
+                }
+                "captured_after" => {
+                    date_filter.captured_after = Some(
+                        field
+                            .text()
+                            .await?
+                            .parse::<i64>()
+                            .map_err(|_| SearchError::InvalidField("captured_after"))?,
+                    );
+                }
+                "captured_before" => {
+                    date_filter.captured_before = Some(
+                        field
+                            .text()
+                            .await?
+                            .parse::<i64>()
+                            .map_err(|_| SearchError::InvalidField("captured_before"))?,
+                    );
+                }
                 _ => {}
             }
         }
     }
 
     // Expand with more types as needed.
-    if !vec![(image.is_some() && image_type.is_some())]
-        .iter()
-        .any(|element| *element)
+    if !vec![
+        (image.is_some() && image_type.is_some()),
+        !date_filter.is_empty(),
+    ]
+    .iter()
+    .any(|element| *element)
     {
         return Err(SearchError::MissingFields);
     }
@@ -134,8 +203,30 @@ pub async fn search(
         for key in correct_keys {
             results.extend(db.image_hashes.get(key).unwrap().unwrap());
         }
+    } else {
+        // Only searching by date, so the date indexes narrow it down.
+        results = images_by_date(&date_filter, &db)
+            .map_err(|err| SearchError::FailedToSearch(err.to_string()))?;
     }
 
+    let results: Vec<Image> = results
+        .into_iter()
+        .filter(|image| date_filter.matches(image))
+        .collect();
+
+    // Suspended users' images come back once they are reinstated.
+    let results = hide_suspended_owners(results, &db)
+        .map_err(|err| SearchError::FailedToSearch(err.to_string()))?;
+
+    // Unlisted and private images only show up for their owner.
+    let viewer = user_id.map(|user_id| user_id.username);
+    let results: Vec<Image> = results
+        .into_iter()
+        .filter(|image| image.is_searchable_by(viewer.as_deref()))
+        .collect();
+    let results = with_likes(protect_originals(results, viewer.as_deref()), &db)
+        .map_err(|err| SearchError::FailedToSearch(err.to_string()))?;
+
     Ok(json!({ "results": results }))
 }
 
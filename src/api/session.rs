@@ -0,0 +1,136 @@
+use rocket::{
+    http::{CookieJar, Status},
+    State,
+};
+
+use crate::{
+    auth::{remove_session_cookies, Username},
+    response::ApiResponse,
+    session::{list_sessions, revoke_session, SessionError},
+    user::revoke_tokens,
+    Database,
+};
+
+/// Maps an error from listing or revoking sessions to a response.
+fn session_error_response(err: anyhow::Error) -> ApiResponse {
+    match err.downcast_ref::<SessionError>() {
+        Some(err) => ApiResponse {
+            json: json!({
+                "message": err.to_string()
+            }),
+            status: Status::NotFound,
+        },
+        None => {
+            println!("Error while managing sessions: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to update sessions, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+#[get("/0/account/sessions")]
+pub fn sessions(db: State<Database>, user_id: Username) -> ApiResponse {
+    match list_sessions(&user_id.username, &db) {
+        Ok(sessions) => {
+            let sessions: Vec<_> = sessions
+                .into_iter()
+                .map(|session| {
+                    json!({
+                        "current": user_id.session_id == session.id,
+                        "id": session.id,
+                        "created": session.created,
+                        "expires": session.expires,
+                        "userAgent": session.user_agent,
+                        "ip": session.ip
+                    })
+                })
+                .collect();
+
+            ApiResponse {
+                json: json!({ "sessions": sessions }),
+                status: Status::Ok,
+            }
+        }
+        Err(err) => session_error_response(err),
+    }
+}
+
+#[get("/0/account/sessions", rank = 2)]
+pub fn sessions_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/account/sessions/<id>")]
+pub fn session_revoke(
+    db: State<Database>,
+    id: String,
+    user_id: Username,
+    cookies: &CookieJar<'_>,
+) -> ApiResponse {
+    match revoke_session(&id, &user_id.username, &db) {
+        Ok(_) => {
+            if user_id.session_id == id {
+                remove_session_cookies(cookies);
+            }
+
+            ApiResponse {
+                json: json!({
+                    "message": "Successfully signed out session"
+                }),
+                status: Status::Ok,
+            }
+        }
+        Err(err) => session_error_response(err),
+    }
+}
+
+#[delete("/0/account/sessions/<_id>", rank = 2)]
+pub fn session_revoke_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/account/sessions")]
+pub fn sessions_revoke_all(
+    db: State<Database>,
+    user_id: Username,
+    cookies: &CookieJar<'_>,
+) -> ApiResponse {
+    match revoke_tokens(&user_id.username, &db) {
+        Ok(_) => {
+            remove_session_cookies(cookies);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Successfully signed out all sessions"
+                }),
+                status: Status::Ok,
+            }
+        }
+        Err(err) => session_error_response(err),
+    }
+}
+
+#[delete("/0/account/sessions", rank = 2)]
+pub fn sessions_revoke_all_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
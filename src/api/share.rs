@@ -0,0 +1,128 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::Username,
+    response::ApiResponse,
+    share::{create_share, list_shares, open_share, revoke_share, ShareError, ShareRequest},
+    Database,
+};
+
+/// Maps an error from creating, using or revoking a share link to a response.
+fn share_error_response(err: anyhow::Error) -> ApiResponse {
+    match err.downcast_ref::<ShareError>() {
+        Some(err) => ApiResponse {
+            json: json!({
+                "message": err.to_string()
+            }),
+            status: Status::NotFound,
+        },
+        None => {
+            println!("Error while handling share link: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to handle share link, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+#[post("/0/image/<id>/shares", format = "json", data = "<request>")]
+pub fn share_create(
+    db: State<Database>,
+    id: String,
+    request: Json<ShareRequest>,
+    user_id: Username,
+) -> ApiResponse {
+    match create_share(&id, &user_id.username, request.0, &db) {
+        Ok((share, path)) => ApiResponse {
+            json: json!({
+                "message": "Successfully created share link",
+                "share": share,
+                "path": path
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => share_error_response(err),
+    }
+}
+
+#[post("/0/image/<_id>/shares", rank = 2)]
+pub fn share_create_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[get("/0/image/<id>/shares")]
+pub fn shares(db: State<Database>, id: String, user_id: Username) -> ApiResponse {
+    match list_shares(&id, &user_id.username, &db) {
+        Ok(shares) => ApiResponse {
+            json: json!({ "shares": shares }),
+            status: Status::Ok,
+        },
+        Err(err) => share_error_response(err),
+    }
+}
+
+#[get("/0/image/<_id>/shares", rank = 2)]
+pub fn shares_no_auth(_id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[delete("/0/image/<image_id>/shares/<id>")]
+pub fn share_revoke(
+    db: State<Database>,
+    image_id: String,
+    id: String,
+    user_id: Username,
+) -> ApiResponse {
+    match revoke_share(&image_id, &id, &user_id.username, &db) {
+        Ok(_) => ApiResponse {
+            json: json!({
+                "message": "Successfully revoked share link"
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => share_error_response(err),
+    }
+}
+
+#[delete("/0/image/<_image_id>/shares/<_id>", rank = 2)]
+pub fn share_revoke_no_auth(_image_id: String, _id: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+/// Opens a share link.  No JWT token is needed, since the signature shows the owner shared it.
+#[get("/0/share/<id>?<image>&<expires>&<signature>")]
+pub async fn share_open(
+    db: State<'_, Database>,
+    id: String,
+    image: String,
+    expires: i64,
+    signature: String,
+) -> ApiResponse {
+    match open_share(&id, &image, expires, &signature, &db).await {
+        Ok(image) => ApiResponse {
+            json: json!({ "image": image }),
+            status: Status::Ok,
+        },
+        Err(err) => share_error_response(err),
+    }
+}
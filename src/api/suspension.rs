@@ -0,0 +1,112 @@
+use rocket::{http::Status, request::Request, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::Admin,
+    response::ApiResponse,
+    user::{reinstate_user, suspend_user, AccountError, SuspendedError, SuspensionRequest},
+    Database,
+};
+
+/// The response for a suspended user trying to log in or use a token.
+pub fn suspended_response(err: &SuspendedError) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": err.to_string(),
+            "reason": err.reason,
+            "until": err.until
+        }),
+        status: Status::Forbidden,
+    }
+}
+
+/// Guards fail with a 403 for suspended users, leaving the reason in the request's local cache.
+#[catch(403)]
+pub fn forbidden(req: &Request<'_>) -> ApiResponse {
+    match req.local_cache(|| None::<SuspendedError>) {
+        Some(err) => suspended_response(err),
+        None => ApiResponse {
+            json: json!({
+                "message": "Forbidden"
+            }),
+            status: Status::Forbidden,
+        },
+    }
+}
+
+/// Maps an error from suspending or reinstating a user to a response.
+fn suspension_error_response(err: anyhow::Error) -> ApiResponse {
+    match err.downcast_ref::<AccountError>() {
+        Some(err) => ApiResponse {
+            json: json!({
+                "message": err.to_string()
+            }),
+            status: Status::BadRequest,
+        },
+        None => {
+            println!("Error while updating suspension: {:?}", err);
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to update suspension, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+#[post(
+    "/0/admin/users/<username>/suspension",
+    format = "json",
+    data = "<request>"
+)]
+pub fn suspension_create(
+    db: State<Database>,
+    username: String,
+    request: Json<SuspensionRequest>,
+    admin: Admin,
+) -> ApiResponse {
+    match suspend_user(&username, request.0, &admin.username, &db) {
+        Ok(_) => ApiResponse {
+            json: json!({
+                "message": "Successfully suspended user"
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => suspension_error_response(err),
+    }
+}
+
+#[post("/0/admin/users/<_username>/suspension", rank = 2)]
+pub fn suspension_create_not_admin(_username: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "only admins can suspend users"
+        }),
+        status: Status::Forbidden,
+    }
+}
+
+#[delete("/0/admin/users/<username>/suspension")]
+pub fn suspension_delete(db: State<Database>, username: String, _admin: Admin) -> ApiResponse {
+    match reinstate_user(&username, &db) {
+        Ok(_) => ApiResponse {
+            json: json!({
+                "message": "Successfully reinstated user"
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => suspension_error_response(err),
+    }
+}
+
+#[delete("/0/admin/users/<_username>/suspension", rank = 2)]
+pub fn suspension_delete_not_admin(_username: String) -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "only admins can reinstate users"
+        }),
+        status: Status::Forbidden,
+    }
+}
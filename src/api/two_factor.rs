@@ -0,0 +1,87 @@
+use rocket::{http::Status, State};
+use rocket_contrib::json::Json;
+
+use crate::{
+    auth::Username,
+    response::ApiResponse,
+    two_factor::{confirm, enroll, TwoFactorCode, TwoFactorError},
+    Database,
+};
+
+/// Maps an error from setting up two-factor authentication to a response.
+fn two_factor_error_response(err: anyhow::Error) -> ApiResponse {
+    match err.downcast_ref::<TwoFactorError>() {
+        Some(err) => ApiResponse {
+            json: json!({
+                "message": err.to_string()
+            }),
+            status: Status::BadRequest,
+        },
+        None => {
+            println!(
+                "Error while setting up two-factor authentication: {:?}",
+                err
+            );
+
+            ApiResponse {
+                json: json!({
+                    "message": "Failed to set up two-factor authentication, please try again"
+                }),
+                status: Status::InternalServerError,
+            }
+        }
+    }
+}
+
+#[post("/0/account/2fa/enroll")]
+pub fn two_factor_enroll(db: State<Database>, user_id: Username) -> ApiResponse {
+    match enroll(&user_id.username, &db) {
+        Ok((secret, otpauth_uri)) => ApiResponse {
+            json: json!({
+                "message": "Enter a code for this secret to finish enabling two-factor authentication",
+                "secret": secret,
+                "otpauthUri": otpauth_uri
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => two_factor_error_response(err),
+    }
+}
+
+#[post("/0/account/2fa/enroll", rank = 2)]
+pub fn two_factor_enroll_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
+
+#[post("/0/account/2fa/confirm", format = "json", data = "<code>")]
+pub fn two_factor_confirm(
+    db: State<Database>,
+    code: Json<TwoFactorCode>,
+    user_id: Username,
+) -> ApiResponse {
+    match confirm(&user_id.username, &code.code, &db) {
+        Ok(recovery_codes) => ApiResponse {
+            json: json!({
+                "message": "Successfully enabled two-factor authentication",
+                "recoveryCodes": recovery_codes
+            }),
+            status: Status::Ok,
+        },
+        Err(err) => two_factor_error_response(err),
+    }
+}
+
+#[post("/0/account/2fa/confirm", rank = 2)]
+pub fn two_factor_confirm_no_auth() -> ApiResponse {
+    ApiResponse {
+        json: json!({
+            "message": "please include a valid JWT token"
+        }),
+        status: Status::Unauthorized,
+    }
+}
@@ -15,36 +15,77 @@ use rocket::{
 use rocket::{http::ContentType, Data};
 use thiserror::Error;
 
-use crate::{auth::Username, Database};
+use crate::{
+    auth::Username,
+    market::{self, Listing, MarketError},
+    quota::{self, QuotaError},
+    Database,
+};
 use crate::{images::*, response::ApiResponse};
 
 #[derive(Error, Debug)]
 pub enum UploadError {
     #[error("Failed to parse field")]
     ParseError(#[from] ImageUploadTypeError),
+    #[error("Invalid {0} field")]
+    InvalidField(&'static str),
+    #[error("Invalid listing")]
+    ListingError(#[from] MarketError),
     #[error("Failed to read multipart form properly")]
     MultipartError(#[from] multer::Error),
     #[error("Missing fields")]
     MissingFields,
     #[error("Failed to add image")]
     FailedToAdd(String),
+    #[error("Over quota")]
+    QuotaExceeded(#[from] QuotaError),
 }
 
 #[rocket::async_trait]
 impl<'r> Responder<'r, 'static> for UploadError {
     fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
-        println!("Error while uploading: {:?}", self);
-
-        Response::build_from(
-            json!({
-                "message": "Failed to upload image"
-            })
-            .respond_to(&req)
-            .unwrap(),
-        )
-        .status(Status::InternalServerError)
-        .header(ContentType::JSON)
-        .ok()
+        let (json, status) = match &self {
+            UploadError::InvalidField(field) => (
+                json!({
+                    "message": self.to_string(),
+                    "field": field
+                }),
+                Status::BadRequest,
+            ),
+            UploadError::ListingError(err) => (
+                json!({
+                    "message": err.to_string()
+                }),
+                Status::BadRequest,
+            ),
+            UploadError::QuotaExceeded(err @ QuotaError::StorageExceeded { .. }) => (
+                json!({
+                    "message": err.to_string()
+                }),
+                Status::PayloadTooLarge,
+            ),
+            UploadError::QuotaExceeded(err @ QuotaError::ImageLimitReached { .. }) => (
+                json!({
+                    "message": err.to_string()
+                }),
+                Status::Forbidden,
+            ),
+            _ => {
+                println!("Error while uploading: {:?}", self);
+
+                (
+                    json!({
+                        "message": "Failed to upload image"
+                    }),
+                    Status::InternalServerError,
+                )
+            }
+        };
+
+        Response::build_from(json.respond_to(&req).unwrap())
+            .status(status)
+            .header(ContentType::JSON)
+            .ok()
     }
 }
 
@@ -80,9 +121,22 @@ pub async fn upload(
 ) -> Result<(), UploadError> {
     use futures::stream::once;
 
+    // Rejects users who are already at their quota before anything is read.  The upload's own size is checked once
+    // it's known.
+    check_upload_quota(&user_id.username, 0, &db)?;
+
     let limit: ByteUnit = 15.mebibytes();
     let constraints = Constraints::new()
-        .allowed_fields(vec!["image", "type", "title", "description"])
+        .allowed_fields(vec![
+            "image",
+            "type",
+            "title",
+            "description",
+            "visibility",
+            "price",
+            "currency",
+            "inventory",
+        ])
         .size_limit(
             SizeLimit::new()
                 // Set 15mb as size limit for the whole stream body.
@@ -92,7 +146,11 @@ pub async fn upload(
                 .for_field("image_type", 100)
                 .for_field("name", 30 * 1024)
                 .for_field("title", 30 * 1024)
-                .for_field("description", 30 * 1024),
+                .for_field("description", 30 * 1024)
+                .for_field("visibility", 100)
+                .for_field("price", 100)
+                .for_field("currency", 100)
+                .for_field("inventory", 100),
         );
 
     let reader = once(async move { data.open(limit).stream_to_vec().await });
@@ -103,6 +161,8 @@ pub async fn upload(
     let mut title: Option<String> = None;
     let mut description: Option<String> = None;
     let mut content_type: Option<String> = None;
+    let mut visibility: Option<Visibility> = None;
+    let mut listing = Listing::default();
     // let mut image_name: Option<String> = None;
 
     while let Ok(Some(field)) = multipart.next_field().await {
@@ -122,12 +182,43 @@ pub async fn upload(
                 "description" => {
                     description = Some(field.text().await?);
                 }
+                "visibility" => {
+                    visibility = Some(
+                        field
+                            .text()
+                            .await?
+                            .parse::<Visibility>()
+                            .map_err(|_| UploadError::InvalidField("visibility"))?,
+                    );
+                }
+                "price" => {
+                    listing.price = Some(
+                        field
+                            .text()
+                            .await?
+                            .parse::<u64>()
+                            .map_err(|_| UploadError::InvalidField("price"))?,
+                    );
+                }
+                "currency" => {
+                    listing.currency = Some(field.text().await?);
+                }
+                "inventory" => {
+                    listing.inventory = Some(
+                        field
+                            .text()
+                            .await?
+                            .parse::<u32>()
+                            .map_err(|_| UploadError::InvalidField("inventory"))?,
+                    );
+                }
                 _ => {}
             }
         }
     }
 
     if let (Some(image), Some(image_type)) = (image, image_type) {
+        let listing = listing.validate()?;
         let image_form = ImageForm {
             image,
             image_type,
@@ -135,13 +226,32 @@ pub async fn upload(
             description: description.unwrap_or_default(),
             mime: content_type.unwrap_or_default(),
             image_name: String::default(),
+            visibility: visibility.unwrap_or_default(),
+            listing,
         };
 
+        // Files sent directly are checked before they are stored.  Other files are checked when they're added.
+        if let ImageUploadType::File = image_form.image_type {
+            check_upload_quota(&user_id.username, image_form.image.len() as u64, &db)?;
+        }
+
         let image = build_image_for_foto(image_form, &user_id.username, &s3_client)
             .await
             .map_err(|err| UploadError::FailedToAdd(err.to_string()))?;
 
-        add_image_to_db(image, &db).map_err(|err| UploadError::FailedToAdd(err.to_string()))?;
+        if let Err(err) = add_image_to_db(image.clone(), &db) {
+            // The files were stored, but the image was never added, so nothing else would clean them up.
+            delete_stored_files(&image, &s3_client)
+                .await
+                .map_err(|err| UploadError::FailedToAdd(err.to_string()))?;
+
+            return Err(match err.downcast::<QuotaError>() {
+                Ok(err) => UploadError::QuotaExceeded(err),
+                Err(err) => UploadError::FailedToAdd(err.to_string()),
+            });
+        }
+
+        market::set_stock(&image, &db).map_err(|err| UploadError::FailedToAdd(err.to_string()))?;
     } else {
         return Err(UploadError::MissingFields);
     }
@@ -149,6 +259,14 @@ pub async fn upload(
     Ok(())
 }
 
+/// Checks a user's quota, telling over-quota errors apart from anything else going wrong.
+fn check_upload_quota(username: &str, bytes: u64, db: &Database) -> Result<(), UploadError> {
+    quota::check_quota(username, bytes, db).map_err(|err| match err.downcast::<QuotaError>() {
+        Ok(err) => UploadError::QuotaExceeded(err),
+        Err(err) => UploadError::FailedToAdd(err.to_string()),
+    })
+}
+
 #[post("/0/upload", rank = 2, format = "multipart/form-data", data = "<data>")]
 pub fn upload_no_auth(data: Data, boundary: Boundary) -> ApiResponse {
     let _data = data;
@@ -1,16 +1,34 @@
+use std::collections::HashSet;
+
 use chrono::Utc;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode, decode_header, encode, errors::ErrorKind, Header, Validation};
+use nanoid::nanoid;
+use ring::constant_time;
 use rocket::http::hyper::header::AUTHORIZATION;
+use rocket::http::{Cookie, CookieJar, Method, SameSite, Status};
 use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::consts;
+use crate::{
+    consts,
+    session::{self, Device},
+    user::{SuspendedError, User},
+    Database,
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct Claims {
     sub: String,
     exp: i64,
+    iat: i64,
+    iss: String,
+    aud: String,
+    /// The user's [`User::token_key`] at the time of issue.
+    key: String,
+    /// The [`Session`](crate::session::Session) the token belongs to.  Empty for challenge tokens.
+    sid: String,
 }
 
 #[derive(Error, Debug)]
@@ -22,33 +40,110 @@ pub enum AuthError {
     InvalidAuthHeader,
     #[error("Expired")]
     ExpiredAuth,
+    #[error("Revoked")]
+    RevokedAuth,
+    #[error("Unknown signing key")]
+    UnknownKey,
+    #[error("Missing or invalid CSRF token")]
+    InvalidCsrfToken,
+    #[error("Suspended")]
+    Suspended(#[from] SuspendedError),
 }
 
 pub struct Username {
     pub username: String,
+    /// The session of the token the request was made with.
+    pub session_id: String,
+}
+
+/// A logged in user who is listed in [`Admins`].
+pub struct Admin {
+    pub username: String,
 }
 
+/// Usernames allowed to use admin endpoints.  Managed by Rocket rather than read from the config directly, so tests
+/// can set their own.
+pub struct Admins(pub HashSet<String>);
+
 const BEARER: &str = "Bearer ";
 
-/// Creates a JWT given a UID.
-pub fn create_jwt(username: &str) -> anyhow::Result<String> {
-    let expiration_time = Utc::now()
-        .checked_add_signed(chrono::Duration::minutes(30))
+/// Holds a JWT for browsers, as an alternative to the `Authorization` header.
+pub const SESSION_COOKIE: &str = "foto_session";
+
+/// Holds a random token that browser pages must echo back in [`CSRF_HEADER`] on state-changing requests.  Other
+/// sites can make a browser send the session cookie, but can't read this cookie to forge the header.
+pub const CSRF_COOKIE: &str = "foto_csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// How long a user has to enter their two-factor code after entering their password.
+const CHALLENGE_MINUTES: i64 = 5;
+
+/// Creates a JWT given a [`User`], recording it as a new session for the device it was issued to.  Fails if the
+/// user is suspended.
+pub fn create_jwt(user: &User, device: &Device, db: &Database) -> anyhow::Result<String> {
+    user.check_not_suspended()?;
+
+    let expires = expiration_time(consts::JWT_CONFIG.expiry_minutes)?;
+    let session_id = session::create_session(&user.username, expires, device, db)?;
+
+    sign_claims(user, &consts::JWT_CONFIG.audience, expires, session_id)
+}
+
+/// Creates a short-lived JWT showing that a [`User`] entered their password, to be exchanged for a real JWT along
+/// with a two-factor code.  It has its own audience, so it can't be used to authorize anything else.
+pub fn create_challenge_jwt(user: &User) -> anyhow::Result<String> {
+    user.check_not_suspended()?;
+
+    sign_claims(
+        user,
+        &challenge_audience(),
+        expiration_time(CHALLENGE_MINUTES)?,
+        String::new(),
+    )
+}
+
+/// Checks a JWT from [`create_challenge_jwt`], returning the username it was issued to.
+pub fn verify_challenge_jwt(jwt: &str, db: &Database) -> Result<String, AuthError> {
+    verify_claims(jwt, &challenge_audience(), db).map(|claims| claims.sub)
+}
+
+fn challenge_audience() -> String {
+    format!("{}/2fa-challenge", consts::JWT_CONFIG.audience)
+}
+
+/// Returns the Unix timestamp a given number of minutes from now.
+fn expiration_time(lifetime_minutes: i64) -> anyhow::Result<i64> {
+    Ok(Utc::now()
+        .checked_add_signed(chrono::Duration::minutes(lifetime_minutes))
         .ok_or(anyhow::format_err!("Could not add time to JWT timestamp."))?
-        .timestamp();
+        .timestamp())
+}
 
+fn sign_claims(
+    user: &User,
+    audience: &str,
+    expiration_time: i64,
+    session_id: String,
+) -> anyhow::Result<String> {
     let claims = Claims {
-        sub: username.to_owned(),
+        sub: user.username.clone(),
         exp: expiration_time,
+        iat: Utc::now().timestamp(),
+        iss: consts::JWT_CONFIG.issuer.clone(),
+        aud: audience.to_string(),
+        key: user.token_key.clone(),
+        sid: session_id,
     };
 
-    let header = Header::new(Algorithm::HS512);
-    encode(
-        &header,
-        &claims,
-        &EncodingKey::from_secret(&consts::JWT_SECRET),
-    )
-    .map_err(|_| anyhow::format_err!("Could not encode JWT"))
+    let kid = consts::JWT_SIGNING_KEY_ID.as_str();
+    let key = consts::JWT_KEYS
+        .get(kid)
+        .ok_or(anyhow::format_err!("No JWT key with key ID {}", kid))?;
+
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(kid.to_string());
+    encode(&header, &claims, key.encoding_key())
+        .map_err(|_| anyhow::format_err!("Could not encode JWT"))
 }
 
 #[rocket::async_trait]
@@ -56,10 +151,21 @@ impl<'a, 'r> FromRequest<'a, 'r> for Username {
     type Error = AuthError;
 
     async fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
-        let username = authorize(req.headers());
+        let db = match req.guard::<State<Database>>().await.succeeded() {
+            Some(db) => db,
+            None => return Outcome::Forward(()),
+        };
 
-        match username {
-            Ok(username) => Outcome::Success(Username { username }),
+        match authorize(req, &db) {
+            Ok(claims) => Outcome::Success(Username {
+                username: claims.sub,
+                session_id: claims.sid,
+            }),
+            // Fail rather than forward, so the 403 catcher can tell the user why.
+            Err(AuthError::Suspended(err)) => {
+                req.local_cache(|| Some(err.clone()));
+                Outcome::Failure((Status::Forbidden, AuthError::Suspended(err)))
+            }
             Err(err) => {
                 // For now, we just forward and print the error...
                 println!("Auth error: {:?}", err);
@@ -69,23 +175,127 @@ impl<'a, 'r> FromRequest<'a, 'r> for Username {
     }
 }
 
-fn authorize(headers: &rocket::http::HeaderMap) -> Result<String, AuthError> {
-    let jwt = get_jwt(headers)?;
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
 
-    let decoded_jwt = decode::<Claims>(
-        &jwt,
-        &DecodingKey::from_secret(&consts::JWT_SECRET),
-        &Validation::new(Algorithm::HS512),
-    )
-    .map_err(|_| AuthError::InvalidAuthHeader)?;
+    async fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let admins = match req.guard::<State<Admins>>().await.succeeded() {
+            Some(admins) => admins,
+            None => return Outcome::Forward(()),
+        };
 
-    // Authorize not expired...
-    let current_time = Utc::now().timestamp();
-    if decoded_jwt.claims.exp <= current_time {
-        return Err(AuthError::ExpiredAuth);
+        match req.guard::<Username>().await {
+            Outcome::Success(user_id) if admins.0.contains(&user_id.username) => {
+                Outcome::Success(Admin {
+                    username: user_id.username,
+                })
+            }
+            _ => Outcome::Forward(()),
+        }
+    }
+}
+
+fn authorize(req: &Request<'_>, db: &Database) -> Result<Claims, AuthError> {
+    let jwt = match get_jwt(req.headers()) {
+        Ok(jwt) => jwt,
+        // Fall back to the session cookie, for browsers.
+        Err(AuthError::NoAuthHeader) => get_session_jwt(req)?,
+        Err(err) => return Err(err),
+    };
+
+    let claims = verify_claims(&jwt, &consts::JWT_CONFIG.audience, db)?;
+
+    // Every token is issued with a session, and stops working once that session is signed out.
+    match session::is_active(&claims.sid, &claims.sub, db) {
+        Ok(true) => Ok(claims),
+        _ => Err(AuthError::RevokedAuth),
     }
+}
+
+/// Sets the session and CSRF cookies for a newly issued JWT.
+pub fn set_session_cookies(cookies: &CookieJar<'_>, jwt: &str) {
+    cookies.add(
+        Cookie::build(SESSION_COOKIE, jwt.to_string())
+            .path("/")
+            .http_only(true)
+            .secure(*consts::SECURE_COOKIES)
+            .same_site(SameSite::Strict)
+            .finish(),
+    );
+
+    cookies.add(
+        Cookie::build(CSRF_COOKIE, nanoid!(32))
+            .path("/")
+            .secure(*consts::SECURE_COOKIES)
+            .same_site(SameSite::Strict)
+            .finish(),
+    );
+}
 
-    Ok(decoded_jwt.claims.sub)
+pub fn remove_session_cookies(cookies: &CookieJar<'_>) {
+    cookies.remove(Cookie::build(SESSION_COOKIE, "").path("/").finish());
+    cookies.remove(Cookie::build(CSRF_COOKIE, "").path("/").finish());
+}
+
+/// Gets the JWT from the session cookie.  Requests that can change state must also pass the CSRF check.
+fn get_session_jwt(req: &Request<'_>) -> Result<String, AuthError> {
+    let cookies = req.cookies();
+    let jwt = cookies
+        .get(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .ok_or(AuthError::NoAuthHeader)?;
+
+    if !matches!(req.method(), Method::Get | Method::Head | Method::Options) {
+        let csrf_cookie = cookies
+            .get(CSRF_COOKIE)
+            .map(|cookie| cookie.value().to_string())
+            .ok_or(AuthError::InvalidCsrfToken)?;
+        let csrf_header = req
+            .headers()
+            .get_one(CSRF_HEADER)
+            .ok_or(AuthError::InvalidCsrfToken)?;
+
+        constant_time::verify_slices_are_equal(csrf_cookie.as_bytes(), csrf_header.as_bytes())
+            .map_err(|_| AuthError::InvalidCsrfToken)?;
+    }
+
+    Ok(jwt)
+}
+
+/// Validates a JWT meant for the given audience, returning its claims.
+fn verify_claims(jwt: &str, audience: &str, db: &Database) -> Result<Claims, AuthError> {
+    // Use the key the token says it was signed with, so tokens from a rotated-out key keep working while that key
+    // is still configured.
+    let kid = decode_header(jwt)
+        .map_err(|_| AuthError::InvalidAuthHeader)?
+        .kid
+        .unwrap_or_else(|| consts::DEFAULT_JWT_KEY_ID.to_string());
+    let key = consts::JWT_KEYS.get(&kid).ok_or(AuthError::UnknownKey)?;
+
+    // Authorize not expired, and meant for us...
+    let mut validation = Validation::new(key.algorithm);
+    validation.leeway = consts::JWT_CONFIG.leeway_seconds;
+    validation.iss = Some(consts::JWT_CONFIG.issuer.clone());
+    validation.set_audience(&[audience]);
+
+    let decoded_jwt = decode::<Claims>(jwt, &key.decoding_key(), &validation).map_err(|err| {
+        match err.kind() {
+            ErrorKind::ExpiredSignature => AuthError::ExpiredAuth,
+            _ => AuthError::InvalidAuthHeader,
+        }
+    })?;
+
+    let claims = decoded_jwt.claims;
+
+    // Authorize not revoked by a credential change or by deleting the user.
+    match db.users.get(&claims.sub) {
+        Ok(Some(user)) if user.token_key == claims.key => {
+            user.check_not_suspended()?;
+            Ok(claims)
+        }
+        _ => Err(AuthError::RevokedAuth),
+    }
 }
 
 fn get_jwt(headers: &rocket::http::HeaderMap) -> Result<String, AuthError> {
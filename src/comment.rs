@@ -0,0 +1,338 @@
+//! Threaded comments on images.  Each comment is stored under its image's ID and its own, so an image's comments can
+//! be read or removed without touching any others.
+
+use std::collections::HashMap;
+
+use chrono::Utc;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{consts, images, Database};
+
+/// Longer comments are rejected.
+const MAX_BODY_LEN: usize = 2000;
+
+/// How deeply replies can nest.  Top-level comments are at depth zero.
+const MAX_DEPTH: usize = 8;
+
+/// Separates the image ID from the comment ID in keys.  Neither can contain it, so one image's keys never overlap
+/// another's.
+const KEY_SEPARATOR: char = '\0';
+
+/// Listings return this many threads if no limit is given.
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+/// Listings return at most this many threads.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Comment {
+    pub id: String,
+    pub image_id: String,
+    /// The comment this replies to, if any.
+    pub parent_id: Option<String>,
+    pub author: String,
+    pub body: String,
+    /// Unix timestamp.
+    pub created: i64,
+    pub edited: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewComment {
+    body: String,
+    parent_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommentEdit {
+    body: String,
+}
+
+/// A comment with its replies, oldest first.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommentThread {
+    #[serde(flatten)]
+    pub comment: Comment,
+    pub replies: Vec<CommentThread>,
+}
+
+/// One page of an image's comment threads.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommentPage {
+    pub comments: Vec<CommentThread>,
+    /// How many threads there are in total.
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum CommentError {
+    #[error("Could not find image")]
+    UnknownImage,
+    #[error("Could not find comment")]
+    UnknownComment,
+    #[error("The comment being replied to could not be found")]
+    UnknownParent,
+    #[error("Comments can't be empty or longer than 2000 characters")]
+    InvalidBody,
+    #[error("Replies can't be nested more than 8 deep")]
+    TooDeep,
+    #[error("Only the author or a moderator can change this comment")]
+    NotAllowed,
+}
+
+/// Whether a user can edit and delete anyone's comments.  Admins are moderators too.
+pub fn is_moderator(username: &str) -> bool {
+    consts::MODERATORS.contains(username) || consts::ADMINS.contains(username)
+}
+
+fn comment_key(image_id: &str, comment_id: &str) -> String {
+    format!("{}{}{}", image_id, KEY_SEPARATOR, comment_id)
+}
+
+pub fn create_comment(
+    image_id: &str,
+    author: &str,
+    new_comment: NewComment,
+    db: &Database,
+) -> anyhow::Result<Comment> {
+    check_image(image_id, Some(author), db)?;
+
+    let comment = Comment {
+        id: nanoid!(11),
+        image_id: image_id.to_string(),
+        parent_id: new_comment.parent_id,
+        author: author.to_string(),
+        body: validate_body(new_comment.body)?,
+        created: Utc::now().timestamp(),
+        edited: false,
+    };
+
+    let stored = comment.clone();
+    db.comments
+        .transaction(move |tx_db| {
+            // Walks up from the parent, so a reply can't go missing under a parent deleted at the same time, or nest
+            // too deeply.
+            let mut ancestor_id = stored.parent_id.clone();
+            let mut depth = 0;
+            while let Some(id) = ancestor_id {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Ok(Err(anyhow::Error::from(CommentError::TooDeep)));
+                }
+
+                ancestor_id = match tx_db.get(comment_key(&stored.image_id, &id).as_bytes())? {
+                    Ok(Some(ancestor)) => ancestor.parent_id,
+                    Ok(None) => return Ok(Err(anyhow::Error::from(CommentError::UnknownParent))),
+                    Err(err) => return Ok(Err(anyhow::Error::from(err))),
+                };
+            }
+
+            match tx_db.insert(
+                comment_key(&stored.image_id, &stored.id).as_bytes(),
+                stored.clone(),
+            )? {
+                Ok(_) => Ok(Ok(())),
+                Err(err) => Ok(Err(anyhow::Error::from(err))),
+            }
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    Ok(comment)
+}
+
+/// Lists a page of an image's comment threads, oldest first, if the viewer is allowed to see the image.
+pub fn list_comments(
+    image_id: &str,
+    viewer: Option<&str>,
+    offset: usize,
+    limit: usize,
+    db: &Database,
+) -> anyhow::Result<CommentPage> {
+    check_image(image_id, viewer, db)?;
+
+    let mut comments = image_comments(image_id, db)?;
+    comments.sort_by_key(|comment| comment.created);
+
+    let mut top_level = vec![];
+    let mut replies: HashMap<String, Vec<Comment>> = HashMap::new();
+    for comment in comments {
+        match &comment.parent_id {
+            Some(parent_id) => replies.entry(parent_id.clone()).or_default().push(comment),
+            None => top_level.push(comment),
+        }
+    }
+
+    let total = top_level.len();
+    let limit = limit.min(MAX_PAGE_SIZE);
+
+    let comments = top_level
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|comment| build_thread(comment, &mut replies))
+        .collect();
+
+    Ok(CommentPage {
+        comments,
+        total,
+        offset,
+        limit,
+    })
+}
+
+pub fn edit_comment(
+    image_id: &str,
+    comment_id: &str,
+    editor: &str,
+    edit: CommentEdit,
+    db: &Database,
+) -> anyhow::Result<Comment> {
+    check_image(image_id, Some(editor), db)?;
+
+    let body = validate_body(edit.body)?;
+    let moderator = is_moderator(editor);
+    let key = comment_key(image_id, comment_id);
+    let editor = editor.to_string();
+
+    // In a transaction, so an edit can't bring back a comment deleted at the same time.
+    let comment = db
+        .comments
+        .transaction(move |tx_db| {
+            let mut comment = match tx_db.get(key.as_bytes())? {
+                Ok(Some(comment)) => comment,
+                Ok(None) => return Ok(Err(anyhow::Error::from(CommentError::UnknownComment))),
+                Err(err) => return Ok(Err(anyhow::Error::from(err))),
+            };
+            if comment.author != editor && !moderator {
+                return Ok(Err(anyhow::Error::from(CommentError::NotAllowed)));
+            }
+
+            comment.body = body.clone();
+            comment.edited = true;
+
+            match tx_db.insert(key.as_bytes(), comment.clone())? {
+                Ok(_) => Ok(Ok(comment)),
+                Err(err) => Ok(Err(anyhow::Error::from(err))),
+            }
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    Ok(comment)
+}
+
+/// Deletes a comment, along with all the replies under it.
+pub fn delete_comment(
+    image_id: &str,
+    comment_id: &str,
+    deleter: &str,
+    db: &Database,
+) -> anyhow::Result<()> {
+    check_image(image_id, Some(deleter), db)?;
+
+    let comment = db
+        .comments
+        .get(comment_key(image_id, comment_id).as_bytes())?
+        .ok_or(CommentError::UnknownComment)?;
+    if comment.author != deleter && !is_moderator(deleter) {
+        Err(CommentError::NotAllowed)?
+    }
+
+    let mut replies: HashMap<String, Vec<String>> = HashMap::new();
+    for reply in image_comments(image_id, db)? {
+        if let Some(parent_id) = reply.parent_id {
+            replies.entry(parent_id).or_default().push(reply.id);
+        }
+    }
+
+    let mut removed = vec![comment.id];
+    let mut index = 0;
+    while index < removed.len() {
+        if let Some(children) = replies.remove(&removed[index]) {
+            removed.extend(children);
+        }
+        index += 1;
+    }
+
+    let keys: Vec<String> = removed.iter().map(|id| comment_key(image_id, id)).collect();
+    db.comments
+        .transaction(move |tx_db| {
+            for key in &keys {
+                if let Err(err) = tx_db.remove(key.as_bytes())? {
+                    return Ok(Err(anyhow::Error::from(err)));
+                }
+            }
+
+            Ok(Ok(()))
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    Ok(())
+}
+
+/// Removes all comments on a deleted image.
+pub fn remove_image_comments(image_id: &str, db: &Database) -> anyhow::Result<()> {
+    for entry in db
+        .comments
+        .scan_prefix(comment_key(image_id, "").as_bytes())
+    {
+        let (key, _) = entry?;
+        db.comments.remove(key)?;
+    }
+
+    Ok(())
+}
+
+fn image_comments(image_id: &str, db: &Database) -> anyhow::Result<Vec<Comment>> {
+    let mut comments = vec![];
+
+    for entry in db
+        .comments
+        .scan_prefix(comment_key(image_id, "").as_bytes())
+    {
+        let (_, comment) = entry?;
+        comments.push(comment);
+    }
+
+    Ok(comments)
+}
+
+/// Comments follow the image's visibility, so only users who can see an image can see or write its comments.
+fn check_image(image_id: &str, viewer: Option<&str>, db: &Database) -> anyhow::Result<()> {
+    match images::get_image(image_id, viewer, db)? {
+        Some(_) => Ok(()),
+        None => Err(CommentError::UnknownImage)?,
+    }
+}
+
+fn validate_body(body: String) -> Result<String, CommentError> {
+    let body = body.trim().to_string();
+
+    if body.is_empty() || body.chars().count() > MAX_BODY_LEN {
+        Err(CommentError::InvalidBody)
+    } else {
+        Ok(body)
+    }
+}
+
+/// Builds a comment's thread from the replies to each comment, taking its replies out as it goes.
+fn build_thread(comment: Comment, replies: &mut HashMap<String, Vec<Comment>>) -> CommentThread {
+    let children = replies
+        .remove(&comment.id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|reply| build_thread(reply, replies))
+        .collect();
+
+    CommentThread {
+        comment,
+        replies: children,
+    }
+}
@@ -1,10 +1,239 @@
-use serde::Deserialize;
+use std::{collections::HashMap, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub salt: String,
-    pub jwt_secret: String,
+    /// Used as the key with the key ID "default", alongside any keys in `jwt`.
+    pub jwt_secret: Option<String>,
     pub hamming_distance: Option<serde_json::Number>,
     pub s3_bucket_name: Option<String>,
+    pub login_throttle: Option<LoginThrottleConfig>,
+    /// Proxies whose `X-Real-IP` header is trusted to give the client's IP.
+    pub trusted_proxies: Option<Vec<IpAddr>>,
+    pub account_policy: Option<AccountPolicyConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub oidc: Option<OidcConfig>,
+    /// Whether cookies are only sent over HTTPS.
+    pub secure_cookies: Option<bool>,
+    pub admins: Option<Vec<String>>,
+    /// Users who can edit and delete anyone's comments.
+    pub moderators: Option<Vec<String>>,
+    pub registration_mode: Option<RegistrationMode>,
+    /// In base64.  Used to sign share links.
+    pub share_link_secret: Option<String>,
+    pub watermark: Option<WatermarkConfig>,
+    pub quotas: Option<QuotaConfig>,
+    pub metadata_privacy: Option<MetadataPrivacy>,
+}
+
+/// Who can create new accounts.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RegistrationMode {
+    /// Anyone can register.
+    Open,
+    /// Registering requires an invite code from an admin.
+    InviteOnly,
+    /// Nobody can register.
+    Closed,
+}
+
+impl Default for RegistrationMode {
+    fn default() -> Self {
+        RegistrationMode::Open
+    }
+}
+
+/// Controls how failed logins are throttled.  Failures are counted separately per username and per client IP.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LoginThrottleConfig {
+    /// How many failures are allowed before any delay is applied.
+    pub free_attempts: u32,
+    /// The delay after the first failure past `free_attempts`, doubling with every further failure.
+    pub base_delay_seconds: u64,
+    /// The longest delay applied before a lockout.
+    pub max_delay_seconds: u64,
+    /// How many failures cause a full lockout.
+    pub lockout_threshold: u32,
+    pub lockout_seconds: u64,
+    /// How long without a failure before the count is reset.
+    pub reset_after_seconds: u64,
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        LoginThrottleConfig {
+            free_attempts: 3,
+            base_delay_seconds: 1,
+            max_delay_seconds: 60,
+            lockout_threshold: 10,
+            lockout_seconds: 15 * 60,
+            reset_after_seconds: 60 * 60,
+        }
+    }
+}
+
+/// Controls which usernames and passwords are accepted.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AccountPolicyConfig {
+    pub username_min_length: usize,
+    pub username_max_length: usize,
+    /// Symbols allowed in usernames, on top of letters and numbers.
+    pub username_symbols: String,
+    /// Whether letters and numbers outside of ASCII are allowed in usernames.
+    pub username_allow_unicode: bool,
+    pub password_min_length: usize,
+    /// Whether to reject passwords found in the bundled list of common passwords.
+    pub deny_common_passwords: bool,
+}
+
+impl Default for AccountPolicyConfig {
+    fn default() -> Self {
+        AccountPolicyConfig {
+            username_min_length: 3,
+            username_max_length: 32,
+            username_symbols: "_-.".to_string(),
+            username_allow_unicode: false,
+            password_min_length: 8,
+            deny_common_passwords: true,
+        }
+    }
+}
+
+/// Controls how JWTs are signed and validated.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct JwtConfig {
+    /// All keys that tokens are accepted from.  Keeping an old key here after rotating lets its tokens expire naturally.
+    pub keys: Vec<JwtKeyConfig>,
+    /// The ID of the key new tokens are signed with.  Defaults to the first key in `keys`.
+    pub signing_key_id: Option<String>,
+    pub expiry_minutes: i64,
+    pub issuer: String,
+    pub audience: String,
+    /// How much clock skew is tolerated when checking expiry.
+    pub leeway_seconds: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            keys: vec![],
+            signing_key_id: None,
+            expiry_minutes: 30,
+            issuer: "foto".to_string(),
+            audience: "foto".to_string(),
+            leeway_seconds: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JwtKeyConfig {
+    /// The key ID, sent as the `kid` header of tokens signed with this key.
+    pub kid: String,
+    /// An HMAC secret in base64, signing with HS512.
+    pub secret: Option<String>,
+    /// A PEM encoded RSA private key, signing with RS256.  Its public key is published as a JWK.
+    pub private_key_pem: Option<String>,
+}
+
+/// An external OpenID Connect provider that users can log in through.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcConfig {
+    /// The provider's issuer URL, which its discovery document is found under.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the provider sends users back to, which should end up at `/api/0/oidc/callback`.
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: String,
+}
+
+fn default_oidc_scopes() -> String {
+    "openid profile email".to_string()
+}
+
+/// Controls the watermarked previews shown in place of protected images.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WatermarkConfig {
+    /// Text to overlay.  Needs `font_path` to be set.
+    pub text: Option<String>,
+    /// A TrueType or OpenType font to draw `text` in.
+    pub font_path: Option<String>,
+    /// An image to overlay, used instead of `text` if both are set.
+    pub logo_path: Option<String>,
+    pub position: WatermarkPosition,
+    /// From 0 (invisible) to 1 (opaque).
+    pub opacity: f32,
+    /// Previews are downscaled to fit in a square this many pixels wide.
+    pub max_dimension: u32,
+}
+
+impl Default for WatermarkConfig {
+    fn default() -> Self {
+        WatermarkConfig {
+            text: None,
+            font_path: None,
+            logo_path: None,
+            position: WatermarkPosition::BottomRight,
+            opacity: 0.5,
+            max_dimension: 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// Limits how much each user can upload.  Everything is unlimited by default.
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QuotaConfig {
+    #[serde(flatten)]
+    pub default_limits: QuotaLimits,
+    /// Limits for particular users, by username.  Limits they leave out fall back to the defaults.
+    pub users: HashMap<String, QuotaLimits>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QuotaLimits {
+    /// The most bytes of original files a user can store.
+    pub max_bytes: Option<u64>,
+    pub max_images: Option<u64>,
+}
+
+/// Which metadata is removed from originals before they are stored.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MetadataPrivacy {
+    /// Originals are stored exactly as uploaded.
+    Keep,
+    /// Only GPS coordinates are removed.
+    StripGps,
+    /// EXIF, XMP and IPTC metadata are all removed.
+    StripAll,
+}
+
+impl Default for MetadataPrivacy {
+    fn default() -> Self {
+        MetadataPrivacy::StripGps
+    }
 }
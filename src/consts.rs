@@ -1,6 +1,26 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
 use once_cell::sync::Lazy;
+use ring::hmac;
+
+use crate::{
+    config::{
+        AccountPolicyConfig, JwtConfig, LoginThrottleConfig, MetadataPrivacy, QuotaConfig,
+        RegistrationMode,
+    },
+    jwt_keys::JwtKey,
+    user::UserDataBaseConfig,
+    watermark::Watermark,
+};
 
-use crate::user::UserDataBaseConfig;
+/// Characters for codes that users have to type in, leaving out ones that are easily confused.
+pub const READABLE_CODE_ALPHABET: [char; 32] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'j', 'k', 'm', 'n', 'p', 'q', 'r', 's', 't', 'u', 'v',
+    'w', 'x', 'y', 'z', '2', '3', '4', '5', '6', '7', '8', '9', '0',
+];
 
 pub static CONFIG: Lazy<crate::config::Config> = Lazy::new(|| {
     let config: crate::config::Config = serde_json::from_str(
@@ -18,8 +38,50 @@ pub static USER_DATABASE_CONFIG: Lazy<UserDataBaseConfig> = Lazy::new(|| UserDat
     db_salt_component: base64::decode(CONFIG.salt.clone()).unwrap(),
 });
 
-pub static JWT_SECRET: Lazy<Vec<u8>> =
-    Lazy::new(|| base64::decode(CONFIG.jwt_secret.clone()).unwrap());
+/// Defaults to [`JwtConfig::default`].
+pub static JWT_CONFIG: Lazy<JwtConfig> = Lazy::new(|| CONFIG.jwt.clone().unwrap_or_default());
+
+/// The key ID used for `jwtSecret`, and for tokens without a `kid` header.
+pub const DEFAULT_JWT_KEY_ID: &str = "default";
+
+/// JWT keys by key ID.
+pub static JWT_KEYS: Lazy<HashMap<String, JwtKey>> = Lazy::new(|| {
+    let mut keys: HashMap<String, JwtKey> = JWT_CONFIG
+        .keys
+        .iter()
+        .map(|key| {
+            (
+                key.kid.clone(),
+                JwtKey::from_config(key).expect("Could not load JWT key."),
+            )
+        })
+        .collect();
+
+    if let Some(jwt_secret) = &CONFIG.jwt_secret {
+        keys.insert(
+            DEFAULT_JWT_KEY_ID.to_string(),
+            JwtKey::hmac(
+                DEFAULT_JWT_KEY_ID,
+                base64::decode(jwt_secret.clone()).unwrap(),
+            ),
+        );
+    }
+
+    if keys.is_empty() {
+        panic!("Either jwtSecret or jwt.keys must be set.");
+    }
+
+    keys
+});
+
+/// The key ID new JWTs are signed with.
+pub static JWT_SIGNING_KEY_ID: Lazy<String> = Lazy::new(|| {
+    JWT_CONFIG
+        .signing_key_id
+        .clone()
+        .or_else(|| JWT_CONFIG.keys.first().map(|key| key.kid.clone()))
+        .unwrap_or_else(|| DEFAULT_JWT_KEY_ID.to_string())
+});
 
 /// Defaults to 10.
 pub static HAMMING_DISTANCE: Lazy<u64> = Lazy::new(|| {
@@ -29,3 +91,76 @@ pub static HAMMING_DISTANCE: Lazy<u64> = Lazy::new(|| {
         10
     }
 });
+
+/// Defaults to [`LoginThrottleConfig::default`].
+pub static LOGIN_THROTTLE: Lazy<LoginThrottleConfig> =
+    Lazy::new(|| CONFIG.login_throttle.clone().unwrap_or_default());
+
+/// Defaults to none, so the client IP is always taken from the connection.
+pub static TRUSTED_PROXIES: Lazy<HashSet<IpAddr>> = Lazy::new(|| {
+    CONFIG
+        .trusted_proxies
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .collect()
+});
+
+/// Defaults to [`AccountPolicyConfig::default`].
+pub static ACCOUNT_POLICY: Lazy<AccountPolicyConfig> =
+    Lazy::new(|| CONFIG.account_policy.clone().unwrap_or_default());
+
+/// Defaults to true.
+pub static SECURE_COOKIES: Lazy<bool> = Lazy::new(|| CONFIG.secure_cookies.unwrap_or(true));
+
+/// Defaults to open.
+pub static REGISTRATION_MODE: Lazy<RegistrationMode> =
+    Lazy::new(|| CONFIG.registration_mode.unwrap_or_default());
+
+/// Signs share links.  Defaults to a key derived from `salt`, so links keep working across restarts.
+pub static SHARE_LINK_KEY: Lazy<hmac::Key> = Lazy::new(|| {
+    let secret = match &CONFIG.share_link_secret {
+        Some(secret) => base64::decode(secret).expect("shareLinkSecret must be base64."),
+        None => hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, &USER_DATABASE_CONFIG.db_salt_component),
+            b"foto share links",
+        )
+        .as_ref()
+        .to_vec(),
+    };
+
+    hmac::Key::new(hmac::HMAC_SHA256, &secret)
+});
+
+/// Defaults to a plain downscaled preview, from [`WatermarkConfig::default`](crate::config::WatermarkConfig).
+pub static WATERMARK: Lazy<Watermark> = Lazy::new(|| {
+    Watermark::from_config(&CONFIG.watermark.clone().unwrap_or_default())
+        .expect("Could not load watermark.")
+});
+
+/// Defaults to only stripping GPS coordinates.
+pub static METADATA_PRIVACY: Lazy<MetadataPrivacy> =
+    Lazy::new(|| CONFIG.metadata_privacy.unwrap_or_default());
+
+/// Defaults to no limits.
+pub static QUOTAS: Lazy<QuotaConfig> = Lazy::new(|| CONFIG.quotas.clone().unwrap_or_default());
+
+/// Usernames allowed to use admin endpoints.  Defaults to none.
+pub static ADMINS: Lazy<HashSet<String>> = Lazy::new(|| {
+    CONFIG
+        .admins
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .collect()
+});
+
+/// Usernames allowed to moderate comments, on top of admins.  Defaults to none.
+pub static MODERATORS: Lazy<HashSet<String>> = Lazy::new(|| {
+    CONFIG
+        .moderators
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .collect()
+});
@@ -0,0 +1,243 @@
+//! Discounts sellers can run on their images, either a percentage or a fixed amount off, for one image, one album or
+//! everything they sell.
+
+use chrono::Utc;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{images::Image, market, Database};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Discount {
+    pub id: String,
+    pub seller: String,
+    /// Set for percentage discounts, from 1 to 100.
+    pub percent_off: Option<u8>,
+    /// Set for fixed-amount discounts, in the currency's smallest unit.
+    pub amount_off: Option<u64>,
+    /// The currency of `amount_off`.  Fixed-amount discounts only apply to images priced in it.
+    pub currency: Option<String>,
+    /// Limits the discount to one image.
+    pub image_id: Option<String>,
+    /// Limits the discount to the seller's images in one album.  If neither this nor `image_id` is set, the discount
+    /// applies to all of the seller's images.
+    pub album_id: Option<String>,
+    /// Unix timestamp.  Starts immediately if not set.
+    pub starts: Option<i64>,
+    /// Unix timestamp.  Never ends if not set.
+    pub ends: Option<i64>,
+    /// How many purchases can use the discount.  Unlimited if not set.
+    pub max_uses: Option<u32>,
+    /// Unix timestamp.
+    pub created: i64,
+}
+
+impl Discount {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.starts.map_or(true, |starts| starts <= now)
+            && self.ends.map_or(true, |ends| now < ends)
+    }
+
+    /// The price after the discount, or `None` if the discount can't apply to a price in this currency.
+    pub fn apply(&self, price: u64, currency: &str) -> Option<u64> {
+        match (self.percent_off, self.amount_off, &self.currency) {
+            // Worked out in u128 so large prices can't overflow.  The amount off is at most the price, so it fits
+            // back in a u64.
+            (Some(percent_off), _, _) => {
+                Some(price - (price as u128 * percent_off as u128 / 100) as u64)
+            }
+            (_, Some(amount_off), Some(discount_currency)) if discount_currency == currency => {
+                Some(price.saturating_sub(amount_off))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewDiscount {
+    percent_off: Option<u8>,
+    amount_off: Option<u64>,
+    currency: Option<String>,
+    image_id: Option<String>,
+    album_id: Option<String>,
+    starts: Option<i64>,
+    ends: Option<i64>,
+    max_uses: Option<u32>,
+}
+
+/// A discount along with how many times it has been used.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscountUsage {
+    #[serde(flatten)]
+    pub discount: Discount,
+    pub uses: u32,
+}
+
+/// Separates the seller from the discount ID in keys.  Usernames can't contain it, so one seller's keys never
+/// overlap another's.
+const KEY_SEPARATOR: char = '\0';
+
+fn discount_key(seller: &str, id: &str) -> String {
+    format!("{}{}{}", seller, KEY_SEPARATOR, id)
+}
+
+#[derive(Error, Debug)]
+pub enum DiscountError {
+    #[error("Set either a percentage from 1 to 100, or an amount more than zero with a three letter currency code")]
+    InvalidAmount,
+    #[error("Discounts have to end after they start")]
+    InvalidPeriod,
+    #[error("Discounts can be limited to an image or an album, but not both")]
+    InvalidScope,
+    #[error("Could not find image")]
+    UnknownImage,
+    #[error("Could not find album")]
+    UnknownAlbum,
+    #[error("Could not find discount")]
+    UnknownDiscount,
+}
+
+pub fn create_discount(
+    seller: &str,
+    new_discount: NewDiscount,
+    db: &Database,
+) -> anyhow::Result<Discount> {
+    let currency = new_discount
+        .currency
+        .map(|currency| currency.to_uppercase());
+
+    match (new_discount.percent_off, new_discount.amount_off, &currency) {
+        (Some(percent_off), None, None) if (1..=100).contains(&percent_off) => {}
+        (None, Some(amount_off), Some(currency))
+            if amount_off > 0 && market::is_currency_code(currency) => {}
+        _ => Err(DiscountError::InvalidAmount)?,
+    }
+
+    if let (Some(starts), Some(ends)) = (new_discount.starts, new_discount.ends) {
+        if ends <= starts {
+            Err(DiscountError::InvalidPeriod)?
+        }
+    }
+
+    match (&new_discount.image_id, &new_discount.album_id) {
+        (Some(_), Some(_)) => Err(DiscountError::InvalidScope)?,
+        (Some(image_id), None) => match db.images.get(image_id.as_bytes())? {
+            Some(image) if image.is_owned_by(seller) => {}
+            _ => Err(DiscountError::UnknownImage)?,
+        },
+        (None, Some(album_id)) => match db.albums.get(album_id.as_bytes())? {
+            Some(album) if album.owner == seller => {}
+            _ => Err(DiscountError::UnknownAlbum)?,
+        },
+        (None, None) => {}
+    }
+
+    let discount = Discount {
+        id: nanoid!(11),
+        seller: seller.to_string(),
+        percent_off: new_discount.percent_off,
+        amount_off: new_discount.amount_off,
+        currency,
+        image_id: new_discount.image_id,
+        album_id: new_discount.album_id,
+        starts: new_discount.starts,
+        ends: new_discount.ends,
+        max_uses: new_discount.max_uses,
+        created: Utc::now().timestamp(),
+    };
+
+    db.discounts.insert(
+        discount_key(seller, &discount.id).as_bytes(),
+        discount.clone(),
+    )?;
+
+    Ok(discount)
+}
+
+/// Lists a seller's discounts, newest first.
+pub fn list_discounts(seller: &str, db: &Database) -> anyhow::Result<Vec<DiscountUsage>> {
+    let mut discounts = vec![];
+
+    for entry in db
+        .discounts
+        .scan_prefix(discount_key(seller, "").as_bytes())
+    {
+        let (_, discount) = entry?;
+        discounts.push(DiscountUsage {
+            uses: market::discount_uses(&discount.id, db)?,
+            discount,
+        });
+    }
+
+    discounts.sort_by(|a, b| b.discount.created.cmp(&a.discount.created));
+
+    Ok(discounts)
+}
+
+pub fn delete_discount(id: &str, seller: &str, db: &Database) -> anyhow::Result<()> {
+    match db.discounts.remove(discount_key(seller, id).as_bytes())? {
+        Some(_) => {
+            market::remove_discount_uses(id, db)?;
+
+            Ok(())
+        }
+        None => Err(DiscountError::UnknownDiscount)?,
+    }
+}
+
+/// Deletes all of a seller's discounts.
+pub fn delete_user_discounts(seller: &str, db: &Database) -> anyhow::Result<()> {
+    for usage in list_discounts(seller, db)? {
+        delete_discount(&usage.discount.id, seller, db)?;
+    }
+
+    Ok(())
+}
+
+/// Finds the discounts that apply to an image right now, with the price each gives, cheapest first.  Usage limits
+/// aren't checked, as they have to be checked when the discount is used.
+pub fn applicable_discounts(image: &Image, db: &Database) -> anyhow::Result<Vec<(Discount, u64)>> {
+    let listing = image.listing();
+    let (price, currency) = match (listing.price, listing.currency) {
+        (Some(price), Some(currency)) => (price, currency),
+        _ => return Ok(vec![]),
+    };
+
+    let now = Utc::now().timestamp();
+    let album_ids = db
+        .image_albums
+        .get(image.id().as_bytes())?
+        .unwrap_or_default();
+    let mut discounts = vec![];
+
+    for entry in db
+        .discounts
+        .scan_prefix(discount_key(image.owner(), "").as_bytes())
+    {
+        let (_, discount) = entry?;
+        if !discount.is_active(now) {
+            continue;
+        }
+
+        let in_scope = match (&discount.image_id, &discount.album_id) {
+            (Some(image_id), _) => image_id == image.id(),
+            (_, Some(album_id)) => album_ids.contains(album_id),
+            (None, None) => true,
+        };
+
+        if in_scope {
+            if let Some(discounted_price) = discount.apply(price, &currency) {
+                discounts.push((discount, discounted_price));
+            }
+        }
+    }
+
+    discounts.sort_by_key(|(_, discounted_price)| *discounted_price);
+
+    Ok(discounts)
+}
@@ -0,0 +1,180 @@
+//! Images users have liked.  Each image's like count is kept alongside, so it doesn't have to be counted up every time
+//! the image is shown.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    images::{self, Image},
+    Database,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Favorite {
+    pub username: String,
+    pub image_id: String,
+    /// Unix timestamp.
+    pub created: i64,
+}
+
+/// An image along with how many users have liked it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LikedImage {
+    #[serde(flatten)]
+    pub image: Image,
+    pub likes: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum FavoriteError {
+    #[error("Could not find image")]
+    UnknownImage,
+}
+
+/// Separates the parts of keys.  Usernames can't contain it, so one user's keys never overlap another's.
+const KEY_SEPARATOR: char = '\0';
+
+fn favorite_key(username: &str, image_id: &str) -> String {
+    format!("{}{}{}", username, KEY_SEPARATOR, image_id)
+}
+
+/// The key in `image_favorites`, which indexes favorites by image.
+fn image_favorite_key(image_id: &str, username: &str) -> String {
+    format!("{}{}{}", image_id, KEY_SEPARATOR, username)
+}
+
+/// Likes an image, returning its new like count.  Liking an image again does nothing.
+pub fn like_image(username: &str, image_id: &str, db: &Database) -> anyhow::Result<u64> {
+    if images::get_image(image_id, Some(username), db)?.is_none() {
+        Err(FavoriteError::UnknownImage)?
+    }
+
+    let favorite = Favorite {
+        username: username.to_string(),
+        image_id: image_id.to_string(),
+        created: Utc::now().timestamp(),
+    };
+
+    // Indexed first, so a like is never stored without its index entry.
+    db.image_favorites.insert(
+        image_favorite_key(image_id, username).as_bytes(),
+        username.to_string(),
+    )?;
+
+    match db
+        .favorites
+        .insert(favorite_key(username, image_id).as_bytes(), favorite)?
+    {
+        Some(_) => like_count(image_id, db),
+        None => update_like_count(image_id, 1, db),
+    }
+}
+
+/// Unlikes an image, returning its new like count.  Unliking an image that isn't liked does nothing.
+pub fn unlike_image(username: &str, image_id: &str, db: &Database) -> anyhow::Result<u64> {
+    let removed = db
+        .favorites
+        .remove(favorite_key(username, image_id).as_bytes())?;
+    db.image_favorites
+        .remove(image_favorite_key(image_id, username).as_bytes())?;
+
+    match removed {
+        Some(_) => update_like_count(image_id, -1, db),
+        None => like_count(image_id, db),
+    }
+}
+
+pub fn like_count(image_id: &str, db: &Database) -> anyhow::Result<u64> {
+    Ok(db.like_counts.get(image_id.as_bytes())?.unwrap_or(0))
+}
+
+/// Adds the like count to an image.
+pub fn with_like_count(image: Image, db: &Database) -> anyhow::Result<LikedImage> {
+    Ok(LikedImage {
+        likes: like_count(image.id(), db)?,
+        image,
+    })
+}
+
+/// Adds like counts to images.
+pub fn with_likes(images: Vec<Image>, db: &Database) -> anyhow::Result<Vec<LikedImage>> {
+    images
+        .into_iter()
+        .map(|image| with_like_count(image, db))
+        .collect()
+}
+
+/// Lists the images a user has liked, most recently liked first.  Images the user can no longer see are left out.
+pub fn user_favorites(username: &str, db: &Database) -> anyhow::Result<Vec<Image>> {
+    let mut favorites = vec![];
+
+    for entry in db
+        .favorites
+        .scan_prefix(favorite_key(username, "").as_bytes())
+    {
+        let (_, favorite) = entry?;
+        favorites.push(favorite);
+    }
+
+    favorites.sort_by(|a, b| b.created.cmp(&a.created));
+
+    let mut liked = vec![];
+    for favorite in favorites {
+        if let Some(image) = images::get_image(&favorite.image_id, Some(username), db)? {
+            liked.push(image);
+        }
+    }
+
+    Ok(images::protect_originals(liked, Some(username)))
+}
+
+/// Removes a user's likes, so they no longer count towards any image.
+pub fn delete_user_favorites(username: &str, db: &Database) -> anyhow::Result<()> {
+    for entry in db
+        .favorites
+        .scan_prefix(favorite_key(username, "").as_bytes())
+    {
+        let (_, favorite) = entry?;
+        unlike_image(username, &favorite.image_id, db)?;
+    }
+
+    Ok(())
+}
+
+/// Removes every like of a deleted image.
+pub fn remove_image_favorites(image_id: &str, db: &Database) -> anyhow::Result<()> {
+    for entry in db
+        .image_favorites
+        .scan_prefix(image_favorite_key(image_id, "").as_bytes())
+    {
+        let (key, username) = entry?;
+        db.favorites
+            .remove(favorite_key(&username, image_id).as_bytes())?;
+        db.image_favorites.remove(key)?;
+    }
+
+    db.like_counts.remove(image_id.as_bytes())?;
+
+    Ok(())
+}
+
+/// Changes an image's like count in a transaction, so concurrent likes aren't lost.
+fn update_like_count(image_id: &str, change: i64, db: &Database) -> anyhow::Result<u64> {
+    let key = image_id.as_bytes().to_vec();
+
+    Ok(db
+        .like_counts
+        .transaction(move |tx_db| {
+            let count = match tx_db.get(&key)? {
+                Ok(Some(count)) => count,
+                _ => 0,
+            };
+            let count = (count as i64 + change).max(0) as u64;
+
+            Ok(tx_db.insert(key.clone(), count)?.map(|_| count))
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??)
+}
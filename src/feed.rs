@@ -0,0 +1,229 @@
+//! Following other users, and a feed of their recent public uploads.
+//!
+//! Uploads are indexed by username and upload time, so a feed only has to read the newest entries for each followed
+//! user instead of scanning every image.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    favorite::{self, LikedImage},
+    images, user, Database,
+};
+
+/// Separates the follower from the followee in keys.  Usernames can't contain it, so one user's keys never overlap
+/// another's.
+const KEY_SEPARATOR: char = '\0';
+
+/// Feeds return this many images if no limit is given.
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+/// Feeds return at most this many images.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Follow {
+    pub follower: String,
+    pub followee: String,
+    /// Unix timestamp.
+    pub created: i64,
+}
+
+/// One page of a feed.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedPage {
+    pub images: Vec<LikedImage>,
+    /// Pass this back to get the next page.  Not set on the last page.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum FeedError {
+    #[error("Could not find user")]
+    UnknownUser,
+    #[error("You can't follow yourself")]
+    SelfFollow,
+    #[error("Invalid cursor")]
+    InvalidCursor,
+}
+
+fn follow_key(follower: &str, followee: &str) -> String {
+    format!("{}{}{}", follower, KEY_SEPARATOR, followee)
+}
+
+/// The key in `followers`, which indexes follows by who is followed.
+fn follower_key(followee: &str, follower: &str) -> String {
+    format!("{}{}{}", followee, KEY_SEPARATOR, follower)
+}
+
+/// Follows a user.  Following someone again does nothing.
+pub fn follow_user(follower: &str, followee: &str, db: &Database) -> anyhow::Result<Follow> {
+    let followee = match user::find_user(followee, db)? {
+        Some(user) => user.username,
+        None => Err(FeedError::UnknownUser)?,
+    };
+    if follower == followee {
+        Err(FeedError::SelfFollow)?
+    }
+
+    let key = follow_key(follower, &followee);
+    if let Some(follow) = db.follows.get(key.as_bytes())? {
+        return Ok(follow);
+    }
+
+    let follow = Follow {
+        follower: follower.to_string(),
+        followee,
+        created: Utc::now().timestamp(),
+    };
+    // Indexed first, so a follow is never stored without its index entry.
+    db.followers.insert(
+        follower_key(&follow.followee, follower).as_bytes(),
+        follower.to_string(),
+    )?;
+    db.follows.insert(key.as_bytes(), follow.clone())?;
+
+    Ok(follow)
+}
+
+/// Unfollows a user.  Unfollowing someone who isn't followed does nothing.
+pub fn unfollow_user(follower: &str, followee: &str, db: &Database) -> anyhow::Result<()> {
+    let followee = match user::find_user(followee, db)? {
+        Some(user) => user.username,
+        None => followee.to_string(),
+    };
+
+    db.follows
+        .remove(follow_key(follower, &followee).as_bytes())?;
+    db.followers
+        .remove(follower_key(&followee, follower).as_bytes())?;
+
+    Ok(())
+}
+
+/// Lists the users someone follows, most recently followed first.
+pub fn following(follower: &str, db: &Database) -> anyhow::Result<Vec<Follow>> {
+    let mut follows = vec![];
+
+    for entry in db.follows.scan_prefix(follow_key(follower, "").as_bytes()) {
+        let (_, follow) = entry?;
+        follows.push(follow);
+    }
+
+    follows.sort_by(|a, b| b.created.cmp(&a.created));
+
+    Ok(follows)
+}
+
+/// Counts how many users follow someone.
+pub fn follower_count(followee: &str, db: &Database) -> anyhow::Result<usize> {
+    let mut count = 0;
+
+    for entry in db
+        .followers
+        .scan_prefix(follower_key(followee, "").as_bytes())
+    {
+        entry?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Removes everything a deleted user followed, and everyone following them.
+pub fn delete_user_follows(username: &str, db: &Database) -> anyhow::Result<()> {
+    for entry in db.follows.scan_prefix(follow_key(username, "").as_bytes()) {
+        let (key, follow) = entry?;
+        db.followers
+            .remove(follower_key(&follow.followee, username).as_bytes())?;
+        db.follows.remove(key)?;
+    }
+
+    for entry in db
+        .followers
+        .scan_prefix(follower_key(username, "").as_bytes())
+    {
+        let (key, follower) = entry?;
+        db.follows
+            .remove(follow_key(&follower, username).as_bytes())?;
+        db.followers.remove(key)?;
+    }
+
+    Ok(())
+}
+
+/// Gets a page of public uploads from the users someone follows, newest first.  The cursor comes from the previous
+/// page, and only uploads older than it are returned.
+pub fn feed(
+    follower: &str,
+    cursor: Option<&str>,
+    limit: usize,
+    db: &Database,
+) -> anyhow::Result<FeedPage> {
+    let limit = limit.max(1).min(MAX_PAGE_SIZE);
+    let before = match cursor {
+        Some(cursor) => Some(parse_cursor(cursor)?),
+        None => None,
+    };
+    let before = before
+        .as_ref()
+        .map(|(datetime, image_id)| (*datetime, image_id.as_str()));
+
+    let mut images = vec![];
+    for follow in following(follower, db)? {
+        if user::is_suspended(&follow.followee, db)? {
+            continue;
+        }
+
+        // One more than the limit is read, to tell whether there's another page.
+        let mut found = 0;
+        for image_id in images::user_upload_ids(&follow.followee, before, db) {
+            if found > limit {
+                break;
+            }
+
+            if let Some(image) = db.images.get(image_id?.as_bytes())? {
+                if image.is_searchable_by(None) {
+                    images.push(image);
+                    found += 1;
+                }
+            }
+        }
+    }
+
+    images.sort_by(|a, b| (b.datetime(), b.id()).cmp(&(a.datetime(), a.id())));
+
+    let next_cursor = if images.len() > limit {
+        images
+            .get(limit - 1)
+            .map(|image| format!("{}.{}", image.datetime(), image.id()))
+    } else {
+        None
+    };
+    let images = images.into_iter().take(limit).collect();
+    let images = images::protect_originals(images, Some(follower));
+
+    Ok(FeedPage {
+        images: favorite::with_likes(images, db)?,
+        next_cursor,
+    })
+}
+
+/// Turns a cursor back into the upload time and image ID it points to.
+fn parse_cursor(cursor: &str) -> Result<(i64, String), FeedError> {
+    let mut parts = cursor.splitn(2, '.');
+
+    match (
+        parts
+            .next()
+            .and_then(|datetime| datetime.parse::<i64>().ok()),
+        parts.next(),
+    ) {
+        (Some(datetime), Some(image_id)) if !image_id.is_empty() => {
+            Ok((datetime, image_id.to_string()))
+        }
+        _ => Err(FeedError::InvalidCursor),
+    }
+}
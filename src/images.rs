@@ -1,7 +1,13 @@
-use std::{convert::TryInto, str::FromStr, time::Duration};
+use std::{collections::HashMap, convert::TryInto, str::FromStr, time::Duration};
 
-use crate::{consts, Database};
+use crate::{
+    album, comment, consts, favorite,
+    market::Listing,
+    metadata::{self, Exif},
+    quota, user, Database,
+};
 use anyhow::Result;
+use futures::TryStreamExt;
 use img_hash::{
     image::{self, DynamicImage, ImageFormat},
     HasherConfig,
@@ -9,8 +15,16 @@ use img_hash::{
 use nanoid::nanoid;
 use reqwest::ClientBuilder;
 use rocket::http::hyper::Bytes;
-use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use rusoto_core::{
+    credential::{DefaultCredentialsProvider, ProvideAwsCredentials},
+    Region,
+};
+use rusoto_s3::{
+    util::{PreSignedRequest, PreSignedRequestOption},
+    DeleteObjectRequest, GetObjectRequest, PutObjectAclRequest, PutObjectRequest, S3Client, S3,
+};
 use serde::{Deserialize, Serialize};
+use sled_extensions::{bincode::Tree, DbExt};
 use thiserror::Error;
 
 pub struct ImageForm {
@@ -25,6 +39,10 @@ pub struct ImageForm {
     pub mime: String,
 
     pub image_name: String,
+
+    pub visibility: Visibility,
+
+    pub listing: Listing,
 }
 
 #[derive(Debug)]
@@ -53,11 +71,51 @@ impl FromStr for ImageUploadType {
     }
 }
 
+/// Who can see an image.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Visibility {
+    /// Shown to anyone, including in search results.
+    Public,
+    /// Shown to anyone who knows the image's ID, but left out of search results.
+    Unlisted,
+    /// Only shown to the owner.
+    Private,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::Public
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum VisibilityError {
+    #[error("Failed to parse field")]
+    VisibilityParseError,
+}
+
+impl FromStr for Visibility {
+    type Err = VisibilityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "public" => Ok(Visibility::Public),
+            "unlisted" => Ok(Visibility::Unlisted),
+            "private" => Ok(Visibility::Private),
+            _ => Err(VisibilityError::VisibilityParseError),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Image {
     id: String,
     image_url: String,
+    /// A downscaled, watermarked copy, shown instead of the original of private images and images for sale.  Only
+    /// made for those images.
+    preview_url: Option<String>,
     #[serde(skip)]
     hash: [u8; 8],
     username: String,
@@ -74,6 +132,211 @@ pub struct Image {
     height: u32,
     /// Unix timestamp
     datetime: i64,
+    visibility: Visibility,
+
+    // Inlined from `Listing` for the same reason as the metadata above.
+    /// In the currency's smallest unit.  Not for sale if not set.
+    price: Option<u64>,
+    currency: Option<String>,
+    /// How many copies are left to sell.  Unlimited if not set.
+    inventory: Option<u32>,
+    /// Of the original as stored, in bytes.  Zero for images uploaded before sizes were recorded.
+    #[serde(default)]
+    size: u64,
+    /// Read from the original file when it was uploaded.  Not set if it had no EXIF data.
+    exif: Option<Exif>,
+}
+
+impl Image {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.username
+    }
+
+    pub fn datetime(&self) -> i64 {
+        self.datetime
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// When the photo was taken, if its EXIF data says.
+    pub fn captured(&self) -> Option<i64> {
+        self.exif.as_ref().and_then(|exif| exif.captured)
+    }
+
+    pub fn listing(&self) -> Listing {
+        Listing {
+            price: self.price,
+            currency: self.currency.clone(),
+            inventory: self.inventory,
+        }
+    }
+
+    pub fn set_listing(&mut self, listing: Listing) {
+        self.price = listing.price;
+        self.currency = listing.currency;
+        self.inventory = listing.inventory;
+    }
+
+    pub fn set_inventory(&mut self, inventory: Option<u32>) {
+        self.inventory = inventory;
+    }
+
+    pub fn is_owned_by(&self, username: &str) -> bool {
+        self.username == username
+    }
+
+    /// Whether an image can be shown in search results to a viewer, who is `None` if not logged in.
+    pub fn is_searchable_by(&self, viewer: Option<&str>) -> bool {
+        self.visibility == Visibility::Public || viewer == Some(self.username.as_str())
+    }
+
+    /// Whether an image can be shown to a viewer who asked for it by ID.
+    pub fn is_viewable_by(&self, viewer: Option<&str>) -> bool {
+        self.visibility != Visibility::Private || viewer == Some(self.username.as_str())
+    }
+
+    /// Whether the original is kept private, and only handed out through [`original_url`] to the owner, to buyers if
+    /// it's for sale, and to share link holders if it isn't.
+    pub fn is_protected(&self) -> bool {
+        self.visibility == Visibility::Private || self.price.is_some()
+    }
+
+    /// Swaps the original for the preview.  Images without a preview are left without a URL.
+    fn into_preview(mut self) -> Image {
+        self.image_url = self.preview_url.clone().unwrap_or_default();
+        self
+    }
+
+    /// Leaves out where the photo was taken.
+    pub fn without_location(mut self) -> Image {
+        if let Some(exif) = &mut self.exif {
+            exif.latitude = None;
+            exif.longitude = None;
+        }
+        self
+    }
+}
+
+/// Limits search results by when images were uploaded and taken.  All bounds are Unix timestamps, and inclusive.
+#[derive(Debug, Default)]
+pub struct DateFilter {
+    pub uploaded_after: Option<i64>,
+    pub uploaded_before: Option<i64>,
+    pub captured_after: Option<i64>,
+    pub captured_before: Option<i64>,
+}
+
+impl DateFilter {
+    pub fn is_empty(&self) -> bool {
+        self.uploaded_after.is_none()
+            && self.uploaded_before.is_none()
+            && self.captured_after.is_none()
+            && self.captured_before.is_none()
+    }
+
+    /// Whether an image falls within the bounds.  Images without a capture time never match capture bounds.
+    pub fn matches(&self, image: &Image) -> bool {
+        let within = |time: Option<i64>, after: Option<i64>, before: Option<i64>| match time {
+            Some(time) => {
+                after.map_or(true, |after| after <= time)
+                    && before.map_or(true, |before| time <= before)
+            }
+            None => after.is_none() && before.is_none(),
+        };
+
+        within(
+            Some(image.datetime),
+            self.uploaded_after,
+            self.uploaded_before,
+        ) && within(image.captured(), self.captured_after, self.captured_before)
+    }
+}
+
+/// Separates the parts of `user_uploads` keys.  Usernames can't contain it, so one user's keys never overlap another's.
+const KEY_SEPARATOR: char = '\0';
+
+/// The key an image stored in S3 is under, taken from its URL.
+fn s3_key(url: &str) -> Option<&str> {
+    url.rsplit('/').next().filter(|key| !key.is_empty())
+}
+
+/// The canned ACL for an original in S3.  Protected originals are private, and only handed out through
+/// [`original_url`], so the bucket itself never has to allow public reads.
+fn original_acl(protected: bool) -> String {
+    if protected { "private" } else { "public-read" }.to_string()
+}
+
+/// Uploads a watermarked preview of an image, returning its URL.
+async fn upload_preview(
+    id: &str,
+    image: &DynamicImage,
+    bucket: &str,
+    s3_client: &S3Client,
+) -> Result<String> {
+    let preview_name = format!("{}-preview.jpg", id);
+    let preview_request = PutObjectRequest {
+        bucket: bucket.to_string(),
+        key: preview_name.clone(),
+        body: Some(consts::WATERMARK.preview_jpeg(image)?.into()),
+        content_type: Some("image/jpeg".to_string()),
+        acl: Some("public-read".to_string()),
+        ..Default::default()
+    };
+
+    s3_client.put_object(preview_request).await?;
+
+    Ok(format!(
+        "https:/{}.s3.amazonaws.com/{}",
+        bucket, preview_name
+    ))
+}
+
+/// The layout images were stored in before visibility, listings and previews were added.
+#[derive(Deserialize)]
+struct ImageV0 {
+    id: String,
+    image_url: String,
+    #[serde(skip)]
+    hash: [u8; 8],
+    username: String,
+    title: String,
+    tags: Vec<String>,
+    description: String,
+    image_type: String,
+    width: u32,
+    height: u32,
+    datetime: i64,
+}
+
+impl From<ImageV0> for Image {
+    fn from(image: ImageV0) -> Self {
+        Image {
+            id: image.id,
+            image_url: image.image_url,
+            preview_url: None,
+            hash: image.hash,
+            username: image.username,
+            title: image.title,
+            tags: image.tags,
+            description: image.description,
+            image_type: image.image_type,
+            width: image.width,
+            height: image.height,
+            datetime: image.datetime,
+            visibility: Visibility::Public,
+            price: None,
+            currency: None,
+            inventory: None,
+            size: 0,
+            exif: None,
+        }
+    }
 }
 
 // #[derive(Debug, Serialize, Deserialize)]
@@ -137,21 +400,36 @@ pub async fn build_image_for_foto(
     match image_result {
         Ok((image, image_type, bytes)) => {
             let id = nanoid!(11);
-            if let Some(extension_str) = image_type.extensions_str().get(0) {
-                image_form.image_name = format!("{}.{}", id, extension_str);
-            }
 
             let hash = get_image_hash(&image);
+            let original: &[u8] = match &bytes {
+                Some(bytes) => bytes,
+                None => &image_form.image,
+            };
+            let exif =
+                Exif::from_bytes(original).map(|exif| exif.redact(*consts::METADATA_PRIVACY));
+            let (stored, stored_type) =
+                metadata::strip(original, image_type, &image, *consts::METADATA_PRIVACY)?;
+            // Formats that metadata can't be removed from in place are stored as PNGs.
+            if stored_type != image_type {
+                image_form.mime = "image/png".to_string();
+            }
+            // Not derived from the ID, so the original can't be found from the ID or the preview's URL.
+            if let Some(extension_str) = stored_type.extensions_str().get(0) {
+                image_form.image_name = format!("{}.{}", nanoid!(32), extension_str);
+            }
+            let size = stored.len() as u64;
             let mut image_url = String::default();
+            let mut preview_url = None;
+            let protected =
+                image_form.visibility == Visibility::Private || image_form.listing.price.is_some();
 
             if let Some(bucket_location) = consts::CONFIG.s3_bucket_name.clone() {
                 let put_request = PutObjectRequest {
                     bucket: bucket_location.to_string(),
                     key: image_form.image_name.clone(),
-                    body: Some(match bytes {
-                        Some(bytes) => bytes.to_vec().into(),
-                        None => image_form.image.into(),
-                    }),
+                    body: Some(stored.into()),
+                    acl: Some(original_acl(protected)),
                     ..Default::default()
                 };
 
@@ -162,6 +440,12 @@ pub async fn build_image_for_foto(
                     bucket_location, image_form.image_name
                 );
 
+                // Images put up for sale later get their preview then, from [`sync_protection`].
+                if protected {
+                    preview_url =
+                        Some(upload_preview(&id, &image, &bucket_location, s3_client).await?);
+                }
+
                 // let image_url = format!(
                 //     "https:/s3-{}.amazonaws.com/{}/{}",
                 //     s3_client
@@ -186,6 +470,7 @@ pub async fn build_image_for_foto(
             Ok(Image {
                 id,
                 image_url,
+                preview_url,
                 hash: hash
                     .try_into()
                     .map_err(|_| anyhow::format_err!("Could not get 8 bytes from hash..."))?,
@@ -197,6 +482,12 @@ pub async fn build_image_for_foto(
                 width: rgba16_img.width(),
                 height: rgba16_img.height(),
                 datetime: chrono::Utc::now().timestamp(),
+                visibility: image_form.visibility,
+                price: image_form.listing.price,
+                currency: image_form.listing.currency,
+                inventory: image_form.listing.inventory,
+                size,
+                exif,
             })
         }
         Err(err) => Err(err)?,
@@ -211,12 +502,19 @@ pub fn add_image_to_db(mut image: Image, db: &Database) -> Result<()> {
     }
 
     image.id = id.clone();
-
-    {
-        let image = image.clone();
-        db.images.insert(id.as_bytes().to_vec(), image)?;
+    // Counted before the image is stored, so concurrent uploads can't both squeeze under the quota, and uncounted
+    // again if it can't be stored.
+    quota::record_upload(&image, db)?;
+    if let Err(err) = db.images.insert(id.as_bytes().to_vec(), image.clone()) {
+        quota::record_deletion(&image, db)?;
+        Err(err)?
     }
 
+    db.image_hash_keys
+        .insert(id.as_bytes(), image.hash.to_vec())?;
+    index_upload(&image, db)?;
+    index_dates(&image, db)?;
+
     {
         db.image_hashes
             .transaction(move |tx_db| {
@@ -248,6 +546,462 @@ pub fn add_image_to_db(mut image: Image, db: &Database) -> Result<()> {
     Ok(())
 }
 
+/// Updates a stored image, keeping the copy stored under its hash in sync.
+pub fn update_image(image: Image, db: &Database) -> Result<()> {
+    db.images.insert(image.id.as_bytes(), image.clone())?;
+
+    let id = image.id.clone();
+    update_hashed_copies(&id, db, move |images| {
+        for other in images.iter_mut() {
+            if other.id == image.id {
+                *other = image.clone();
+            }
+        }
+    })
+}
+
+/// Deletes an image from S3 and then from the database, returning it if it existed.  Files are deleted first, so if
+/// that fails the image is still there to try again.
+pub async fn delete_image(id: &str, db: &Database, s3_client: &S3Client) -> Result<Option<Image>> {
+    let image = match db.images.get(id.as_bytes())? {
+        Some(image) => image,
+        None => return Ok(None),
+    };
+
+    delete_stored_files(&image, s3_client).await?;
+
+    if db.images.remove(id.as_bytes())?.is_none() {
+        // Deleted by someone else in the meantime.
+        return Ok(None);
+    }
+    update_hashed_copies(id, db, |images| images.retain(|other| other.id != id))?;
+    db.image_hash_keys.remove(id.as_bytes())?;
+    album::remove_image_from_albums(id, db)?;
+    favorite::remove_image_favorites(id, db)?;
+    comment::remove_image_comments(id, db)?;
+    remove_upload(&image, db)?;
+    remove_dates(&image, db)?;
+    quota::record_deletion(&image, db)?;
+
+    Ok(Some(image))
+}
+
+/// Deletes an image's original and preview from S3.
+pub async fn delete_stored_files(image: &Image, s3_client: &S3Client) -> Result<()> {
+    if let Some(bucket_location) = consts::CONFIG.s3_bucket_name.clone() {
+        let urls = std::iter::once(&image.image_url).chain(image.preview_url.as_ref());
+        for key in urls.filter_map(|url| s3_key(url)) {
+            let delete_request = DeleteObjectRequest {
+                bucket: bucket_location.clone(),
+                key: key.to_string(),
+                ..Default::default()
+            };
+
+            s3_client.delete_object(delete_request).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Rewrites images stored in the [`ImageV0`] layout, along with their copies in `image_hashes`, in the current one.
+/// Records already in the current layout are left alone.
+pub fn upgrade_v0_images(sled: &sled_extensions::Db, db: &Database) -> Result<()> {
+    let legacy_images: Tree<ImageV0> = sled.open_bincode_tree("images")?;
+    for key in sled.open_tree("images")?.iter().keys() {
+        let key = key?;
+        if db.images.get(&key).is_err() {
+            if let Some(image) = legacy_images.get(&key)? {
+                db.images.insert(&key, Image::from(image))?;
+            }
+        }
+    }
+
+    let legacy_hashes: Tree<Vec<ImageV0>> = sled.open_bincode_tree("image_hashes")?;
+    for key in sled.open_tree("image_hashes")?.iter().keys() {
+        let key = key?;
+        if db.image_hashes.get(&key).is_err() {
+            if let Some(images) = legacy_hashes.get(&key)? {
+                let images: Vec<Image> = images.into_iter().map(Image::from).collect();
+                db.image_hashes.insert(&key, images)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the hash key of images stored before hash keys were recorded.
+pub fn index_existing_hash_keys(db: &Database) -> Result<()> {
+    if db.image_hash_keys.iter().next().is_some() {
+        return Ok(());
+    }
+
+    for entry in db.image_hashes.iter() {
+        let (key, images) = entry?;
+        for image in images {
+            db.image_hash_keys
+                .insert(image.id.as_bytes(), key.to_vec())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A link to download an image's original file.  Links into S3 are presigned for an hour, so they work for protected
+/// originals, which aren't publicly readable.
+pub async fn original_url(image: &Image) -> Result<String> {
+    let (bucket, key) = match (
+        consts::CONFIG.s3_bucket_name.clone(),
+        s3_key(&image.image_url),
+    ) {
+        (Some(bucket), Some(key)) => (bucket, key.to_string()),
+        _ => return Ok(image.image_url.clone()),
+    };
+
+    let credentials = DefaultCredentialsProvider::new()?.credentials().await?;
+    let request = GetObjectRequest {
+        bucket,
+        key,
+        ..Default::default()
+    };
+
+    Ok(request.get_presigned_url(
+        &Region::UsEast1,
+        &credentials,
+        &PreSignedRequestOption {
+            expires_in: Duration::from_secs(60 * 60),
+        },
+    ))
+}
+
+/// Points an image at a presigned link to its original, for share link holders, who are allowed to see it.
+pub async fn with_original_url(mut image: Image) -> Result<Image> {
+    image.image_url = original_url(&image).await?;
+    Ok(image)
+}
+
+/// Brings an image's files in S3 in line with whether it is protected, after it has been put up for sale or taken
+/// off sale.  A preview is made the first time one is needed, and the original is made private or public to match.
+pub async fn sync_protection(
+    mut image: Image,
+    s3_client: &S3Client,
+    db: &Database,
+) -> Result<Image> {
+    let (bucket, key) = match (
+        consts::CONFIG.s3_bucket_name.clone(),
+        s3_key(&image.image_url),
+    ) {
+        (Some(bucket), Some(key)) => (bucket, key.to_string()),
+        _ => return Ok(image),
+    };
+    let protected = image.is_protected();
+
+    if protected && image.preview_url.is_none() {
+        let original = s3_client
+            .get_object(GetObjectRequest {
+                bucket: bucket.clone(),
+                key: key.clone(),
+                ..Default::default()
+            })
+            .await?;
+        let bytes: Vec<u8> = original
+            .body
+            .ok_or_else(|| anyhow::format_err!("Original has no body"))?
+            .map_ok(|bytes| bytes.to_vec())
+            .try_concat()
+            .await?;
+
+        let preview_url = upload_preview(
+            &image.id,
+            &image::load_from_memory(&bytes)?,
+            &bucket,
+            s3_client,
+        )
+        .await?;
+        image.preview_url = Some(preview_url);
+        update_image(image.clone(), db)?;
+    }
+
+    let acl_request = PutObjectAclRequest {
+        bucket,
+        key,
+        acl: Some(original_acl(protected)),
+        ..Default::default()
+    };
+    s3_client.put_object_acl(acl_request).await?;
+
+    Ok(image)
+}
+
+/// Every `user_uploads` key belonging to a user starts with this.
+fn upload_prefix(username: &str) -> String {
+    format!("{}{}", username, KEY_SEPARATOR)
+}
+
+/// Zero-padded, so a user's uploads are stored in the order they happened.
+fn upload_position(datetime: i64, image_id: &str) -> String {
+    format!("{:020}{}{}", datetime, KEY_SEPARATOR, image_id)
+}
+
+fn upload_key(image: &Image) -> String {
+    format!(
+        "{}{}",
+        upload_prefix(&image.username),
+        upload_position(image.datetime, &image.id)
+    )
+}
+
+/// Adds an image to its owner's uploads.
+pub fn index_upload(image: &Image, db: &Database) -> Result<()> {
+    db.user_uploads
+        .insert(upload_key(image).as_bytes(), image.id.clone())?;
+
+    Ok(())
+}
+
+/// Removes an image from its owner's uploads.
+pub fn remove_upload(image: &Image, db: &Database) -> Result<()> {
+    db.user_uploads.remove(upload_key(image).as_bytes())?;
+
+    Ok(())
+}
+
+/// Indexes any images uploaded before uploads were indexed.
+pub fn index_existing_uploads(db: &Database) -> Result<()> {
+    if db.user_uploads.iter().next().is_some() {
+        return Ok(());
+    }
+
+    for entry in db.images.iter() {
+        let (_, image) = entry?;
+        index_upload(&image, db)?;
+    }
+
+    Ok(())
+}
+
+/// Iterates over the ids of a user's images, newest first.  Given the upload time and id of an image, only images
+/// uploaded before it are included.
+pub fn user_upload_ids<'a>(
+    username: &str,
+    before: Option<(i64, &str)>,
+    db: &'a Database,
+) -> impl Iterator<Item = Result<String>> + 'a {
+    let prefix = upload_prefix(username);
+    let end = match before {
+        Some((datetime, image_id)) => format!("{}{}", prefix, upload_position(datetime, image_id)),
+        // Every key starting with the prefix sorts before this.
+        None => format!("{}\u{1}", username),
+    };
+
+    db.user_uploads
+        .range(prefix.as_bytes()..end.as_bytes())
+        .rev()
+        .map(|entry| -> Result<String> { Ok(entry?.1) })
+}
+
+/// Returns the ids of all images uploaded by a user.
+pub fn user_image_ids(username: &str, db: &Database) -> Result<Vec<String>> {
+    user_upload_ids(username, None, db).collect()
+}
+
+/// Adds an image to the upload and capture time indexes searched by date.
+pub fn index_dates(image: &Image, db: &Database) -> Result<()> {
+    db.upload_times.insert(
+        upload_position(image.datetime, &image.id).as_bytes(),
+        image.id.clone(),
+    )?;
+    if let Some(captured) = image.captured() {
+        db.capture_times.insert(
+            upload_position(captured, &image.id).as_bytes(),
+            image.id.clone(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Removes an image from the upload and capture time indexes.
+pub fn remove_dates(image: &Image, db: &Database) -> Result<()> {
+    db.upload_times
+        .remove(upload_position(image.datetime, &image.id).as_bytes())?;
+    if let Some(captured) = image.captured() {
+        db.capture_times
+            .remove(upload_position(captured, &image.id).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Indexes the upload times of images stored before they were indexed.  Those images have no capture time.
+pub fn index_existing_dates(db: &Database) -> Result<()> {
+    if db.upload_times.iter().next().is_some() {
+        return Ok(());
+    }
+
+    for entry in db.images.iter() {
+        let (_, image) = entry?;
+        index_dates(&image, db)?;
+    }
+
+    Ok(())
+}
+
+/// Finds the images within a filter's bounds.  Only the part of the upload or capture time index the bounds cover is
+/// read, so a narrow search stays cheap however many images there are.
+pub fn images_by_date(filter: &DateFilter, db: &Database) -> Result<Vec<Image>> {
+    let (index, after, before) =
+        if filter.uploaded_after.is_some() || filter.uploaded_before.is_some() {
+            (
+                &db.upload_times,
+                filter.uploaded_after,
+                filter.uploaded_before,
+            )
+        } else {
+            (
+                &db.capture_times,
+                filter.captured_after,
+                filter.captured_before,
+            )
+        };
+
+    // Negative times don't sort in order, so bounds below zero are left open and checked by `matches` instead.
+    let start = match after.filter(|after| *after >= 0) {
+        Some(after) => format!("{:020}", after),
+        None => String::new(),
+    };
+    let entries = match before.filter(|before| *before >= 0) {
+        // Every key for that time sorts before this.
+        Some(before) => index.range(start.as_bytes()..format!("{:020}\u{1}", before).as_bytes()),
+        None => index.range(start.as_bytes()..),
+    };
+
+    let mut images = vec![];
+    for entry in entries {
+        let (_, id) = entry?;
+        if let Some(image) = db.images.get(id.as_bytes())? {
+            if filter.matches(&image) {
+                images.push(image);
+            }
+        }
+    }
+
+    Ok(images)
+}
+
+/// Deletes all images uploaded by a user.
+pub async fn delete_user_images(username: &str, db: &Database, s3_client: &S3Client) -> Result<()> {
+    for id in user_image_ids(username, db)? {
+        delete_image(&id, db, s3_client).await?;
+    }
+
+    Ok(())
+}
+
+/// Gives all images uploaded by one user to another.
+pub fn reassign_user_images(from: &str, to: &str, db: &Database) -> Result<()> {
+    for id in user_image_ids(from, db)? {
+        if let Some(mut image) = db.images.get(id.as_bytes())? {
+            remove_upload(&image, db)?;
+            quota::record_reassignment(&image, to, db)?;
+            image.username = to.to_string();
+            index_upload(&image, db)?;
+            update_image(image, db)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies `update` to the list of images stored under the same hash as the image with the given id, removing
+/// the hash entry entirely if the list ends up empty.
+fn update_hashed_copies<F>(id: &str, db: &Database, update: F) -> Result<()>
+where
+    F: Fn(&mut Vec<Image>),
+{
+    // The hash is skipped when an image is serialized, so the key it's stored under is looked up separately.
+    let hash_key = db.image_hash_keys.get(id.as_bytes())?;
+
+    if let Some(hash_key) = hash_key {
+        db.image_hashes
+            .transaction(move |tx_db| {
+                let mut images = match tx_db.get(&hash_key)? {
+                    Ok(Some(images)) => images,
+                    _ => vec![],
+                };
+
+                update(&mut images);
+
+                let result = if images.is_empty() {
+                    tx_db.remove(hash_key.clone())?
+                } else {
+                    tx_db.insert(hash_key.clone(), images)?
+                };
+
+                Ok(result.map(|_| ()))
+            })
+            .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+    }
+
+    Ok(())
+}
+
+/// Gets an image by ID, if the viewer is allowed to see it.
+pub fn get_image(id: &str, viewer: Option<&str>, db: &Database) -> Result<Option<Image>> {
+    Ok(db
+        .images
+        .get(id.as_bytes())?
+        .filter(|image| image.is_viewable_by(viewer)))
+}
+
+/// Swaps the original of a protected image for its preview.  Owners and buyers get the original through
+/// [`original_url`] instead, so a lasting link to it is never given out.  Where the photo was taken is only shown to
+/// the owner.
+pub fn protect_original(image: Image, viewer: Option<&str>) -> Image {
+    let image = if image.is_protected() {
+        image.into_preview()
+    } else {
+        image
+    };
+
+    match viewer {
+        Some(viewer) if image.is_owned_by(viewer) => image,
+        _ => image.without_location(),
+    }
+}
+
+/// Applies [`protect_original`] to each image.
+pub fn protect_originals(images: Vec<Image>, viewer: Option<&str>) -> Vec<Image> {
+    images
+        .into_iter()
+        .map(|image| protect_original(image, viewer))
+        .collect()
+}
+
+/// Leaves out images whose owners are currently suspended.
+pub fn hide_suspended_owners(images: Vec<Image>, db: &Database) -> Result<Vec<Image>> {
+    let mut suspended: HashMap<String, bool> = HashMap::new();
+    let mut visible = vec![];
+
+    for image in images {
+        let is_suspended = match suspended.get(&image.username) {
+            Some(is_suspended) => *is_suspended,
+            None => {
+                let is_suspended = user::is_suspended(&image.username, db)?;
+                suspended.insert(image.username.clone(), is_suspended);
+                is_suspended
+            }
+        };
+
+        if !is_suspended {
+            visible.push(image);
+        }
+    }
+
+    Ok(visible)
+}
+
 pub fn get_image_hash(image: &DynamicImage) -> Vec<u8> {
     let hasher = HasherConfig::new().to_hasher();
     let hash = hasher.hash_image(image);
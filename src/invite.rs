@@ -0,0 +1,111 @@
+//! Invite codes, which admins hand out to let people register while registration is invite-only.
+
+use chrono::Utc;
+use nanoid::nanoid;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{consts, Database};
+
+const DEFAULT_USES: u32 = 1;
+const MAX_USES: u32 = 1000;
+const DEFAULT_EXPIRY_MINUTES: i64 = 7 * 24 * 60;
+const MAX_EXPIRY_MINUTES: i64 = 90 * 24 * 60;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteRequest {
+    uses: Option<u32>,
+    expires_in_minutes: Option<i64>,
+}
+
+/// An invite code.  Stored under a hash of the code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub created_by: String,
+    pub uses_remaining: u32,
+    /// Unix timestamp.
+    pub expires: i64,
+}
+
+#[derive(Error, Debug)]
+/// An error while trying to register given the current registration mode.
+pub enum RegistrationError {
+    #[error("Registration is closed")]
+    Closed,
+    #[error("Invalid, used up or expired invite code")]
+    InvalidInvite,
+}
+
+/// Creates an invite code, returning it along with the stored [`Invite`].
+pub fn create_invite(
+    request: InviteRequest,
+    created_by: &str,
+    db: &Database,
+) -> anyhow::Result<(String, Invite)> {
+    let now = Utc::now().timestamp();
+
+    // Clean up any invites that expired before they were used up.
+    for entry in db.invites.iter() {
+        let (key, invite) = entry?;
+        if invite.expires < now {
+            db.invites.remove(key)?;
+        }
+    }
+
+    let minutes = request
+        .expires_in_minutes
+        .unwrap_or(DEFAULT_EXPIRY_MINUTES)
+        .max(1)
+        .min(MAX_EXPIRY_MINUTES);
+    let code = nanoid!(16, &consts::READABLE_CODE_ALPHABET);
+    let invite = Invite {
+        created_by: created_by.to_string(),
+        uses_remaining: request.uses.unwrap_or(DEFAULT_USES).max(1).min(MAX_USES),
+        expires: now + minutes * 60,
+    };
+
+    db.invites
+        .insert(hash_invite_code(&code).as_bytes(), invite.clone())?;
+
+    Ok((code, invite))
+}
+
+/// Uses up one use of an invite code, failing if it is invalid, used up or expired.
+pub fn redeem_invite(code: &str, db: &Database) -> anyhow::Result<()> {
+    let key = hash_invite_code(&code.trim().to_lowercase()).into_bytes();
+    let now = Utc::now().timestamp();
+
+    // In a transaction, so concurrent registrations can't use the same last use.
+    let redeemed = db
+        .invites
+        .transaction(move |tx_db| {
+            let result = match tx_db.get(&key)? {
+                Ok(Some(mut invite)) if invite.uses_remaining > 0 && invite.expires > now => {
+                    invite.uses_remaining -= 1;
+
+                    if invite.uses_remaining == 0 {
+                        tx_db.remove(key.clone())?.map(|_| true)
+                    } else {
+                        tx_db.insert(key.clone(), invite)?.map(|_| true)
+                    }
+                }
+                Ok(_) => Ok(false),
+                Err(err) => Err(err),
+            };
+
+            Ok(result)
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    if !redeemed {
+        Err(RegistrationError::InvalidInvite)?
+    }
+
+    Ok(())
+}
+
+fn hash_invite_code(code: &str) -> String {
+    base64::encode(digest::digest(&digest::SHA256, code.as_bytes()))
+}
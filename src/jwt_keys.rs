@@ -0,0 +1,120 @@
+//! Keys used to sign and verify JWTs, and their public representation as a JSON Web Key Set.
+
+use anyhow::Context;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use ring::signature::RsaKeyPair;
+use rocket_contrib::json::JsonValue;
+
+use crate::config::JwtKeyConfig;
+
+/// A key to sign and verify JWTs with.
+pub struct JwtKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    material: KeyMaterial,
+}
+
+enum KeyMaterial {
+    Hmac(Vec<u8>),
+    /// The public modulus and exponent of an RSA key, in unpadded base64url as used by JWKs.
+    Rsa {
+        modulus: String,
+        exponent: String,
+    },
+}
+
+impl JwtKey {
+    /// An HMAC key, signing with HS512.
+    pub fn hmac(kid: &str, secret: Vec<u8>) -> JwtKey {
+        JwtKey {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS512,
+            encoding_key: EncodingKey::from_secret(&secret),
+            material: KeyMaterial::Hmac(secret),
+        }
+    }
+
+    /// An RSA key from a PEM encoded private key in either PKCS#1 or PKCS#8 format, signing with RS256.
+    pub fn rsa(kid: &str, private_key_pem: &str) -> anyhow::Result<JwtKey> {
+        let der = pem_contents(private_key_pem).context("Could not read RSA private key PEM.")?;
+        let key_pair = if private_key_pem.contains("BEGIN RSA PRIVATE KEY") {
+            RsaKeyPair::from_der(&der)
+        } else {
+            RsaKeyPair::from_pkcs8(&der)
+        }
+        .map_err(|err| anyhow::format_err!("Invalid RSA private key: {}", err))?;
+
+        let public_key = key_pair.public_key();
+
+        Ok(JwtKey {
+            kid: kid.to_string(),
+            algorithm: Algorithm::RS256,
+            encoding_key: EncodingKey::from_rsa_pem(private_key_pem.as_bytes())?,
+            material: KeyMaterial::Rsa {
+                modulus: base64::encode_config(
+                    public_key.modulus().big_endian_without_leading_zero(),
+                    base64::URL_SAFE_NO_PAD,
+                ),
+                exponent: base64::encode_config(
+                    public_key.exponent().big_endian_without_leading_zero(),
+                    base64::URL_SAFE_NO_PAD,
+                ),
+            },
+        })
+    }
+
+    /// Builds a key from its config, which must have exactly one of a secret or a private key.
+    pub fn from_config(config: &JwtKeyConfig) -> anyhow::Result<JwtKey> {
+        match (&config.secret, &config.private_key_pem) {
+            (Some(secret), None) => Ok(JwtKey::hmac(
+                &config.kid,
+                base64::decode(secret.clone()).context("JWT secrets must be base64.")?,
+            )),
+            (None, Some(private_key_pem)) => JwtKey::rsa(&config.kid, private_key_pem),
+            _ => Err(anyhow::format_err!(
+                "JWT key {} must have exactly one of secret or privateKeyPem.",
+                config.kid
+            )),
+        }
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    pub fn decoding_key(&self) -> DecodingKey<'_> {
+        match &self.material {
+            KeyMaterial::Hmac(secret) => DecodingKey::from_secret(secret),
+            KeyMaterial::Rsa { modulus, exponent } => {
+                DecodingKey::from_rsa_components(modulus, exponent)
+            }
+        }
+    }
+
+    /// The public JWK for this key.  HMAC keys are secret, so they have none.
+    pub fn public_jwk(&self) -> Option<JsonValue> {
+        match &self.material {
+            KeyMaterial::Hmac(_) => None,
+            KeyMaterial::Rsa { modulus, exponent } => Some(json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.kid,
+                "n": modulus,
+                "e": exponent,
+            })),
+        }
+    }
+}
+
+/// Decodes the base64 body of a PEM block.
+fn pem_contents(pem: &str) -> anyhow::Result<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("-----"))
+        .collect();
+
+    Ok(base64::decode(body)?)
+}
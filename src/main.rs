@@ -7,14 +7,36 @@ extern crate rocket_contrib;
 #[cfg(test)]
 mod test;
 
+mod album;
 mod api;
 mod auth;
+mod comment;
 mod config;
 mod consts;
+mod discount;
+mod favorite;
+mod feed;
 mod images;
+mod invite;
+mod jwt_keys;
+mod market;
+mod metadata;
+mod oidc;
 mod page;
+mod policy;
+mod profile;
+mod quota;
 mod response;
+mod schema;
+mod session;
+mod share;
+mod throttle;
+mod two_factor;
 mod user;
+mod watermark;
+mod well_known;
+
+use std::collections::HashSet;
 
 use images::Image;
 use rusoto_core;
@@ -35,7 +57,56 @@ fn rocket() -> rocket::Rocket {
 /// Builds a rocket given a sled_embedded database reference.  The reason this is pulled out from the main [`rocket`] function
 /// is mostly for testing purposes, as the testing client will use its own database connection across all clients.
 fn rocket_from_db(db: &sled_extensions::Db) -> rocket::Rocket {
+    rocket_from_db_with(db, consts::CONFIG.oidc.clone(), consts::ADMINS.clone())
+}
+
+/// Like [`rocket_from_db`], but with the OpenID Connect provider and admins given directly, so tests can use a mock
+/// provider and their own admins.
+fn rocket_from_db_with(
+    db: &sled_extensions::Db,
+    oidc: Option<config::OidcConfig>,
+    admins: HashSet<String>,
+) -> rocket::Rocket {
     let s3_client = S3Client::new(rusoto_core::Region::UsEast1);
+    let database = Database {
+        users: db.open_bincode_tree("users").unwrap(),
+        image_hashes: db.open_bincode_tree("image_hashes").unwrap(),
+        images: db.open_bincode_tree("images").unwrap(),
+        image_hash_keys: db.open_bincode_tree("image_hash_keys").unwrap(),
+        login_attempts: db.open_bincode_tree("login_attempts").unwrap(),
+        usernames: db.open_bincode_tree("usernames").unwrap(),
+        two_factor: db.open_bincode_tree("two_factor").unwrap(),
+        oidc_states: db.open_bincode_tree("oidc_states").unwrap(),
+        oidc_links: db.open_bincode_tree("oidc_links").unwrap(),
+        user_oidc_links: db.open_bincode_tree("user_oidc_links").unwrap(),
+        password_resets: db.open_bincode_tree("password_resets").unwrap(),
+        sessions: db.open_bincode_tree("sessions").unwrap(),
+        invites: db.open_bincode_tree("invites").unwrap(),
+        shares: db.open_bincode_tree("shares").unwrap(),
+        share_expiries: db.open_bincode_tree("share_expiries").unwrap(),
+        albums: db.open_bincode_tree("albums").unwrap(),
+        image_albums: db.open_bincode_tree("image_albums").unwrap(),
+        user_albums: db.open_bincode_tree("user_albums").unwrap(),
+        ledger: db.open_bincode_tree("ledger").unwrap(),
+        discounts: db.open_bincode_tree("discounts").unwrap(),
+        favorites: db.open_bincode_tree("favorites").unwrap(),
+        image_favorites: db.open_bincode_tree("image_favorites").unwrap(),
+        like_counts: db.open_bincode_tree("like_counts").unwrap(),
+        comments: db.open_bincode_tree("comments").unwrap(),
+        follows: db.open_bincode_tree("follows").unwrap(),
+        followers: db.open_bincode_tree("followers").unwrap(),
+        user_uploads: db.open_bincode_tree("user_uploads").unwrap(),
+        profiles: db.open_bincode_tree("profiles").unwrap(),
+        usage: db.open_bincode_tree("usage").unwrap(),
+        upload_times: db.open_bincode_tree("upload_times").unwrap(),
+        capture_times: db.open_bincode_tree("capture_times").unwrap(),
+    };
+    schema::upgrade(db, &database).expect("Failed to upgrade stored records");
+    user::index_existing_usernames(&database).expect("Failed to index usernames");
+    images::index_existing_hash_keys(&database).expect("Failed to index image hashes");
+    images::index_existing_uploads(&database).expect("Failed to index uploads");
+    quota::count_existing_usage(&database).expect("Failed to count usage");
+    images::index_existing_dates(&database).expect("Failed to index image dates");
 
     rocket::ignite()
         .mount(
@@ -46,21 +117,165 @@ fn rocket_from_db(db: &sled_extensions::Db) -> rocket::Rocket {
                 api::upload::upload,
                 api::upload::upload_no_auth,
                 api::upload::upload_invalid_form,
+                api::image::image,
+                api::favorite::like,
+                api::favorite::like_no_auth,
+                api::favorite::unlike,
+                api::favorite::unlike_no_auth,
+                api::favorite::favorites,
+                api::favorite::favorites_no_auth,
+                api::feed::follow,
+                api::feed::follow_no_auth,
+                api::feed::unfollow,
+                api::feed::unfollow_no_auth,
+                api::feed::follows,
+                api::feed::follows_no_auth,
+                api::feed::feed,
+                api::feed::feed_no_auth,
+                api::profile::profile,
+                api::profile::profile_images,
+                api::profile::profile_update,
+                api::profile::profile_update_no_auth,
+                api::quota::account_usage,
+                api::quota::account_usage_no_auth,
+                api::comment::comment_create,
+                api::comment::comment_create_no_auth,
+                api::comment::comments,
+                api::comment::comment_edit,
+                api::comment::comment_edit_no_auth,
+                api::comment::comment_delete,
+                api::comment::comment_delete_no_auth,
+                api::share::share_create,
+                api::share::share_create_no_auth,
+                api::share::shares,
+                api::share::shares_no_auth,
+                api::share::share_revoke,
+                api::share::share_revoke_no_auth,
+                api::share::share_open,
+                api::market::listing_update,
+                api::market::listing_update_no_auth,
+                api::market::purchase,
+                api::market::purchase_no_auth,
+                api::market::original,
+                api::market::original_no_auth,
+                api::market::price,
+                api::market::balance,
+                api::market::balance_no_auth,
+                api::market::deposit_create,
+                api::market::deposit_create_not_admin,
+                api::discount::discount_create,
+                api::discount::discount_create_no_auth,
+                api::discount::discounts,
+                api::discount::discounts_no_auth,
+                api::discount::discount_delete,
+                api::discount::discount_delete_no_auth,
+                api::album::album_create,
+                api::album::album_create_no_auth,
+                api::album::albums,
+                api::album::albums_no_auth,
+                api::album::album,
+                api::album::album_update,
+                api::album::album_update_no_auth,
+                api::album::album_delete,
+                api::album::album_delete_no_auth,
+                api::album::album_add_image,
+                api::album::album_add_image_no_auth,
+                api::album::album_reorder,
+                api::album::album_reorder_no_auth,
+                api::album::album_remove_image,
+                api::album::album_remove_image_no_auth,
                 api::register::register,
-                api::login::login
+                api::login::login,
+                api::login::login_two_factor,
+                api::login::logout,
+                api::account::password,
+                api::account::password_no_auth,
+                api::account::delete,
+                api::account::delete_no_auth,
+                api::two_factor::two_factor_enroll,
+                api::two_factor::two_factor_enroll_no_auth,
+                api::two_factor::two_factor_confirm,
+                api::two_factor::two_factor_confirm_no_auth,
+                api::password_reset::password_reset_create,
+                api::password_reset::password_reset_create_not_admin,
+                api::password_reset::password_reset,
+                api::invite::invite_create,
+                api::invite::invite_create_not_admin,
+                api::suspension::suspension_create,
+                api::suspension::suspension_create_not_admin,
+                api::suspension::suspension_delete,
+                api::suspension::suspension_delete_not_admin,
+                api::session::sessions,
+                api::session::sessions_no_auth,
+                api::session::session_revoke,
+                api::session::session_revoke_no_auth,
+                api::session::sessions_revoke_all,
+                api::session::sessions_revoke_all_no_auth,
+                api::oidc::oidc_login,
+                api::oidc::oidc_callback
             ],
         )
-        .mount("/", routes![page::login::login])
-        .manage(Database {
-            users: db.open_bincode_tree("users").unwrap(),
-            image_hashes: db.open_bincode_tree("image_hashes").unwrap(),
-            images: db.open_bincode_tree("images").unwrap(),
-        })
+        .mount("/", routes![page::login::login, well_known::jwks])
+        .register(catchers![api::suspension::forbidden])
+        .manage(database)
         .manage(s3_client)
+        .manage(oidc::OidcProvider { config: oidc })
+        .manage(auth::Admins(admins))
 }
 
 pub struct Database {
     users: Tree<User>,
     image_hashes: Tree<Vec<Image>>,
     images: Tree<Image>,
+    /// Maps each image ID to the key of its copy in `image_hashes`, since the hash isn't stored with the image.
+    image_hash_keys: Tree<Vec<u8>>,
+    login_attempts: Tree<throttle::LoginAttempts>,
+    /// Maps each [`policy::username_key`] to the username it belongs to.
+    usernames: Tree<String>,
+    two_factor: Tree<two_factor::TwoFactor>,
+    oidc_states: Tree<oidc::OidcLoginState>,
+    /// Maps an OpenID Connect issuer and subject to the username they are linked to.
+    oidc_links: Tree<String>,
+    /// Maps each username and link to the link, so a user's links can be found without a full scan.
+    user_oidc_links: Tree<String>,
+    /// Keyed by a hash of the reset code.
+    password_resets: Tree<PasswordResetCode>,
+    sessions: Tree<session::Session>,
+    /// Keyed by a hash of the invite code.
+    invites: Tree<invite::Invite>,
+    /// Keyed by image ID and share ID.
+    shares: Tree<share::Share>,
+    /// Maps each share's expiry time and key to its key, so expired shares can be found in order.
+    share_expiries: Tree<String>,
+    albums: Tree<album::Album>,
+    /// Maps each image ID to the albums it is in.
+    image_albums: Tree<Vec<String>>,
+    /// Maps each owner and album ID to the album ID, so a user's albums can be found without a full scan.
+    user_albums: Tree<String>,
+    /// Balances, stock, purchases and transactions, kept in one tree so a purchase can update them in a single
+    /// transaction.
+    ledger: Tree<market::LedgerRecord>,
+    /// Keyed by seller and discount ID.
+    discounts: Tree<discount::Discount>,
+    /// Keyed by username and image ID.
+    favorites: Tree<favorite::Favorite>,
+    /// Maps each image ID and username to the username, so an image's likes can be found without a full scan.
+    image_favorites: Tree<String>,
+    /// How many users have liked each image, by image ID.
+    like_counts: Tree<u64>,
+    /// Keyed by image ID and comment ID.
+    comments: Tree<comment::Comment>,
+    /// Keyed by follower and followee.
+    follows: Tree<feed::Follow>,
+    /// Maps each followee and follower to the follower, so a user's followers can be found without a full scan.
+    followers: Tree<String>,
+    /// Maps each username, upload time and image ID to the image ID, so a user's uploads can be read newest first.
+    user_uploads: Tree<String>,
+    profiles: Tree<profile::Profile>,
+    /// How much each user has uploaded, by username.
+    usage: Tree<quota::Usage>,
+    /// Maps each upload time and image ID to the image ID, so images can be searched by when they were uploaded.
+    upload_times: Tree<String>,
+    /// Maps each capture time and image ID to the image ID, for images whose EXIF data says when they were taken.
+    capture_times: Tree<String>,
 }
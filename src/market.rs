@@ -0,0 +1,557 @@
+//! Selling images.  Money moves between per-user balances as double-entry transactions, where every transaction's
+//! postings sum to zero in each currency.
+//!
+//! Balances, remaining stock, purchase records and transactions all live in the one `ledger` tree, so that a
+//! purchase updates all of them in a single sled transaction.
+
+use std::convert::TryFrom;
+
+use chrono::Utc;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    discount,
+    images::{self, Image},
+    Database,
+};
+
+/// Unwraps the result of an operation on a transactional tree, passing its error out of the transaction.
+macro_rules! try_tx {
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(err) => return Ok(Err(anyhow::Error::from(err))),
+        }
+    };
+}
+
+/// The account money is deposited from.  Its balance goes negative by the total deposited.
+const DEPOSITS_ACCOUNT: &str = "external:deposits";
+
+/// The price, currency and stock of an image for sale.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Listing {
+    /// In the currency's smallest unit, such as cents.  Not for sale if not set.
+    pub price: Option<u64>,
+    /// An ISO 4217 code, such as `USD`.
+    pub currency: Option<String>,
+    /// How many copies can be sold.  Unlimited if not set.
+    pub inventory: Option<u32>,
+}
+
+impl Listing {
+    pub fn validate(mut self) -> Result<Self, MarketError> {
+        self.currency = self.currency.map(|currency| currency.to_uppercase());
+
+        match (&self.price, &self.currency) {
+            (Some(price), Some(_)) if i64::try_from(*price).is_err() => {
+                Err(MarketError::AmountTooLarge)
+            }
+            (Some(_), Some(currency)) if is_currency_code(currency) => Ok(self),
+            (None, None) => Ok(self),
+            _ => Err(MarketError::InvalidListing),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Deposit {
+    username: String,
+    currency: String,
+    amount: u64,
+}
+
+/// One side of a [`LedgerTransaction`].  Positive amounts credit the account, negative ones debit it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Posting {
+    pub account: String,
+    pub currency: String,
+    pub amount: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LedgerTransaction {
+    pub id: String,
+    /// Unix timestamp.
+    pub created: i64,
+    pub description: String,
+    /// The image bought, for purchases.
+    pub image_id: Option<String>,
+    pub postings: Vec<Posting>,
+}
+
+/// Everything stored in the `ledger` tree, told apart by key prefix.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LedgerRecord {
+    /// Under `balance/<account>/<currency>`.
+    Balance(i64),
+    /// Under `stock/<image id>`, for images with limited inventory.
+    Stock(u32),
+    /// Under `purchase/<username>/<image id>`.
+    Purchase(Purchase),
+    /// Under `transaction/<timestamp>/<id>`.
+    Transaction(LedgerTransaction),
+    /// Under `discount/<discount id>`, for discounts that have been used.
+    DiscountUses(u32),
+    /// Under `account/<account>/<timestamp>/<id>` for each account a transaction posts to, holding the key of the
+    /// transaction.
+    AccountTransaction(String),
+}
+
+/// What a user paid for an image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Purchase {
+    pub transaction_id: String,
+    pub list_price: u64,
+    pub price: u64,
+    pub currency: String,
+    /// The discount applied, if any.
+    pub discount_id: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum MarketError {
+    #[error("Price and currency must be set together, with a three letter currency code")]
+    InvalidListing,
+    #[error("Could not find image")]
+    UnknownImage,
+    #[error("Could not find user")]
+    UnknownUser,
+    #[error("This image is not for sale")]
+    NotForSale,
+    #[error("You can't buy your own image")]
+    OwnImage,
+    #[error("You have already bought this image")]
+    AlreadyPurchased,
+    #[error("This image is sold out")]
+    SoldOut,
+    #[error("Insufficient balance")]
+    InsufficientFunds,
+    #[error("Deposits must be more than zero")]
+    InvalidAmount,
+    #[error("Amount is too large")]
+    AmountTooLarge,
+}
+
+pub fn user_account(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+fn balance_key(account: &str, currency: &str) -> String {
+    format!("balance/{}/{}", account, currency)
+}
+
+fn stock_key(image_id: &str) -> String {
+    format!("stock/{}", image_id)
+}
+
+fn purchase_key(username: &str, image_id: &str) -> String {
+    format!("purchase/{}/{}", username, image_id)
+}
+
+fn discount_uses_key(discount_id: &str) -> String {
+    format!("discount/{}", discount_id)
+}
+
+fn transaction_key(transaction: &LedgerTransaction) -> String {
+    // Zero-padded, so transactions are stored in the order they happened.
+    format!("transaction/{:020}/{}", transaction.created, transaction.id)
+}
+
+fn account_transaction_key(account: &str, transaction: &LedgerTransaction) -> String {
+    format!(
+        "account/{}/{:020}/{}",
+        account, transaction.created, transaction.id
+    )
+}
+
+pub fn is_currency_code(currency: &str) -> bool {
+    currency.len() == 3 && currency.chars().all(|c| c.is_ascii_uppercase())
+}
+
+/// Sets the listing for one of the owner's images, resetting its remaining stock.
+pub fn set_listing(
+    image_id: &str,
+    owner: &str,
+    listing: Listing,
+    db: &Database,
+) -> anyhow::Result<Image> {
+    let listing = listing.validate()?;
+    let mut image = db
+        .images
+        .get(image_id.as_bytes())?
+        .filter(|image| image.is_owned_by(owner))
+        .ok_or(MarketError::UnknownImage)?;
+
+    image.set_listing(listing);
+    images::update_image(image.clone(), db)?;
+    set_stock(&image, db)?;
+
+    Ok(image)
+}
+
+/// Records the remaining stock of a newly listed image.
+pub fn set_stock(image: &Image, db: &Database) -> anyhow::Result<()> {
+    let key = stock_key(image.id());
+
+    match image.listing().inventory {
+        Some(inventory) => db
+            .ledger
+            .insert(key.as_bytes(), LedgerRecord::Stock(inventory))?,
+        None => db.ledger.remove(key.as_bytes())?,
+    };
+
+    Ok(())
+}
+
+/// The price an image sells for right now, after the best discount that can still be used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Quote {
+    pub list_price: u64,
+    pub price: u64,
+    pub currency: String,
+    pub discount_id: Option<String>,
+}
+
+/// Works out what an image would sell for right now.
+pub fn quote(image: &Image, db: &Database) -> anyhow::Result<Quote> {
+    let listing = image.listing();
+    let (list_price, currency) = match (listing.price, listing.currency) {
+        (Some(price), Some(currency)) => (price, currency),
+        _ => Err(MarketError::NotForSale)?,
+    };
+
+    for (discount, price) in discount::applicable_discounts(image, db)? {
+        let uses = discount_uses(&discount.id, db)?;
+        if discount.max_uses.map_or(true, |max_uses| uses < max_uses) {
+            return Ok(Quote {
+                list_price,
+                price,
+                currency,
+                discount_id: Some(discount.id),
+            });
+        }
+    }
+
+    Ok(Quote {
+        list_price,
+        price: list_price,
+        currency,
+        discount_id: None,
+    })
+}
+
+/// Buys an image, moving its price from the buyer's balance to the seller's.  The best discount that can still be
+/// used is applied.  Returns the transaction, and the price it was bought for.
+pub fn purchase_image(
+    buyer: &str,
+    image_id: &str,
+    db: &Database,
+) -> anyhow::Result<(LedgerTransaction, Quote)> {
+    let image = db
+        .images
+        .get(image_id.as_bytes())?
+        .filter(|image| image.is_viewable_by(Some(buyer)))
+        .ok_or(MarketError::UnknownImage)?;
+    let listing = image.listing();
+
+    let (list_price, currency) = match (listing.price, listing.currency) {
+        (Some(price), Some(currency)) => (price, currency),
+        _ => Err(MarketError::NotForSale)?,
+    };
+    if image.is_owned_by(buyer) {
+        Err(MarketError::OwnImage)?
+    }
+
+    // Usage limits are checked in the transaction, so a discount can't be used more times than allowed.
+    let discounts = discount::applicable_discounts(&image, db)?;
+
+    let buyer_account = user_account(buyer);
+    let seller_account = user_account(image.owner());
+    let transaction_id = nanoid!();
+    let created = Utc::now().timestamp();
+
+    let purchase_key = purchase_key(buyer, image.id());
+    let stock_key = stock_key(image.id());
+    let buyer_balance_key = balance_key(&buyer_account, &currency);
+    let seller_balance_key = balance_key(&seller_account, &currency);
+    let limited = listing.inventory.is_some();
+    let image_id = image.id().to_string();
+
+    let (transaction, quote, remaining_stock) = db
+        .ledger
+        .transaction(move |tx_db| {
+            if try_tx!(tx_db.get(purchase_key.as_bytes())?).is_some() {
+                return Ok(Err(MarketError::AlreadyPurchased.into()));
+            }
+
+            let stock = match try_tx!(tx_db.get(stock_key.as_bytes())?) {
+                Some(LedgerRecord::Stock(stock)) => Some(stock),
+                _ => None,
+            };
+            if limited && stock.unwrap_or(0) == 0 {
+                return Ok(Err(MarketError::SoldOut.into()));
+            }
+
+            let mut applied = None;
+            for (discount, price) in &discounts {
+                let uses = match try_tx!(tx_db.get(discount_uses_key(&discount.id).as_bytes())?) {
+                    Some(LedgerRecord::DiscountUses(uses)) => uses,
+                    _ => 0,
+                };
+                if discount.max_uses.map_or(true, |max_uses| uses < max_uses) {
+                    applied = Some((discount.id.clone(), *price, uses));
+                    break;
+                }
+            }
+
+            let quote = Quote {
+                list_price,
+                price: applied.as_ref().map_or(list_price, |(_, price, _)| *price),
+                currency: currency.clone(),
+                discount_id: applied
+                    .as_ref()
+                    .map(|(discount_id, _, _)| discount_id.clone()),
+            };
+            let price = match i64::try_from(quote.price) {
+                Ok(price) => price,
+                Err(_) => return Ok(Err(MarketError::AmountTooLarge.into())),
+            };
+
+            let buyer_balance = match try_tx!(tx_db.get(buyer_balance_key.as_bytes())?) {
+                Some(LedgerRecord::Balance(balance)) => balance,
+                _ => 0,
+            };
+            if buyer_balance < price {
+                return Ok(Err(MarketError::InsufficientFunds.into()));
+            }
+
+            let seller_balance = match try_tx!(tx_db.get(seller_balance_key.as_bytes())?) {
+                Some(LedgerRecord::Balance(balance)) => balance,
+                _ => 0,
+            };
+
+            let (buyer_balance, seller_balance) = match (
+                buyer_balance.checked_sub(price),
+                seller_balance.checked_add(price),
+            ) {
+                (Some(buyer_balance), Some(seller_balance)) => (buyer_balance, seller_balance),
+                _ => return Ok(Err(MarketError::AmountTooLarge.into())),
+            };
+
+            let transaction = LedgerTransaction {
+                id: transaction_id.clone(),
+                created,
+                description: format!("Purchase of {}", image_id),
+                image_id: Some(image_id.clone()),
+                postings: vec![
+                    Posting {
+                        account: buyer_account.clone(),
+                        currency: currency.clone(),
+                        amount: -price,
+                    },
+                    Posting {
+                        account: seller_account.clone(),
+                        currency: currency.clone(),
+                        amount: price,
+                    },
+                ],
+            };
+
+            // Errors returned from here on would still commit, so every check has to come before this.
+            let remaining_stock = stock.map(|stock| stock - 1);
+            if let Some(remaining_stock) = remaining_stock {
+                try_tx!(tx_db.insert(stock_key.as_bytes(), LedgerRecord::Stock(remaining_stock))?);
+            }
+            if let Some((discount_id, _, uses)) = &applied {
+                try_tx!(tx_db.insert(
+                    discount_uses_key(discount_id).as_bytes(),
+                    LedgerRecord::DiscountUses(uses + 1),
+                )?);
+            }
+            try_tx!(tx_db.insert(
+                buyer_balance_key.as_bytes(),
+                LedgerRecord::Balance(buyer_balance),
+            )?);
+            try_tx!(tx_db.insert(
+                seller_balance_key.as_bytes(),
+                LedgerRecord::Balance(seller_balance),
+            )?);
+            let purchase = Purchase {
+                transaction_id: transaction_id.clone(),
+                list_price,
+                price: quote.price,
+                currency: currency.clone(),
+                discount_id: quote.discount_id.clone(),
+            };
+            try_tx!(tx_db.insert(purchase_key.as_bytes(), LedgerRecord::Purchase(purchase))?);
+            for (key, record) in transaction_records(&transaction) {
+                try_tx!(tx_db.insert(key.as_bytes(), record)?);
+            }
+
+            Ok(Ok((transaction, quote, remaining_stock)))
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    // The ledger holds the real stock, this just keeps what listings show in step.
+    if let Some(remaining_stock) = remaining_stock {
+        let mut image = image;
+        image.set_inventory(Some(remaining_stock));
+        images::update_image(image, db)?;
+    }
+
+    Ok((transaction, quote))
+}
+
+/// Credits a user's balance from outside foto.
+pub fn deposit(deposit: Deposit, db: &Database) -> anyhow::Result<LedgerTransaction> {
+    if deposit.amount == 0 {
+        Err(MarketError::InvalidAmount)?
+    }
+
+    let currency = deposit.currency.to_uppercase();
+    if !is_currency_code(&currency) {
+        Err(MarketError::InvalidListing)?
+    }
+    if db.users.get(deposit.username.as_bytes())?.is_none() {
+        Err(MarketError::UnknownUser)?
+    }
+
+    let amount = i64::try_from(deposit.amount).map_err(|_| MarketError::AmountTooLarge)?;
+    let account = user_account(&deposit.username);
+    let transaction = LedgerTransaction {
+        id: nanoid!(),
+        created: Utc::now().timestamp(),
+        description: "Deposit".to_string(),
+        image_id: None,
+        postings: vec![
+            Posting {
+                account: DEPOSITS_ACCOUNT.to_string(),
+                currency: currency.clone(),
+                amount: -amount,
+            },
+            Posting {
+                account: account.clone(),
+                currency: currency.clone(),
+                amount,
+            },
+        ],
+    };
+
+    let deposits_key = balance_key(DEPOSITS_ACCOUNT, &currency);
+    let account_key = balance_key(&account, &currency);
+    let record = transaction.clone();
+
+    db.ledger
+        .transaction(move |tx_db| {
+            let mut balances = vec![];
+            for (key, amount) in &[(&deposits_key, -amount), (&account_key, amount)] {
+                let balance = match try_tx!(tx_db.get(key.as_bytes())?) {
+                    Some(LedgerRecord::Balance(balance)) => balance,
+                    _ => 0,
+                };
+                // Checked before anything is written, since returning an error doesn't abort the transaction.
+                match balance.checked_add(*amount) {
+                    Some(balance) => balances.push((key, balance)),
+                    None => return Ok(Err(MarketError::AmountTooLarge.into())),
+                }
+            }
+            for (key, balance) in balances {
+                try_tx!(tx_db.insert(key.as_bytes(), LedgerRecord::Balance(balance))?);
+            }
+
+            for (key, record) in transaction_records(&record) {
+                try_tx!(tx_db.insert(key.as_bytes(), record)?);
+            }
+
+            Ok(Ok(()))
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    Ok(transaction)
+}
+
+/// How many purchases have used a discount.
+pub fn discount_uses(discount_id: &str, db: &Database) -> anyhow::Result<u32> {
+    match db.ledger.get(discount_uses_key(discount_id).as_bytes())? {
+        Some(LedgerRecord::DiscountUses(uses)) => Ok(uses),
+        _ => Ok(0),
+    }
+}
+
+/// Forgets how many times a deleted discount was used.
+pub fn remove_discount_uses(discount_id: &str, db: &Database) -> anyhow::Result<()> {
+    db.ledger
+        .remove(discount_uses_key(discount_id).as_bytes())?;
+
+    Ok(())
+}
+
+/// Whether a user has bought an image.
+pub fn has_purchased(username: &str, image_id: &str, db: &Database) -> anyhow::Result<bool> {
+    Ok(db
+        .ledger
+        .get(purchase_key(username, image_id).as_bytes())?
+        .is_some())
+}
+
+/// The records to store for a transaction: the transaction itself, and an entry under each account it posts to.
+fn transaction_records(transaction: &LedgerTransaction) -> Vec<(String, LedgerRecord)> {
+    let key = transaction_key(transaction);
+    let mut records: Vec<(String, LedgerRecord)> = transaction
+        .postings
+        .iter()
+        .map(|posting| {
+            (
+                account_transaction_key(&posting.account, transaction),
+                LedgerRecord::AccountTransaction(key.clone()),
+            )
+        })
+        .collect();
+    records.push((key, LedgerRecord::Transaction(transaction.clone())));
+
+    records
+}
+
+/// Gets a user's balance in each currency they have used.
+pub fn balances(username: &str, db: &Database) -> anyhow::Result<Vec<(String, i64)>> {
+    let prefix = format!("balance/{}/", user_account(username));
+    let mut balances = vec![];
+
+    for entry in db.ledger.scan_prefix(prefix.as_bytes()) {
+        let (key, record) = entry?;
+        let key = String::from_utf8_lossy(&key);
+
+        if let (Some(currency), LedgerRecord::Balance(balance)) =
+            (key.strip_prefix(prefix.as_str()), record)
+        {
+            balances.push((currency.to_string(), balance));
+        }
+    }
+
+    Ok(balances)
+}
+
+/// Lists the transactions touching a user's balance, newest first.
+pub fn transactions(username: &str, db: &Database) -> anyhow::Result<Vec<LedgerTransaction>> {
+    let prefix = format!("account/{}/", user_account(username));
+    let mut transactions = vec![];
+
+    for entry in db.ledger.scan_prefix(prefix.as_bytes()).rev() {
+        if let (_, LedgerRecord::AccountTransaction(key)) = entry? {
+            if let Some(LedgerRecord::Transaction(transaction)) = db.ledger.get(key.as_bytes())? {
+                transactions.push(transaction);
+            }
+        }
+    }
+
+    Ok(transactions)
+}
@@ -0,0 +1,485 @@
+//! Camera metadata read from an image's EXIF data when it's uploaded, and removing metadata from originals before
+//! they are stored.
+
+use std::io::Cursor;
+
+use chrono::NaiveDate;
+use exif::{DateTime, Field, In, Reader, Tag, Value};
+use img_hash::image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::config::MetadataPrivacy;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADERS: [&[u8]; 2] = [
+    b"http://ns.adobe.com/xap/1.0/\0",
+    b"http://ns.adobe.com/xmp/extension/\0",
+];
+/// IPTC is stored in Photoshop's resource block.
+const IPTC_HEADER: &[u8] = b"Photoshop 3.0\0";
+/// The EXIF tag pointing to the GPS IFD.
+const GPS_IFD_TAG: u16 = 0x8825;
+/// Flags in a WebP's extended header saying it has EXIF and XMP chunks.
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+/// The identifier and authentication code of the GIF application extension holding XMP.
+const GIF_XMP_APPLICATION: &[u8] = b"XMP DataXMP";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Exif {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    /// In seconds.
+    pub exposure_time: Option<f64>,
+    pub f_number: Option<f64>,
+    /// In millimetres.
+    pub focal_length: Option<f64>,
+    pub iso: Option<u32>,
+    /// Unix timestamp of when the photo was taken.  Cameras that don't record their time zone are taken to be in UTC.
+    pub captured: Option<i64>,
+    /// In degrees, negative south of the equator.
+    pub latitude: Option<f64>,
+    /// In degrees, negative west of the prime meridian.
+    pub longitude: Option<f64>,
+    /// From 1 to 8, as defined by EXIF.  1 is upright.
+    pub orientation: Option<u32>,
+}
+
+impl Exif {
+    /// Reads the EXIF data in an image file.  Returns `None` if there isn't any, or none of it is understood.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Exif> {
+        let exif = Reader::new()
+            .read_from_container(&mut Cursor::new(bytes))
+            .ok()?;
+        let field = |tag| exif.get_field(tag, In::PRIMARY);
+
+        let metadata = Exif {
+            make: field(Tag::Make).and_then(ascii),
+            model: field(Tag::Model).and_then(ascii),
+            lens: field(Tag::LensModel).and_then(ascii),
+            exposure_time: field(Tag::ExposureTime).and_then(|field| rational(field, 0)),
+            f_number: field(Tag::FNumber).and_then(|field| rational(field, 0)),
+            focal_length: field(Tag::FocalLength).and_then(|field| rational(field, 0)),
+            iso: field(Tag::PhotographicSensitivity).and_then(|field| field.value.get_uint(0)),
+            captured: field(Tag::DateTimeOriginal)
+                .and_then(|captured| timestamp(captured, field(Tag::OffsetTimeOriginal))),
+            latitude: coordinate(field(Tag::GPSLatitude), field(Tag::GPSLatitudeRef), "S"),
+            longitude: coordinate(field(Tag::GPSLongitude), field(Tag::GPSLongitudeRef), "W"),
+            orientation: field(Tag::Orientation).and_then(|field| field.value.get_uint(0)),
+        };
+
+        Some(metadata).filter(|metadata| *metadata != Exif::default())
+    }
+
+    /// Drops anything the privacy mode removes from stored originals, so it isn't shown through the API either.
+    pub fn redact(mut self, privacy: MetadataPrivacy) -> Exif {
+        if privacy != MetadataPrivacy::Keep {
+            self.latitude = None;
+            self.longitude = None;
+        }
+
+        self
+    }
+}
+
+/// Removes metadata from an original as the privacy mode says, returning the bytes to store and their format.  JPEGs,
+/// WebPs and GIFs have metadata removed without being recompressed, so animated GIFs keep all of their frames.  PNG
+/// and BMP images are re-encoded from the decoded image, which drops all metadata, and anything else is converted to
+/// PNG the same way, since those formats either can't be written back or can carry metadata that isn't understood
+/// here.
+pub fn strip(
+    original: &[u8],
+    format: ImageFormat,
+    decoded: &DynamicImage,
+    privacy: MetadataPrivacy,
+) -> anyhow::Result<(Vec<u8>, ImageFormat)> {
+    if privacy == MetadataPrivacy::Keep {
+        return Ok((original.to_vec(), format));
+    }
+
+    let (format, output_format) = match format {
+        ImageFormat::Jpeg => return Ok((strip_jpeg(original, privacy)?, format)),
+        ImageFormat::WebP => return Ok((strip_webp(original, privacy)?, format)),
+        ImageFormat::Gif => return Ok((strip_gif(original, privacy)?, format)),
+        ImageFormat::Png => (format, ImageOutputFormat::Png),
+        ImageFormat::Bmp => (format, ImageOutputFormat::Bmp),
+        _ => (ImageFormat::Png, ImageOutputFormat::Png),
+    };
+
+    let mut bytes = vec![];
+    decoded.write_to(&mut bytes, output_format)?;
+
+    Ok((bytes, format))
+}
+
+/// Copies a JPEG segment by segment, leaving out or cleaning metadata segments.  Everything from the start of the
+/// compressed image data onwards is copied as is.
+fn strip_jpeg(original: &[u8], privacy: MetadataPrivacy) -> anyhow::Result<Vec<u8>> {
+    let invalid = || anyhow::format_err!("Invalid JPEG.");
+
+    if !original.starts_with(&[0xff, 0xd8]) {
+        Err(invalid())?
+    }
+
+    let mut stripped = original[..2].to_vec();
+    let mut position = 2;
+
+    while position < original.len() {
+        if original[position] != 0xff {
+            Err(invalid())?
+        }
+
+        let marker = *original.get(position + 1).ok_or_else(invalid)?;
+        match marker {
+            // Padding before a marker.
+            0xff => {
+                position += 1;
+                continue;
+            }
+            // Start of scan, or end of image.  Neither can hold metadata after them.
+            0xda | 0xd9 => {
+                stripped.extend_from_slice(&original[position..]);
+                break;
+            }
+            // Markers without a length.
+            0x01 | 0xd0..=0xd7 => {
+                stripped.extend_from_slice(&original[position..position + 2]);
+                position += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let length = original
+            .get(position + 2..position + 4)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .filter(|length| *length >= 2)
+            .ok_or_else(invalid)?;
+        let end = position + 2 + length;
+        let mut segment = original.get(position..end).ok_or_else(invalid)?.to_vec();
+        let payload = &segment[4..];
+
+        let is_exif = marker == 0xe1 && payload.starts_with(EXIF_HEADER);
+        let is_xmp = marker == 0xe1 && XMP_HEADERS.iter().any(|header| payload.starts_with(header));
+        let is_iptc = marker == 0xed && payload.starts_with(IPTC_HEADER);
+
+        let keep = match privacy {
+            MetadataPrivacy::Keep => true,
+            MetadataPrivacy::StripAll => !(is_exif || is_xmp || is_iptc),
+            MetadataPrivacy::StripGps if is_exif => {
+                remove_gps(&mut segment[4 + EXIF_HEADER.len()..]);
+                true
+            }
+            // XMP can hold a copy of the GPS coordinates.
+            MetadataPrivacy::StripGps => {
+                !(is_xmp && payload.windows(8).any(|window| window == b"exif:GPS"))
+            }
+        };
+
+        if keep {
+            stripped.extend(segment);
+        }
+        position = end;
+    }
+
+    Ok(stripped)
+}
+
+/// Copies a WebP chunk by chunk, leaving out or cleaning the EXIF and XMP chunks, and clearing their flags in the
+/// extended header when they are left out.  Image data is copied as is.
+fn strip_webp(original: &[u8], privacy: MetadataPrivacy) -> anyhow::Result<Vec<u8>> {
+    let invalid = || anyhow::format_err!("Invalid WebP.");
+
+    if original.len() < 12 || &original[..4] != b"RIFF" || &original[8..12] != b"WEBP" {
+        Err(invalid())?
+    }
+
+    let mut stripped = original[..12].to_vec();
+    let mut extended_header = None;
+    let mut has_exif = false;
+    let mut has_xmp = false;
+    let mut position = 12;
+
+    while position < original.len() {
+        let header = original.get(position..position + 8).ok_or_else(invalid)?;
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        // Chunks are padded to an even length.
+        let end = position + 8 + length + length % 2;
+        let mut chunk = original.get(position..end).ok_or_else(invalid)?.to_vec();
+
+        let keep = match (&header[..4], privacy) {
+            (b"EXIF", MetadataPrivacy::StripGps) => {
+                let payload = &mut chunk[8..8 + length];
+                // Some encoders keep the header JPEG puts in front of the TIFF data.
+                let tiff = if payload.starts_with(EXIF_HEADER) {
+                    &mut payload[EXIF_HEADER.len()..]
+                } else {
+                    payload
+                };
+                remove_gps(tiff);
+                true
+            }
+            (b"EXIF", _) => false,
+            // XMP can hold a copy of the GPS coordinates.
+            (b"XMP ", MetadataPrivacy::StripGps) => !chunk[8..8 + length]
+                .windows(8)
+                .any(|window| window == b"exif:GPS"),
+            (b"XMP ", _) => false,
+            _ => true,
+        };
+
+        if keep {
+            match &chunk[..4] {
+                b"VP8X" => extended_header = Some(stripped.len()),
+                b"EXIF" => has_exif = true,
+                b"XMP " => has_xmp = true,
+                _ => {}
+            }
+            stripped.extend(chunk);
+        }
+        position = end;
+    }
+
+    // The flags are the first byte of the extended header's payload.
+    if let Some(flags) = extended_header.and_then(|header| stripped.get_mut(header + 8)) {
+        if !has_exif {
+            *flags &= !WEBP_EXIF_FLAG;
+        }
+        if !has_xmp {
+            *flags &= !WEBP_XMP_FLAG;
+        }
+    }
+
+    let riff_length = (stripped.len() - 8) as u32;
+    stripped[4..8].copy_from_slice(&riff_length.to_le_bytes());
+
+    Ok(stripped)
+}
+
+/// Copies a GIF block by block, leaving out the XMP application extension.  GIFs can't hold EXIF data, and frames
+/// are copied as is.
+fn strip_gif(original: &[u8], privacy: MetadataPrivacy) -> anyhow::Result<Vec<u8>> {
+    let invalid = || anyhow::format_err!("Invalid GIF.");
+
+    if !original.starts_with(b"GIF87a") && !original.starts_with(b"GIF89a") {
+        Err(invalid())?
+    }
+
+    // The header and logical screen descriptor, followed by the global color table if there is one.
+    let flags = *original.get(10).ok_or_else(invalid)?;
+    let mut position = 13 + color_table_length(flags);
+    let mut stripped = original.get(..position).ok_or_else(invalid)?.to_vec();
+
+    // Extensions and image data are split into sub-blocks, each starting with its length and ended by an empty one.
+    let sub_blocks_end = |mut position: usize| -> anyhow::Result<usize> {
+        loop {
+            let length = *original.get(position).ok_or_else(invalid)? as usize;
+            position += 1 + length;
+            if length == 0 {
+                return Ok(position);
+            }
+        }
+    };
+
+    while position < original.len() {
+        let end = match original[position] {
+            // An extension, whose label comes before its sub-blocks.
+            0x21 => sub_blocks_end(position + 2)?,
+            // An image descriptor, followed by the local color table if there is one, the LZW code size and the image
+            // data.
+            0x2c => {
+                let flags = *original.get(position + 9).ok_or_else(invalid)?;
+                sub_blocks_end(position + 11 + color_table_length(flags))?
+            }
+            // The trailer, ending the file.
+            0x3b => {
+                stripped.extend_from_slice(&original[position..]);
+                break;
+            }
+            _ => Err(invalid())?,
+        };
+        let block = original.get(position..end).ok_or_else(invalid)?;
+
+        let is_xmp = block.get(1) == Some(&0xff)
+            && block.get(2) == Some(&(GIF_XMP_APPLICATION.len() as u8))
+            && block.get(3..3 + GIF_XMP_APPLICATION.len()) == Some(GIF_XMP_APPLICATION);
+        let keep = match privacy {
+            MetadataPrivacy::Keep => true,
+            MetadataPrivacy::StripAll => !is_xmp,
+            // XMP can hold a copy of the GPS coordinates.
+            MetadataPrivacy::StripGps => {
+                !(is_xmp && block.windows(8).any(|window| window == b"exif:GPS"))
+            }
+        };
+
+        if keep {
+            stripped.extend_from_slice(block);
+        }
+        position = end;
+    }
+
+    Ok(stripped)
+}
+
+/// How long the color table described by a GIF's packed fields is, in bytes.
+fn color_table_length(flags: u8) -> usize {
+    if flags & 0x80 == 0 {
+        0
+    } else {
+        3 << ((flags & 0x07) + 1)
+    }
+}
+
+/// Empties the GPS IFD of TIFF-structured EXIF data in place, zeroing its entries and any values they point to.
+/// Nothing else moves, so other offsets stay valid.  Data that can't be understood is left alone.
+fn remove_gps(tiff: &mut [u8]) {
+    let big_endian = match tiff.get(..2) {
+        Some(b"MM") => true,
+        Some(b"II") => false,
+        _ => return,
+    };
+    let read_u16 = |tiff: &[u8], offset: usize| {
+        tiff.get(offset..offset + 2).map(|bytes| {
+            let bytes = [bytes[0], bytes[1]];
+            if big_endian {
+                u16::from_be_bytes(bytes)
+            } else {
+                u16::from_le_bytes(bytes)
+            }
+        })
+    };
+    let read_u32 = |tiff: &[u8], offset: usize| {
+        tiff.get(offset..offset + 4).map(|bytes| {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        })
+    };
+
+    let ifd0 = match read_u32(tiff, 4) {
+        Some(ifd0) => ifd0 as usize,
+        None => return,
+    };
+    let gps_ifd = read_u16(tiff, ifd0)
+        .and_then(|entries| {
+            (0..entries as usize)
+                .map(|index| ifd0 + 2 + index * 12)
+                .find(|&entry| read_u16(tiff, entry) == Some(GPS_IFD_TAG))
+        })
+        .and_then(|entry| read_u32(tiff, entry + 8));
+    let gps_ifd = match gps_ifd {
+        Some(gps_ifd) => gps_ifd as usize,
+        None => return,
+    };
+    let entries = match read_u16(tiff, gps_ifd) {
+        Some(entries) => entries as usize,
+        None => return,
+    };
+
+    for index in 0..entries {
+        let entry = gps_ifd + 2 + index * 12;
+        let (kind, count, value) = match (
+            read_u16(tiff, entry + 2),
+            read_u32(tiff, entry + 4),
+            read_u32(tiff, entry + 8),
+        ) {
+            (Some(kind), Some(count), Some(value)) => (kind, count, value),
+            _ => break,
+        };
+
+        let unit_size = match kind {
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => 1,
+        };
+        let size = unit_size * count as usize;
+        // Values of four bytes or less are stored in the entry itself.
+        if size > 4 {
+            if let Some(values) = tiff.get_mut(value as usize..value as usize + size) {
+                values.iter_mut().for_each(|byte| *byte = 0);
+            }
+        }
+        if let Some(entry) = tiff.get_mut(entry..entry + 12) {
+            entry.iter_mut().for_each(|byte| *byte = 0);
+        }
+    }
+
+    // With no entries, the zeroed first entry is read as a null pointer to the next IFD.
+    if let Some(count) = tiff.get_mut(gps_ifd..gps_ifd + 2) {
+        count.iter_mut().for_each(|byte| *byte = 0);
+    }
+}
+
+/// The first string in a text field, without the padding some cameras add.
+fn ascii(field: &Field) -> Option<String> {
+    match &field.value {
+        Value::Ascii(strings) => strings
+            .first()
+            .map(|string| {
+                String::from_utf8_lossy(string)
+                    .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                    .to_string()
+            })
+            .filter(|string| !string.is_empty()),
+        _ => None,
+    }
+}
+
+fn rational(field: &Field, index: usize) -> Option<f64> {
+    match &field.value {
+        Value::Rational(rationals) => rationals
+            .get(index)
+            .filter(|rational| rational.denom != 0)
+            .map(|rational| rational.to_f64()),
+        _ => None,
+    }
+}
+
+/// Converts degrees, minutes and seconds to degrees, negated if the reference is `negative_ref`.
+fn coordinate(
+    degrees: Option<&Field>,
+    reference: Option<&Field>,
+    negative_ref: &str,
+) -> Option<f64> {
+    let degrees = degrees?;
+    let value = rational(degrees, 0)?
+        + rational(degrees, 1).unwrap_or(0.0) / 60.0
+        + rational(degrees, 2).unwrap_or(0.0) / 3600.0;
+
+    match reference.and_then(ascii) {
+        Some(reference) if reference.eq_ignore_ascii_case(negative_ref) => Some(-value),
+        _ => Some(value),
+    }
+}
+
+fn timestamp(captured: &Field, offset: Option<&Field>) -> Option<i64> {
+    let mut datetime = match &captured.value {
+        Value::Ascii(strings) => DateTime::from_ascii(strings.first()?).ok()?,
+        _ => return None,
+    };
+    if let Some(Value::Ascii(strings)) = offset.map(|offset| &offset.value) {
+        if let Some(offset) = strings.first() {
+            // An unreadable offset is treated the same as a missing one.
+            let _ = datetime.parse_offset(offset);
+        }
+    }
+
+    let local = NaiveDate::from_ymd_opt(
+        datetime.year as i32,
+        datetime.month as u32,
+        datetime.day as u32,
+    )?
+    .and_hms_opt(
+        datetime.hour as u32,
+        datetime.minute as u32,
+        datetime.second as u32,
+    )?;
+
+    Some(local.timestamp() - datetime.offset.unwrap_or(0) as i64 * 60)
+}
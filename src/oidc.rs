@@ -0,0 +1,373 @@
+//! Logging in through an external OpenID Connect provider, using the authorization code flow with PKCE.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use nanoid::nanoid;
+use reqwest::{Client, ClientBuilder, Url};
+use ring::{constant_time, digest};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    config::{AccountPolicyConfig, OidcConfig, RegistrationMode},
+    policy::{normalize_username, validate_username, PolicyError},
+    user::{add_external_user, User},
+    Database,
+};
+
+/// How long a user has to finish logging in with the provider, in seconds.
+const STATE_LIFETIME_SECONDS: i64 = 10 * 60;
+/// Holds the state of the login a browser started, so a login can only be finished by the browser that started it.
+/// Otherwise, someone could send a victim the callback URL for their own login and log them into the wrong account.
+pub const STATE_COOKIE: &str = "foto_oidc_state";
+/// Separates the username from the link in `user_oidc_links` keys.  Usernames can't contain it, so one user's keys
+/// never overlap another's.
+const KEY_SEPARATOR: char = '\0';
+const USERNAME_SUFFIX_ALPHABET: [char; 36] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's',
+    't', 'u', 'v', 'w', 'x', 'y', 'z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+/// The OpenID Connect provider users can log in through, if one is configured.
+pub struct OidcProvider {
+    pub config: Option<OidcConfig>,
+}
+
+/// What we need to remember between sending a user to the provider and them coming back.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcLoginState {
+    pub code_verifier: String,
+    pub nonce: String,
+    /// Unix timestamp.
+    pub created: i64,
+    /// If a logged in user started the login, the provider account is linked to them.
+    pub link_to: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error("OpenID Connect login is not configured")]
+    NotConfigured,
+    #[error("Unknown or expired login, please try again")]
+    UnknownState,
+    #[error("Could not exchange the authorization code")]
+    TokenExchange,
+    #[error("Invalid ID token")]
+    InvalidIdToken,
+    #[error("No account is linked to this login, and registration is not open")]
+    RegistrationClosed,
+}
+
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
+/// Starts a login, returning the provider URL to send the user to and the state to keep in [`STATE_COOKIE`].
+pub async fn begin_login(
+    config: &OidcConfig,
+    link_to: Option<String>,
+    db: &Database,
+) -> anyhow::Result<(String, String)> {
+    let metadata = discover(config, &http_client()?).await?;
+
+    remove_expired_states(db)?;
+
+    let state = nanoid!(32);
+    let nonce = nanoid!(32);
+    let code_verifier = nanoid!(64);
+    let code_challenge = base64::encode_config(
+        digest::digest(&digest::SHA256, code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    db.oidc_states.insert(
+        state.as_bytes(),
+        OidcLoginState {
+            code_verifier,
+            nonce: nonce.clone(),
+            created: Utc::now().timestamp(),
+            link_to,
+        },
+    )?;
+
+    let mut url = Url::parse(&metadata.authorization_endpoint)?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &config.redirect_uri)
+        .append_pair("scope", &config.scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    Ok((url.to_string(), state))
+}
+
+/// Finishes a login given the code and state the provider sent the user back with, and the state in the browser's
+/// [`STATE_COOKIE`], returning the linked [`User`].  Users logging in for the first time are provisioned a new account,
+/// if registration is open.
+pub async fn finish_login(
+    config: &OidcConfig,
+    code: &str,
+    state: &str,
+    state_cookie: Option<&str>,
+    registration: RegistrationMode,
+    policy: &AccountPolicyConfig,
+    db: &Database,
+) -> anyhow::Result<User> {
+    let started_here = state_cookie.map_or(false, |state_cookie| {
+        constant_time::verify_slices_are_equal(state_cookie.as_bytes(), state.as_bytes()).is_ok()
+    });
+    if !started_here {
+        Err(OidcError::UnknownState)?
+    }
+
+    let login_state = db
+        .oidc_states
+        .remove(state.as_bytes())?
+        .ok_or(OidcError::UnknownState)?;
+
+    if Utc::now().timestamp() - login_state.created > STATE_LIFETIME_SECONDS {
+        Err(OidcError::UnknownState)?
+    }
+
+    let client = http_client()?;
+    let metadata = discover(config, &client).await?;
+
+    let response = client
+        .post(&metadata.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+            ("code_verifier", login_state.code_verifier.as_str()),
+        ])
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        Err(OidcError::TokenExchange)?
+    }
+
+    let tokens: TokenResponse = serde_json::from_str(&response.text().await?)?;
+    let claims = verify_id_token(&tokens.id_token, config, &metadata, &client).await?;
+
+    if claims.nonce.as_deref() != Some(login_state.nonce.as_str()) {
+        Err(OidcError::InvalidIdToken)?
+    }
+
+    link_or_provision(
+        config,
+        claims,
+        login_state.link_to,
+        registration,
+        policy,
+        db,
+    )
+}
+
+fn http_client() -> anyhow::Result<Client> {
+    Ok(ClientBuilder::new().timeout(Duration::new(10, 0)).build()?)
+}
+
+async fn discover(config: &OidcConfig, client: &Client) -> anyhow::Result<ProviderMetadata> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer.trim_end_matches('/')
+    );
+
+    Ok(serde_json::from_str(
+        &client.get(&url).send().await?.text().await?,
+    )?)
+}
+
+/// Checks an ID token's signature against the provider's published keys, and that it was issued to us.
+async fn verify_id_token(
+    id_token: &str,
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    client: &Client,
+) -> anyhow::Result<IdTokenClaims> {
+    let header = decode_header(id_token)?;
+    if !matches!(
+        header.alg,
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+    ) {
+        Err(OidcError::InvalidIdToken)?
+    }
+
+    let jwks: JwkSet =
+        serde_json::from_str(&client.get(&metadata.jwks_uri).send().await?.text().await?)?;
+    let jwk = jwks
+        .keys
+        .iter()
+        .filter(|jwk| jwk.kty == "RSA")
+        .find(|jwk| header.kid.is_none() || jwk.kid == header.kid)
+        .ok_or(OidcError::InvalidIdToken)?;
+
+    let (n, e) = match (&jwk.n, &jwk.e) {
+        (Some(n), Some(e)) => (n, e),
+        _ => Err(OidcError::InvalidIdToken)?,
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.leeway = 60;
+    validation.iss = Some(config.issuer.clone());
+    validation.set_audience(&[config.client_id.clone()]);
+
+    let token = decode::<IdTokenClaims>(
+        id_token,
+        &DecodingKey::from_rsa_components(n, e),
+        &validation,
+    )
+    .map_err(|_| OidcError::InvalidIdToken)?;
+
+    Ok(token.claims)
+}
+
+fn link_or_provision(
+    config: &OidcConfig,
+    claims: IdTokenClaims,
+    link_to: Option<String>,
+    registration: RegistrationMode,
+    policy: &AccountPolicyConfig,
+    db: &Database,
+) -> anyhow::Result<User> {
+    // Subjects are only unique per issuer.
+    let link_key = format!("{}|{}", config.issuer, claims.sub);
+
+    if let Some(username) = db.oidc_links.get(link_key.as_bytes())? {
+        if let Some(user) = db.users.get(username.as_bytes())? {
+            return Ok(user);
+        }
+    }
+
+    let user = match link_to.and_then(|username| db.users.get(username.as_bytes()).transpose()) {
+        Some(user) => user?,
+        // Invite codes can't be passed through the provider, so only open registration provisions accounts.
+        None if registration == RegistrationMode::Open => provision_user(&claims, policy, db)?,
+        None => Err(OidcError::RegistrationClosed)?,
+    };
+
+    db.oidc_links
+        .insert(link_key.as_bytes(), user.username.clone())?;
+    db.user_oidc_links.insert(
+        user_link_key(&user.username, &link_key).as_bytes(),
+        link_key,
+    )?;
+
+    Ok(user)
+}
+
+fn user_link_key(username: &str, link_key: &str) -> String {
+    format!("{}{}{}", username, KEY_SEPARATOR, link_key)
+}
+
+/// Unlinks all provider accounts from a deleted user.
+pub fn delete_user_links(username: &str, db: &Database) -> anyhow::Result<()> {
+    for entry in db
+        .user_oidc_links
+        .scan_prefix(user_link_key(username, "").as_bytes())
+    {
+        let (key, link_key) = entry?;
+        db.oidc_links.remove(link_key.as_bytes())?;
+        db.user_oidc_links.remove(key)?;
+    }
+
+    Ok(())
+}
+
+/// Creates a user with a free username based on what the provider calls them.
+fn provision_user(
+    claims: &IdTokenClaims,
+    policy: &AccountPolicyConfig,
+    db: &Database,
+) -> anyhow::Result<User> {
+    let preferred = claims
+        .preferred_username
+        .clone()
+        .or_else(|| {
+            claims
+                .email
+                .as_ref()
+                .and_then(|email| email.split('@').next().map(str::to_string))
+        })
+        .unwrap_or_default();
+
+    let mut base: String = normalize_username(&preferred)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .take(policy.username_max_length.saturating_sub(7))
+        .collect();
+    if base.chars().count() < policy.username_min_length {
+        base = "user".to_string();
+    }
+
+    let candidates = std::iter::once(base.clone())
+        .chain((0..5).map(|_| format!("{}_{}", base, nanoid!(6, &USERNAME_SUFFIX_ALPHABET))));
+    for candidate in candidates {
+        if validate_username(&candidate, policy).is_err() {
+            continue;
+        }
+
+        // Taking the name can fail even if it was free a moment ago, if someone else took it first.
+        match add_external_user(&candidate, db) {
+            Ok(user) => return Ok(user),
+            Err(err)
+                if matches!(
+                    err.downcast_ref::<PolicyError>(),
+                    Some(PolicyError::UsernameTaken)
+                ) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(anyhow::format_err!("Could not find a free username"))
+}
+
+fn remove_expired_states(db: &Database) -> anyhow::Result<()> {
+    let now = Utc::now().timestamp();
+
+    for entry in db.oidc_states.iter() {
+        let (key, state) = entry?;
+        if now - state.created > STATE_LIFETIME_SECONDS {
+            db.oidc_states.remove(key)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Rules for which usernames and passwords are accepted.
+
+use std::collections::HashSet;
+
+use once_cell::sync::Lazy;
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::AccountPolicyConfig;
+
+/// A bundled list of commonly used passwords, compared case-insensitively.
+static COMMON_PASSWORDS: Lazy<HashSet<String>> = Lazy::new(|| {
+    include_str!("common_passwords.txt")
+        .lines()
+        .map(|line| line.trim().to_lowercase())
+        .filter(|line| !line.is_empty())
+        .collect()
+});
+
+#[derive(Error, Debug)]
+/// A username or password that breaks the configured policy.
+pub enum PolicyError {
+    #[error("Username must be between {0} and {1} characters long")]
+    UsernameLength(usize, usize),
+    #[error("Username may only contain letters, numbers, and the following symbols: {0}")]
+    UsernameCharacters(String),
+    #[error("Username is already taken")]
+    UsernameTaken,
+    #[error("Password must be at least {0} characters long")]
+    PasswordTooShort(usize),
+    #[error("Password is too common")]
+    PasswordTooCommon,
+}
+
+impl PolicyError {
+    /// The name of the field that caused the error.
+    pub fn field(&self) -> &'static str {
+        match self {
+            PolicyError::UsernameLength(..)
+            | PolicyError::UsernameCharacters(_)
+            | PolicyError::UsernameTaken => "username",
+            PolicyError::PasswordTooShort(_) | PolicyError::PasswordTooCommon => "password",
+        }
+    }
+}
+
+/// Normalizes a username to NFKC, which is how it is stored.
+pub fn normalize_username(username: &str) -> String {
+    username.nfkc().collect()
+}
+
+/// Returns the key used to keep usernames unique, so names differing only in case or normalization collide.
+pub fn username_key(username: &str) -> String {
+    normalize_username(username).to_lowercase()
+}
+
+/// Checks a normalized username against the policy.
+pub fn validate_username(username: &str, config: &AccountPolicyConfig) -> Result<(), PolicyError> {
+    let length = username.chars().count();
+    if length < config.username_min_length || length > config.username_max_length {
+        return Err(PolicyError::UsernameLength(
+            config.username_min_length,
+            config.username_max_length,
+        ));
+    }
+
+    let allowed = |c: char| {
+        if config.username_symbols.contains(c) {
+            true
+        } else if config.username_allow_unicode {
+            c.is_alphanumeric()
+        } else {
+            c.is_ascii_alphanumeric()
+        }
+    };
+
+    if !username.chars().all(allowed) {
+        return Err(PolicyError::UsernameCharacters(
+            config.username_symbols.clone(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks a password against the policy.
+pub fn validate_password(password: &str, config: &AccountPolicyConfig) -> Result<(), PolicyError> {
+    if password.chars().count() < config.password_min_length {
+        return Err(PolicyError::PasswordTooShort(config.password_min_length));
+    }
+
+    if config.deny_common_passwords && COMMON_PASSWORDS.contains(&password.to_lowercase()) {
+        return Err(PolicyError::PasswordTooCommon);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! Public profiles, and listing the images a user has uploaded.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    favorite::{self, LikedImage},
+    feed, images,
+    user::{self, find_user},
+    Database,
+};
+
+/// Longer display names are rejected.
+const MAX_DISPLAY_NAME_LEN: usize = 50;
+/// Longer bios are rejected.
+const MAX_BIO_LEN: usize = 500;
+
+/// Listings return this many images if no limit is given.
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+/// Listings return at most this many images.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub username: String,
+    /// Shown instead of the username if set.
+    pub display_name: Option<String>,
+    pub bio: String,
+    /// One of the user's own images.
+    pub avatar_image_id: Option<String>,
+    /// Unix timestamp.  Not set for users who registered before profiles existed.
+    pub created: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileUpdate {
+    /// An empty display name removes it.
+    display_name: Option<String>,
+    bio: Option<String>,
+    /// An empty ID removes the avatar.
+    avatar_image_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileStats {
+    /// How many of the user's images the viewer can see.
+    pub images: usize,
+    pub followers: usize,
+    pub following: usize,
+    /// How many likes the user's images have in total.
+    pub likes: u64,
+}
+
+/// A profile along with the user's stats.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileView {
+    #[serde(flatten)]
+    pub profile: Profile,
+    pub stats: ProfileStats,
+}
+
+/// One page of a user's images.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserImagePage {
+    pub images: Vec<LikedImage>,
+    /// How many of the user's images the viewer can see in total.
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("Could not find user")]
+    UnknownUser,
+    #[error("Display names can't be longer than 50 characters")]
+    InvalidDisplayName,
+    #[error("Bios can't be longer than 500 characters")]
+    InvalidBio,
+    #[error("Avatars have to be one of your own images that others can see")]
+    InvalidAvatar,
+}
+
+/// Creates an empty profile for a newly registered user.
+pub fn create_profile(username: &str, db: &Database) -> anyhow::Result<()> {
+    db.profiles.insert(
+        username.as_bytes(),
+        Profile {
+            username: username.to_string(),
+            display_name: None,
+            bio: String::new(),
+            avatar_image_id: None,
+            created: Some(Utc::now().timestamp()),
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Gets a user's profile and stats, as seen by the viewer.  Suspended users can't be found.
+pub fn get_profile(
+    username: &str,
+    viewer: Option<&str>,
+    db: &Database,
+) -> anyhow::Result<ProfileView> {
+    let username = visible_username(username, db)?;
+    let mut profile = stored_profile(&username, db)?;
+
+    // The avatar may have been deleted or made private since it was chosen.
+    if let Some(avatar_image_id) = &profile.avatar_image_id {
+        if images::get_image(avatar_image_id, None, db)?.is_none() {
+            profile.avatar_image_id = None;
+        }
+    }
+
+    let mut stats = ProfileStats {
+        images: 0,
+        followers: feed::follower_count(&username, db)?,
+        following: feed::following(&username, db)?.len(),
+        likes: 0,
+    };
+    for image_id in images::user_upload_ids(&username, None, db) {
+        let image_id = image_id?;
+        stats.likes += favorite::like_count(&image_id, db)?;
+
+        if let Some(image) = db.images.get(image_id.as_bytes())? {
+            if image.is_searchable_by(viewer) {
+                stats.images += 1;
+            }
+        }
+    }
+
+    Ok(ProfileView { profile, stats })
+}
+
+pub fn update_profile(
+    username: &str,
+    update: ProfileUpdate,
+    db: &Database,
+) -> anyhow::Result<Profile> {
+    let mut profile = stored_profile(username, db)?;
+
+    if let Some(display_name) = update.display_name {
+        let display_name = display_name.trim().to_string();
+        if display_name.chars().count() > MAX_DISPLAY_NAME_LEN {
+            Err(ProfileError::InvalidDisplayName)?
+        }
+        profile.display_name = Some(display_name).filter(|name| !name.is_empty());
+    }
+    if let Some(bio) = update.bio {
+        let bio = bio.trim().to_string();
+        if bio.chars().count() > MAX_BIO_LEN {
+            Err(ProfileError::InvalidBio)?
+        }
+        profile.bio = bio;
+    }
+    if let Some(avatar_image_id) = update.avatar_image_id {
+        if avatar_image_id.is_empty() {
+            profile.avatar_image_id = None;
+        } else {
+            match images::get_image(&avatar_image_id, None, db)? {
+                Some(image) if image.is_owned_by(username) => {}
+                _ => Err(ProfileError::InvalidAvatar)?,
+            }
+            profile.avatar_image_id = Some(avatar_image_id);
+        }
+    }
+
+    db.profiles.insert(username.as_bytes(), profile.clone())?;
+
+    Ok(profile)
+}
+
+/// Lists a page of the images a user has uploaded that the viewer can see, newest first.  Others only see public
+/// images, while users see all of their own.
+pub fn user_images(
+    username: &str,
+    viewer: Option<&str>,
+    offset: usize,
+    limit: usize,
+    db: &Database,
+) -> anyhow::Result<UserImagePage> {
+    let username = visible_username(username, db)?;
+    let limit = limit.min(MAX_PAGE_SIZE);
+
+    let mut total = 0;
+    let mut page = vec![];
+    for image_id in images::user_upload_ids(&username, None, db) {
+        if let Some(image) = db.images.get(image_id?.as_bytes())? {
+            if image.is_searchable_by(viewer) {
+                if total >= offset && page.len() < limit {
+                    page.push(image);
+                }
+                total += 1;
+            }
+        }
+    }
+
+    let page = images::protect_originals(page, viewer);
+
+    Ok(UserImagePage {
+        images: favorite::with_likes(page, db)?,
+        total,
+        offset,
+        limit,
+    })
+}
+
+/// Removes a deleted user's profile.
+pub fn delete_profile(username: &str, db: &Database) -> anyhow::Result<()> {
+    db.profiles.remove(username.as_bytes())?;
+
+    Ok(())
+}
+
+/// Looks up the stored username for a user who isn't suspended.
+fn visible_username(username: &str, db: &Database) -> anyhow::Result<String> {
+    match find_user(username, db)? {
+        Some(user) if !user::is_suspended(&user.username, db)? => Ok(user.username),
+        _ => Err(ProfileError::UnknownUser)?,
+    }
+}
+
+/// Gets a user's profile, or an empty one if they registered before profiles existed.
+fn stored_profile(username: &str, db: &Database) -> anyhow::Result<Profile> {
+    Ok(db
+        .profiles
+        .get(username.as_bytes())?
+        .unwrap_or_else(|| Profile {
+            username: username.to_string(),
+            display_name: None,
+            bio: String::new(),
+            avatar_image_id: None,
+            created: None,
+        }))
+}
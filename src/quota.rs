@@ -0,0 +1,154 @@
+//! How much each user has uploaded, and the limits on it.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{config::QuotaLimits, consts, images::Image, Database};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    /// Bytes of original files stored.
+    pub bytes: u64,
+    pub images: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum QuotaError {
+    #[error("This upload would take you over your storage quota of {max_bytes} bytes")]
+    StorageExceeded { max_bytes: u64 },
+    #[error("You have reached your limit of {max_images} images")]
+    ImageLimitReached { max_images: u64 },
+}
+
+/// The limits that apply to a user.
+pub fn limits(username: &str) -> QuotaLimits {
+    let defaults = consts::QUOTAS.default_limits;
+
+    match consts::QUOTAS.users.get(username) {
+        Some(limits) => QuotaLimits {
+            max_bytes: limits.max_bytes.or(defaults.max_bytes),
+            max_images: limits.max_images.or(defaults.max_images),
+        },
+        None => defaults,
+    }
+}
+
+pub fn usage(username: &str, db: &Database) -> anyhow::Result<Usage> {
+    Ok(db.usage.get(username.as_bytes())?.unwrap_or_default())
+}
+
+/// Checks whether a user could upload one more image of the given size.
+pub fn check_quota(username: &str, bytes: u64, db: &Database) -> anyhow::Result<()> {
+    Ok(check_limits(
+        &usage(username, db)?,
+        bytes,
+        &limits(username),
+    )?)
+}
+
+/// Counts a new image towards its owner's usage, failing without counting it if that would go over their quota.
+pub fn record_upload(image: &Image, db: &Database) -> anyhow::Result<Usage> {
+    let limits = limits(image.owner());
+
+    update_usage(image.owner(), db, move |usage| {
+        check_limits(usage, image.size(), &limits)?;
+
+        usage.bytes = usage.bytes.saturating_add(image.size());
+        usage.images += 1;
+        Ok(())
+    })
+}
+
+/// Stops counting a deleted image towards its owner's usage.
+pub fn record_deletion(image: &Image, db: &Database) -> anyhow::Result<Usage> {
+    update_usage(image.owner(), db, move |usage| {
+        usage.bytes = usage.bytes.saturating_sub(image.size());
+        usage.images = usage.images.saturating_sub(1);
+        Ok(())
+    })
+}
+
+/// Moves an image's usage from one user to another.  Reassigned images count towards the new owner even if they go
+/// over their quota.
+pub fn record_reassignment(image: &Image, to: &str, db: &Database) -> anyhow::Result<()> {
+    record_deletion(image, db)?;
+    update_usage(to, db, move |usage| {
+        usage.bytes = usage.bytes.saturating_add(image.size());
+        usage.images += 1;
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// Removes a deleted user's usage.
+pub fn delete_usage(username: &str, db: &Database) -> anyhow::Result<()> {
+    db.usage.remove(username.as_bytes())?;
+
+    Ok(())
+}
+
+/// Counts up the usage of images uploaded before usage was tracked.
+pub fn count_existing_usage(db: &Database) -> anyhow::Result<()> {
+    if db.usage.iter().next().is_some() {
+        return Ok(());
+    }
+
+    for entry in db.images.iter() {
+        let (_, image) = entry?;
+        update_usage(image.owner(), db, |usage| {
+            usage.bytes = usage.bytes.saturating_add(image.size());
+            usage.images += 1;
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Checks whether one more image of the given size fits within the limits.
+pub fn check_limits(usage: &Usage, bytes: u64, limits: &QuotaLimits) -> Result<(), QuotaError> {
+    if let Some(max_images) = limits.max_images {
+        if usage.images >= max_images {
+            return Err(QuotaError::ImageLimitReached { max_images });
+        }
+    }
+    if let Some(max_bytes) = limits.max_bytes {
+        if usage.bytes.saturating_add(bytes) > max_bytes {
+            return Err(QuotaError::StorageExceeded { max_bytes });
+        }
+    }
+
+    Ok(())
+}
+
+/// Changes a user's usage in a transaction, so concurrent uploads can't both squeeze under the quota.  Nothing is
+/// saved if `update` returns an error.
+fn update_usage<F>(username: &str, db: &Database, update: F) -> anyhow::Result<Usage>
+where
+    F: Fn(&mut Usage) -> Result<(), QuotaError>,
+{
+    let key = username.as_bytes().to_vec();
+
+    let usage = db
+        .usage
+        .transaction(move |tx_db| {
+            let mut usage = match tx_db.get(&key)? {
+                Ok(Some(usage)) => usage,
+                _ => Usage::default(),
+            };
+
+            if let Err(err) = update(&mut usage) {
+                return Ok(Err(anyhow::Error::from(err)));
+            }
+
+            match tx_db.insert(key.clone(), usage)? {
+                Ok(_) => Ok(Ok(usage)),
+                Err(err) => Ok(Err(anyhow::Error::from(err))),
+            }
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    Ok(usage)
+}
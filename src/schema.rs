@@ -0,0 +1,33 @@
+//! The layout of stored records.  Bincode records don't say which fields they have, so a record stored before a field
+//! was added can't be read as the current type.  Instead, the database records which layout it is in, and older
+//! records are upgraded at startup, before anything reads them.
+
+use std::convert::TryInto;
+
+use crate::{images, user, Database};
+
+/// Bumped whenever a step is added to [`upgrade`].
+const SCHEMA_VERSION: u64 = 2;
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// Upgrades records stored by older versions of foto to the current layout.
+pub fn upgrade(sled: &sled_extensions::Db, db: &Database) -> anyhow::Result<()> {
+    let version = match sled.get(SCHEMA_VERSION_KEY)? {
+        Some(bytes) => u64::from_be_bytes(bytes.as_ref().try_into()?),
+        None => 0,
+    };
+
+    if version < 1 {
+        user::upgrade_v0_users(sled, db)?;
+    }
+
+    if version < 2 {
+        images::upgrade_v0_images(sled, db)?;
+    }
+
+    if version < SCHEMA_VERSION {
+        sled.insert(SCHEMA_VERSION_KEY, &SCHEMA_VERSION.to_be_bytes()[..])?;
+    }
+
+    Ok(())
+}
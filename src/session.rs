@@ -0,0 +1,160 @@
+//! Records each token issued to a user as a session, so users can see where they are logged in and sign out remotely.
+
+use std::net::IpAddr;
+
+use chrono::Utc;
+use nanoid::nanoid;
+use rocket::http::hyper::header::USER_AGENT;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{throttle, Database};
+
+/// Longer user agents are cut off, so clients can't fill up the database.
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// The client a request came from, as far as we can tell.
+#[derive(Debug, Clone, Default)]
+pub struct Device {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Device {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Device {
+            ip: throttle::client_ip(req),
+            user_agent: req
+                .headers()
+                .get_one(USER_AGENT.as_str())
+                .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LEN).collect()),
+        })
+    }
+}
+
+/// A token issued to a user.  The token is only accepted while its session exists.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    /// Unix timestamp.
+    pub created: i64,
+    /// Unix timestamp.
+    pub expires: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("Could not find session")]
+    UnknownSession,
+}
+
+/// Separates the username from the session ID in keys.  Usernames can't contain it, so one user's keys never overlap
+/// another's.
+const KEY_SEPARATOR: char = '\0';
+
+fn session_key(username: &str, id: &str) -> String {
+    format!("{}{}{}", username, KEY_SEPARATOR, id)
+}
+
+/// Records a new session for a user, returning its ID.
+pub fn create_session(
+    username: &str,
+    expires: i64,
+    device: &Device,
+    db: &Database,
+) -> anyhow::Result<String> {
+    remove_expired_sessions(username, db)?;
+
+    let session = Session {
+        id: nanoid!(),
+        username: username.to_string(),
+        created: Utc::now().timestamp(),
+        expires,
+        user_agent: device.user_agent.clone(),
+        ip: device.ip.map(|ip| ip.to_string()),
+    };
+
+    db.sessions.insert(
+        session_key(username, &session.id).as_bytes(),
+        session.clone(),
+    )?;
+
+    Ok(session.id)
+}
+
+/// Whether a session exists and belongs to the given user.
+pub fn is_active(id: &str, username: &str, db: &Database) -> anyhow::Result<bool> {
+    Ok(db
+        .sessions
+        .contains_key(session_key(username, id).as_bytes())?)
+}
+
+/// Lists a user's unexpired sessions, newest first.
+pub fn list_sessions(username: &str, db: &Database) -> anyhow::Result<Vec<Session>> {
+    let now = Utc::now().timestamp();
+    let mut sessions = Vec::new();
+
+    for entry in db
+        .sessions
+        .scan_prefix(session_key(username, "").as_bytes())
+    {
+        let (_, session) = entry?;
+        if session.expires > now {
+            sessions.push(session);
+        }
+    }
+
+    sessions.sort_by(|a, b| b.created.cmp(&a.created));
+
+    Ok(sessions)
+}
+
+/// Revokes one of a user's sessions.
+pub fn revoke_session(id: &str, username: &str, db: &Database) -> anyhow::Result<()> {
+    if db
+        .sessions
+        .remove(session_key(username, id).as_bytes())?
+        .is_none()
+    {
+        Err(SessionError::UnknownSession)?
+    }
+
+    Ok(())
+}
+
+/// Revokes all of a user's sessions.
+pub fn revoke_all_sessions(username: &str, db: &Database) -> anyhow::Result<()> {
+    for entry in db
+        .sessions
+        .scan_prefix(session_key(username, "").as_bytes())
+    {
+        let (key, _) = entry?;
+        db.sessions.remove(key)?;
+    }
+
+    Ok(())
+}
+
+fn remove_expired_sessions(username: &str, db: &Database) -> anyhow::Result<()> {
+    let now = Utc::now().timestamp();
+
+    for entry in db
+        .sessions
+        .scan_prefix(session_key(username, "").as_bytes())
+    {
+        let (key, session) = entry?;
+        if session.expires <= now {
+            db.sessions.remove(key)?;
+        }
+    }
+
+    Ok(())
+}
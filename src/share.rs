@@ -0,0 +1,243 @@
+//! Expiring share links, which let anyone holding the link see an image regardless of its visibility.
+//!
+//! Links are signed, so forged or altered ones are rejected without touching the database.  Each link also has a
+//! record, which counts downloads and is removed when the owner revokes the link.
+
+use chrono::Utc;
+use nanoid::nanoid;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    consts,
+    images::{self, Image},
+    Database,
+};
+
+const DEFAULT_EXPIRY_MINUTES: i64 = 24 * 60;
+const MAX_EXPIRY_MINUTES: i64 = 30 * 24 * 60;
+/// Separates the image ID from the share ID in keys, so an image's links can be found by prefix.
+const KEY_SEPARATOR: char = '\0';
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareRequest {
+    expires_in_minutes: Option<i64>,
+    /// If set, the link stops working after this many downloads.
+    max_downloads: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Share {
+    pub id: String,
+    pub image_id: String,
+    pub owner: String,
+    /// Unix timestamp.
+    pub expires: i64,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+}
+
+#[derive(Error, Debug)]
+pub enum ShareError {
+    #[error("Could not find image")]
+    UnknownImage,
+    #[error("Could not find share link")]
+    UnknownShare,
+    #[error("Invalid, expired or revoked share link")]
+    InvalidLink,
+}
+
+/// Creates a share link for one of the owner's images, returning the [`Share`] and the path of the link.
+pub fn create_share(
+    image_id: &str,
+    owner: &str,
+    request: ShareRequest,
+    db: &Database,
+) -> anyhow::Result<(Share, String)> {
+    match db.images.get(image_id.as_bytes())? {
+        Some(image) if image.is_owned_by(owner) => {}
+        _ => Err(ShareError::UnknownImage)?,
+    }
+
+    remove_expired_shares(db)?;
+
+    let minutes = request
+        .expires_in_minutes
+        .unwrap_or(DEFAULT_EXPIRY_MINUTES)
+        .max(1)
+        .min(MAX_EXPIRY_MINUTES);
+    let share = Share {
+        id: nanoid!(),
+        image_id: image_id.to_string(),
+        owner: owner.to_string(),
+        expires: Utc::now().timestamp() + minutes * 60,
+        max_downloads: request.max_downloads,
+        downloads: 0,
+    };
+
+    let key = share_key(&share.image_id, &share.id);
+    db.shares.insert(key.as_bytes(), share.clone())?;
+    db.share_expiries
+        .insert(expiry_key(share.expires, &key), key)?;
+
+    let path = format!(
+        "/api/0/share/{}?image={}&expires={}&signature={}",
+        share.id,
+        share.image_id,
+        share.expires,
+        sign(&share.id, &share.image_id, share.expires)
+    );
+
+    Ok((share, path))
+}
+
+/// Checks a share link and counts a download, returning the shared image.
+pub async fn open_share(
+    id: &str,
+    image_id: &str,
+    expires: i64,
+    signature: &str,
+    db: &Database,
+) -> anyhow::Result<Image> {
+    // Check the signature and expiry first, so bad links never reach the database.
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ShareError::InvalidLink)?;
+    hmac::verify(
+        &consts::SHARE_LINK_KEY,
+        signed_message(id, image_id, expires).as_bytes(),
+        &signature,
+    )
+    .map_err(|_| ShareError::InvalidLink)?;
+
+    if expires <= Utc::now().timestamp() {
+        Err(ShareError::InvalidLink)?
+    }
+
+    // Suspended owners' images are hidden here the same as in search, and deleted owners' images shouldn't be left.
+    let image = db
+        .images
+        .get(image_id.as_bytes())?
+        .ok_or(ShareError::InvalidLink)?;
+    let image = images::hide_suspended_owners(vec![image], db)?
+        .pop()
+        .ok_or(ShareError::InvalidLink)?;
+    if !db.users.contains_key(image.owner().as_bytes())? {
+        Err(ShareError::InvalidLink)?
+    }
+
+    // In a transaction, so concurrent downloads can't go over the limit.
+    let key = share_key(image_id, id).as_bytes().to_vec();
+    let counted = db
+        .shares
+        .transaction(move |tx_db| {
+            let result = match tx_db.get(&key)? {
+                Ok(Some(mut share))
+                    if share
+                        .max_downloads
+                        .map_or(true, |max_downloads| share.downloads < max_downloads) =>
+                {
+                    share.downloads += 1;
+                    tx_db.insert(key.clone(), share)?.map(|_| true)
+                }
+                Ok(_) => Ok(false),
+                Err(err) => Err(err),
+            };
+
+            Ok(result)
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    if !counted {
+        Err(ShareError::InvalidLink)?
+    }
+
+    // Links don't identify who opened them, so images for sale are only shown as previews, and where a photo was
+    // taken is left out.  Anything else can be seen by whoever holds the link, private images included.
+    if image.listing().price.is_some() {
+        Ok(images::protect_original(image, None))
+    } else {
+        images::with_original_url(image.without_location()).await
+    }
+}
+
+/// Lists the share links for one of the owner's images.
+pub fn list_shares(image_id: &str, owner: &str, db: &Database) -> anyhow::Result<Vec<Share>> {
+    let mut shares = vec![];
+
+    for entry in db.shares.scan_prefix(share_key(image_id, "").as_bytes()) {
+        let (_, share) = entry?;
+        if share.owner == owner {
+            shares.push(share);
+        }
+    }
+
+    Ok(shares)
+}
+
+/// Revokes one of the owner's share links.
+pub fn revoke_share(image_id: &str, id: &str, owner: &str, db: &Database) -> anyhow::Result<()> {
+    let key = share_key(image_id, id);
+    match db.shares.get(key.as_bytes())? {
+        Some(share) if share.owner == owner => remove_share(&key, &share, db),
+        _ => Err(ShareError::UnknownShare)?,
+    }
+}
+
+/// Revokes all share links for the images a user owns.
+pub fn delete_user_shares(username: &str, db: &Database) -> anyhow::Result<()> {
+    for image_id in images::user_image_ids(username, db)? {
+        for entry in db.shares.scan_prefix(share_key(&image_id, "").as_bytes()) {
+            let (key, share) = entry?;
+            remove_share(std::str::from_utf8(&key)?, &share, db)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn share_key(image_id: &str, id: &str) -> String {
+    format!("{}{}{}", image_id, KEY_SEPARATOR, id)
+}
+
+/// Orders the expiry index by expiry time.  Expiry times are never negative, so their big-endian bytes sort in order.
+fn expiry_key(expires: i64, key: &str) -> Vec<u8> {
+    let mut expiry_key = expires.to_be_bytes().to_vec();
+    expiry_key.extend(key.as_bytes());
+    expiry_key
+}
+
+fn remove_share(key: &str, share: &Share, db: &Database) -> anyhow::Result<()> {
+    db.shares.remove(key.as_bytes())?;
+    db.share_expiries.remove(expiry_key(share.expires, key))?;
+
+    Ok(())
+}
+
+fn signed_message(id: &str, image_id: &str, expires: i64) -> String {
+    format!("{}.{}.{}", id, image_id, expires)
+}
+
+fn sign(id: &str, image_id: &str, expires: i64) -> String {
+    base64::encode_config(
+        hmac::sign(
+            &consts::SHARE_LINK_KEY,
+            signed_message(id, image_id, expires).as_bytes(),
+        ),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+fn remove_expired_shares(db: &Database) -> anyhow::Result<()> {
+    let now = Utc::now().timestamp();
+
+    for entry in db.share_expiries.range(..expiry_key(now + 1, "")) {
+        let (expiry_key, key) = entry?;
+        db.shares.remove(key.as_bytes())?;
+        db.share_expiries.remove(expiry_key)?;
+    }
+
+    Ok(())
+}
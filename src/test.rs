@@ -1,7 +1,9 @@
-use crate::rocket_from_db;
+use std::net::SocketAddr;
+
+use crate::{rocket_from_db, rocket_from_db_with};
 
 use rocket::{
-    http::{ContentType, Status},
+    http::{ContentType, Header, Status},
     local::blocking::Client,
 };
 
@@ -81,7 +83,7 @@ fn test_account_creation() {
         .body(format!(
             r#"{{ 
                 "username": "test_user_{}",
-                "password": "123456789"
+                "password": "goose_pictures_1"
             }}"#,
             rand_string
         ))
@@ -106,7 +108,7 @@ fn test_dupe_account() {
         .body(format!(
             r#"{{ 
                 "username": "test_user_{}",
-                "password": "123456789"
+                "password": "goose_pictures_1"
             }}"#,
             rand_string
         ))
@@ -120,7 +122,7 @@ fn test_dupe_account() {
         .body(format!(
             r#"{{ 
                 "username": "test_user_{}",
-                "password": "987654321"
+                "password": "goose_pictures_2"
             }}"#,
             rand_string
         ))
@@ -145,7 +147,7 @@ fn login() {
         .body(format!(
             r#"{{ 
                 "username": "test_user_{}",
-                "password": "123456789"
+                "password": "goose_pictures_1"
             }}"#,
             rand_string
         ))
@@ -159,11 +161,2588 @@ fn login() {
         .body(format!(
             r#"{{ 
             "username": "test_user_{}",
-            "password": "123456789"
+            "password": "goose_pictures_1"
+        }}"#,
+            rand_string
+        ))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn login_throttled() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+
+    // Use a made-up IP so other tests logging in at the same time aren't throttled.
+    let client_ip = SocketAddr::from((
+        [
+            10,
+            thread_rng().gen::<u8>(),
+            thread_rng().gen::<u8>(),
+            thread_rng().gen::<u8>(),
+        ],
+        8000,
+    ));
+
+    create_or_do_nothing(
+        &client,
+        &format!("test_user_{}", rand_string),
+        "goose_pictures_1",
+    );
+
+    let mut throttled = false;
+    for attempt in 0..8 {
+        let response = client
+            .post("/api/0/login")
+            .header(ContentType::JSON)
+            .remote(client_ip)
+            .body(format!(
+                r#"{{ 
+                "username": "test_user_{}",
+                "password": "wrong_password"
+            }}"#,
+                rand_string
+            ))
+            .dispatch();
+
+        if attempt == 0 {
+            assert_eq!(response.status(), Status::BadRequest);
+        }
+
+        if response.status() == Status::TooManyRequests {
+            assert!(response.headers().get_one("Retry-After").is_some());
+            throttled = true;
+            break;
+        }
+    }
+
+    assert!(throttled);
+
+    // Without a trusted proxy, the header can't be used to get a fresh IP.
+    let response = client
+        .post("/api/0/login")
+        .header(ContentType::JSON)
+        .header(Header::new("X-Real-IP", "10.0.0.1"))
+        .remote(client_ip)
+        .body(format!(
+            r#"{{
+            "username": "test_user_other_{}",
+            "password": "wrong_password"
         }}"#,
             rand_string
         ))
         .dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+}
+
+#[test]
+fn change_password_revokes_tokens() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let old_token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+
+    let change_password = |token: &str| {
+        client
+            .post("/api/0/account/password")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .body(
+                r#"{ 
+                "currentPassword": "goose_pictures_1",
+                "newPassword": "goose_pictures_2"
+            }"#,
+            )
+            .dispatch()
+            .status()
+    };
+
+    assert_eq!(change_password(&old_token), Status::Ok);
+    assert_eq!(change_password(&old_token), Status::Unauthorized);
+
+    assert!(login_get_json(&client, &username, "goose_pictures_2")
+        .token
+        .is_some());
+}
+
+#[test]
+fn account_deletion() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+    let image_id = format!("deleted_account_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+    let auth_header = Header::new("Authorization", format!("Bearer {}", token));
+    let image: crate::images::Image = serde_json::from_str(&format!(
+        r#"{{
+            "id": "{}",
+            "imageUrl": "",
+            "username": "{}",
+            "title": "Goose",
+            "tags": [],
+            "description": "",
+            "imageType": "image/jpeg",
+            "width": 1,
+            "height": 1,
+            "datetime": 0,
+            "visibility": "public"
+        }}"#,
+        image_id, username
+    ))
+    .unwrap();
+    db.images.insert(image_id.as_bytes(), image).unwrap();
+
+    let delete_account = |password: &str| {
+        client
+            .delete("/api/0/account")
+            .header(ContentType::JSON)
+            .header(auth_header.clone())
+            .body(format!(
+                r#"{{
+                "password": "{}",
+                "deleteImages": true
+            }}"#,
+                password
+            ))
+            .dispatch()
+            .status()
+    };
+
+    assert_eq!(delete_account("wrong_password"), Status::BadRequest);
+    assert!(db.users.get(&username).unwrap().is_some());
+    assert!(login_get_json(&client, &username, "wrong_password")
+        .token
+        .is_none());
+
+    assert_eq!(delete_account("goose_pictures_1"), Status::Ok);
+
+    assert!(db.users.get(&username).unwrap().is_none());
+    assert!(db
+        .login_attempts
+        .get(format!("user:{}", username).as_bytes())
+        .unwrap()
+        .is_none());
+    assert!(db.images.get(image_id.as_bytes()).unwrap().is_none());
+    assert!(db
+        .image_hash_keys
+        .get(image_id.as_bytes())
+        .unwrap()
+        .is_none());
+    assert!(crate::images::user_image_ids(&username, db)
+        .unwrap()
+        .is_empty());
+
+    // The old token is no longer accepted.
+    assert_eq!(delete_account("goose_pictures_1"), Status::Unauthorized);
+    assert!(login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .is_none());
+}
+
+#[test]
+fn schema_upgrade() {
+    use sled_extensions::DbExt;
+
+    /// Users and images as the first version of foto stored them.
+    #[derive(Serialize)]
+    struct UserV0 {
+        username: String,
+        password: String,
+    }
+    #[derive(Serialize)]
+    struct ImageV0 {
+        id: String,
+        image_url: String,
+        username: String,
+        title: String,
+        tags: Vec<String>,
+        description: String,
+        image_type: String,
+        width: u32,
+        height: u32,
+        datetime: i64,
+    }
+
+    // A database of its own, so no other test reads the old records before they're upgraded.
+    let sled = sled_extensions::Config::default()
+        .temporary(true)
+        .open()
+        .expect("Failed to open sled db");
+    sled.open_bincode_tree::<UserV0>("users")
+        .unwrap()
+        .insert(
+            b"goose",
+            UserV0 {
+                username: "goose".to_string(),
+                password: "".to_string(),
+            },
+        )
+        .unwrap();
+    sled.open_bincode_tree::<ImageV0>("images")
+        .unwrap()
+        .insert(
+            b"old_goose",
+            ImageV0 {
+                id: "old_goose".to_string(),
+                image_url: "".to_string(),
+                username: "goose".to_string(),
+                title: "Goose".to_string(),
+                tags: vec![],
+                description: "".to_string(),
+                image_type: "image/jpeg".to_string(),
+                width: 1,
+                height: 1,
+                datetime: 0,
+            },
+        )
+        .unwrap();
+
+    let client = Client::tracked(rocket_from_db(&sled)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let user = db.users.get(b"goose").unwrap().unwrap();
+    assert!(!user.token_key.is_empty());
+    assert!(user.suspension.is_none());
+
+    let image = db.images.get(b"old_goose").unwrap().unwrap();
+    assert!(image.is_viewable_by(None));
+}
+
+#[derive(Deserialize)]
+struct FieldErrorResponse {
+    pub field: String,
+}
+
+#[test]
+fn registration_policy() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+
+    let register = |username: &str, password: &str| {
+        client
+            .post("/api/0/register")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ 
+                "username": "{}",
+                "password": "{}"
+            }}"#,
+                username, password
+            ))
+            .dispatch()
+    };
+
+    let response = register(&format!("test_user_{}", rand_string), "password");
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: FieldErrorResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body.field, "password");
+
+    let response = register("a b", "goose_pictures_1");
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: FieldErrorResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body.field, "username");
+
+    let response = register(&format!("test_user_{}", rand_string), "goose_pictures_1");
+    assert_eq!(response.status(), Status::Ok);
+
+    // Usernames differing only in case are the same user.
+    let response = register(
+        &format!("TEST_USER_{}", rand_string.to_uppercase()),
+        "goose_pictures_1",
+    );
+    assert_eq!(response.status(), Status::BadRequest);
+
+    // Users who registered before usernames were indexed are indexed on startup.
+    let db = client.rocket().state::<crate::Database>().unwrap();
+    let legacy_username = format!("legacy_user_{}", rand_string);
+    db.users
+        .insert(
+            legacy_username.as_bytes(),
+            crate::user::User {
+                username: legacy_username.clone(),
+                password: "!".to_string(),
+                token_key: "legacy".to_string(),
+                suspension: None,
+            },
+        )
+        .unwrap();
+    crate::user::index_existing_usernames(db).unwrap();
+
+    let response = register(&legacy_username.to_uppercase(), "goose_pictures_1");
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn jwt_key_ids() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+
+    let header = jsonwebtoken::decode_header(&token).unwrap();
+    assert_eq!(
+        header.kid.as_deref(),
+        Some(crate::consts::JWT_SIGNING_KEY_ID.as_str())
+    );
+
+    // The same claims, claiming to be signed by a key we don't know, should be rejected.
+    let claims: serde_json::Value = jsonwebtoken::dangerous_insecure_decode(&token)
+        .unwrap()
+        .claims;
+    let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS512);
+    header.kid = Some("unknown_key".to_string());
+    let forged_token = jsonwebtoken::encode(
+        &header,
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(b"unknown_secret"),
+    )
+    .unwrap();
+
+    let response = client
+        .post("/api/0/account/password")
+        .header(ContentType::JSON)
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", forged_token),
+        ))
+        .body(
+            r#"{ 
+            "currentPassword": "goose_pictures_1",
+            "newPassword": "goose_pictures_2"
+        }"#,
+        )
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn jwks() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+
+    let response = client.get("/.well-known/jwks.json").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    for key in body["keys"].as_array().unwrap() {
+        assert_eq!(key["kty"], "RSA");
+    }
+
+    // Only the RS256 key is published, with the modulus and exponent of the private key it came from.
+    let keys = vec![
+        crate::jwt_keys::JwtKey::rsa("test_rsa", include_str!("test_data/rsa_key.pem")).unwrap(),
+        crate::jwt_keys::JwtKey::hmac("test_hmac", b"goose secret".to_vec()),
+    ];
+    let body: serde_json::Value =
+        serde_json::from_str(&crate::well_known::key_set(&keys).to_string()).unwrap();
+    let published = body["keys"].as_array().unwrap();
+
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0]["kid"], "test_rsa");
+    assert_eq!(published[0]["alg"], "RS256");
+    assert_eq!(published[0]["e"], "AQAB");
+    assert_eq!(
+        published[0]["n"],
+        "t6Mb_GIDr4-ky0Ej_lHCNbMxx5PrIfyBJlWkYmgPUyw9ey5RCYxvET4czXy1i4pRw0jZR96h_h7ntw320cEpBfm1kPqpf7-YYomXjXvlJxyb\
+         YVVd_A020MPlorU-d2P8FVB9DfAmD47T9-8QY5YnHFkED_-GuqGXJjKNNlmw8BsUy7eEvd_QLqhOPQmeU4U0CrhdxfXsSMkX18a8UkRZ7Dnv\
+         vIuuUGOdgPkhAjy7g-sW3erPdReDvkr_-0L6A7_ml5ZYSb6SFHKsQS59jDCOUGv1dWkvRZQEKyuUKklvTk-8DKZE4Ywqh0u5rHeuhzZd231R\
+         5bOB1UtuIMXbY2LsqQ"
+    );
+}
+
+#[test]
+fn totp_rfc_6238() {
+    // SHA-1 test vectors from RFC 6238, truncated to 6 digits.
+    let secret = b"12345678901234567890";
+    assert_eq!(crate::two_factor::totp_code(secret, 59 / 30), "287082");
+    assert_eq!(
+        crate::two_factor::totp_code(secret, 1111111109 / 30),
+        "081804"
+    );
+    assert_eq!(
+        crate::two_factor::totp_code(secret, 1234567890 / 30),
+        "005924"
+    );
+}
+
+#[derive(Deserialize)]
+struct TwoFactorConfirmResponse {
+    #[serde(rename = "recoveryCodes")]
+    pub recovery_codes: Vec<String>,
+}
+
+#[test]
+fn two_factor_login() {
+    use sled_extensions::DbExt;
+
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+    let auth_header = Header::new("Authorization", format!("Bearer {}", token));
+
+    let response = client
+        .post("/api/0/account/2fa/enroll")
+        .header(auth_header.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let secret = DATABASE
+        .open_bincode_tree::<crate::two_factor::TwoFactor>("two_factor")
+        .unwrap()
+        .get(username.as_bytes())
+        .unwrap()
+        .unwrap()
+        .secret;
+    let code = crate::two_factor::totp_code(&secret, chrono::Utc::now().timestamp() as u64 / 30);
+
+    let response = client
+        .post("/api/0/account/2fa/confirm")
+        .header(ContentType::JSON)
+        .header(auth_header)
+        .body(format!(r#"{{ "code": "{}" }}"#, code))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let recovery_codes =
+        serde_json::from_str::<TwoFactorConfirmResponse>(&response.into_string().unwrap())
+            .unwrap()
+            .recovery_codes;
+
+    // Logging in now gives a challenge instead of a token.
+    let response = client
+        .post("/api/0/login")
+        .header(ContentType::JSON)
+        .body(format!(
+            r#"{{ "username": "{}", "password": "goose_pictures_1" }}"#,
+            username
+        ))
+        .dispatch();
+    let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert!(body["token"].is_null());
+    let challenge = body["challenge"].as_str().unwrap().to_string();
+
+    let exchange = |code: &str| {
+        client
+            .post("/api/0/login/2fa")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "challenge": "{}", "code": "{}" }}"#,
+                challenge, code
+            ))
+            .dispatch()
+            .status()
+    };
+
+    // The challenge itself can't be used as a token.
+    let response = client
+        .post("/api/0/account/2fa/enroll")
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", challenge),
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    // Recovery codes only work once.
+    assert_eq!(exchange(&recovery_codes[0]), Status::Ok);
+    assert_eq!(exchange(&recovery_codes[0]), Status::BadRequest);
+}
+
+/// Starts a minimal OpenID Connect provider on a random port, returning its issuer URL.  Rather than having users
+/// log in, its token endpoint accepts codes of the form `subject.nonce.code_challenge`.
+fn start_mock_oidc_provider() -> String {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let key =
+        crate::jwt_keys::JwtKey::rsa("mock_key", include_str!("test_data/rsa_key.pem")).unwrap();
+
+    let provider_issuer = issuer.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            // Read the headers, then however much body they say there is.
+            let mut request = vec![];
+            let mut buffer = [0u8; 4096];
+            let header_end = loop {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+            let content_length = head
+                .lines()
+                .find_map(|line| {
+                    let line = line.to_lowercase();
+                    line.strip_prefix("content-length:")
+                        .map(|length| length.trim().parse::<usize>().unwrap())
+                })
+                .unwrap_or(0);
+            while request.len() < header_end + content_length {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let body = String::from_utf8_lossy(&request[header_end..]).to_string();
+
+            let request_line = head.lines().next().unwrap_or_default().to_string();
+            let (status, response) = if request_line
+                .starts_with("GET /.well-known/openid-configuration")
+            {
+                (
+                    "200 OK",
+                    serde_json::json!({
+                        "issuer": provider_issuer,
+                        "authorization_endpoint": format!("{}/authorize", provider_issuer),
+                        "token_endpoint": format!("{}/token", provider_issuer),
+                        "jwks_uri": format!("{}/jwks", provider_issuer),
+                    })
+                    .to_string(),
+                )
+            } else if request_line.starts_with("GET /jwks") {
+                (
+                    "200 OK",
+                    serde_json::json!({ "keys": [key.public_jwk()] }).to_string(),
+                )
+            } else if request_line.starts_with("POST /token") {
+                let form: std::collections::HashMap<String, String> =
+                    reqwest::Url::parse(&format!("http://localhost/?{}", body))
+                        .unwrap()
+                        .query_pairs()
+                        .into_owned()
+                        .collect();
+                let code_parts: Vec<&str> = form["code"].splitn(3, '.').collect();
+                let verifier_challenge = base64::encode_config(
+                    ring::digest::digest(&ring::digest::SHA256, form["code_verifier"].as_bytes()),
+                    base64::URL_SAFE_NO_PAD,
+                );
+
+                if code_parts.len() != 3 || code_parts[2] != verifier_challenge {
+                    (
+                        "400 Bad Request",
+                        r#"{"error": "invalid_grant"}"#.to_string(),
+                    )
+                } else {
+                    let now = chrono::Utc::now().timestamp();
+                    let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+                    header.kid = Some("mock_key".to_string());
+                    let id_token = jsonwebtoken::encode(
+                        &header,
+                        &serde_json::json!({
+                            "iss": provider_issuer,
+                            "sub": code_parts[0],
+                            "aud": form["client_id"],
+                            "iat": now,
+                            "exp": now + 300,
+                            "nonce": code_parts[1],
+                            "preferred_username": code_parts[0],
+                        }),
+                        key.encoding_key(),
+                    )
+                    .unwrap();
+
+                    (
+                        "200 OK",
+                        serde_json::json!({ "id_token": id_token, "token_type": "Bearer" })
+                            .to_string(),
+                    )
+                }
+            } else {
+                ("404 Not Found", "{}".to_string())
+            };
+
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            );
+        }
+    });
+
+    issuer
+}
+
+#[test]
+fn oidc_login() {
+    let oidc_config = crate::config::OidcConfig {
+        issuer: start_mock_oidc_provider(),
+        client_id: "foto_test".to_string(),
+        client_secret: "foto_test_secret".to_string(),
+        redirect_uri: "http://127.0.0.1:8000/api/0/oidc/callback".to_string(),
+        scopes: "openid".to_string(),
+    };
+    let client = Client::tracked(rocket_from_db_with(
+        &DATABASE,
+        Some(oidc_config.clone()),
+        Default::default(),
+    ))
+    .expect("Valid rocket instance...");
+    let other_browser = Client::tracked(rocket_from_db_with(
+        &DATABASE,
+        Some(oidc_config),
+        Default::default(),
+    ))
+    .expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+
+    let start_login = || {
+        let response = client.get("/api/0/oidc/login").dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+
+        let location =
+            reqwest::Url::parse(response.headers().get_one("Location").unwrap()).unwrap();
+        let params: std::collections::HashMap<String, String> =
+            location.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        format!(
+            "/api/0/oidc/callback?code=oidc_{}.{}.{}&state={}",
+            rand_string, params["nonce"], params["code_challenge"], params["state"]
+        )
+    };
+
+    let callback = start_login();
+
+    // A login can't be finished by a browser that didn't start it.
+    let response = other_browser.get(callback.clone()).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = client.get(callback.clone()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: LoginResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert!(body.token.is_some());
+
+    // Each login can only be finished once.
+    let response = client.get(callback).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    // Users with two-factor authentication still have to enter a code.
+    let username = format!("oidc_{}", rand_string);
+    crate::two_factor::enroll(&username, db).unwrap();
+    let secret = db
+        .two_factor
+        .get(username.as_bytes())
+        .unwrap()
+        .unwrap()
+        .secret;
+    let step = (chrono::Utc::now().timestamp() / 30) as u64;
+    crate::two_factor::confirm(&username, &crate::two_factor::totp_code(&secret, step), db)
+        .unwrap();
+
+    let response = client.get(start_login()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert!(body["token"].is_null());
+    assert!(body["challenge"].is_string());
+}
+
+#[test]
+fn session_cookie_with_csrf() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+
+    // Logging in stores the session cookies in the tracked client.
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    login_get_json(&client, &username, "goose_pictures_1");
+
+    let csrf_token = client
+        .cookies()
+        .get(crate::auth::CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .unwrap();
+
+    let response = client.post("/api/0/account/2fa/enroll").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = client
+        .post("/api/0/account/2fa/enroll")
+        .header(Header::new(crate::auth::CSRF_HEADER, "wrong_token"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = client
+        .post("/api/0/account/2fa/enroll")
+        .header(Header::new(crate::auth::CSRF_HEADER, csrf_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client.post("/api/0/logout").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(client.cookies().get(crate::auth::SESSION_COOKIE).is_none());
+}
+
+#[test]
+fn password_reset() {
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+    let admin_username = format!("test_admin_{}", rand_string);
+
+    let client = Client::tracked(rocket_from_db_with(
+        &DATABASE,
+        None,
+        std::iter::once(admin_username.clone()).collect(),
+    ))
+    .expect("Valid rocket instance...");
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let old_token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+    create_or_do_nothing(&client, &admin_username, "goose_pictures_1");
+    let admin_token = login_get_json(&client, &admin_username, "goose_pictures_1")
+        .token
+        .unwrap();
+
+    let create_reset = |token: &str| {
+        client
+            .post("/api/0/admin/password-reset")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .body(format!(r#"{{ "username": "{}" }}"#, username))
+            .dispatch()
+    };
 
+    assert_eq!(create_reset(&old_token).status(), Status::Forbidden);
+
+    let response = create_reset(&admin_token);
     assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    let code = body["code"].as_str().unwrap().to_string();
+    assert!(body["expires"].is_i64());
+
+    let reset = |code: &str| {
+        client
+            .post("/api/0/password/reset")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "code": "{}", "newPassword": "goose_pictures_2" }}"#,
+                code
+            ))
+            .dispatch()
+            .status()
+    };
+
+    assert_eq!(reset("not_a_real_code"), Status::BadRequest);
+    assert_eq!(reset(&code), Status::Ok);
+    assert_eq!(reset(&code), Status::BadRequest);
+
+    let response = client
+        .post("/api/0/account/2fa/enroll")
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", old_token),
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    assert!(login_get_json(&client, &username, "goose_pictures_2")
+        .token
+        .is_some());
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionResponse {
+    pub id: String,
+    pub current: bool,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SessionsResponse {
+    pub sessions: Vec<SessionResponse>,
+}
+
+#[test]
+fn sessions() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let first_token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+
+    let response = client
+        .post("/api/0/login")
+        .header(ContentType::JSON)
+        .header(Header::new("User-Agent", "goose_phone"))
+        .body(format!(
+            r#"{{ "username": "{}", "password": "goose_pictures_1" }}"#,
+            username
+        ))
+        .dispatch();
+    let second_token = serde_json::from_str::<LoginResponse>(&response.into_string().unwrap())
+        .unwrap()
+        .token
+        .unwrap();
+
+    let list = |token: &str| {
+        client
+            .get("/api/0/account/sessions")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch()
+    };
+
+    let response = list(&first_token);
+    assert_eq!(response.status(), Status::Ok);
+    let sessions = serde_json::from_str::<SessionsResponse>(&response.into_string().unwrap())
+        .unwrap()
+        .sessions;
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions.iter().filter(|session| session.current).count(), 1);
+
+    let second_session = sessions
+        .iter()
+        .find(|session| session.user_agent.as_deref() == Some("goose_phone"))
+        .unwrap();
+    assert!(!second_session.current);
+
+    let response = client
+        .delete(format!("/api/0/account/sessions/{}", second_session.id))
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", first_token),
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(list(&second_token).status(), Status::Unauthorized);
+    assert_eq!(list(&first_token).status(), Status::Ok);
+
+    let response = client
+        .delete("/api/0/account/sessions")
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", first_token),
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(list(&first_token).status(), Status::Unauthorized);
+
+    assert!(login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .is_some());
+}
+
+#[test]
+fn invite_only_registration() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+
+    let register = |username: &str, invite_code: &str, mode: crate::config::RegistrationMode| {
+        let credentials = serde_json::from_str(&format!(
+            r#"{{ "username": "{}", "password": "goose_pictures_1", "inviteCode": "{}" }}"#,
+            username, invite_code
+        ))
+        .unwrap();
+
+        crate::user::add_user(
+            credentials,
+            mode,
+            &crate::consts::USER_DATABASE_CONFIG,
+            &crate::consts::ACCOUNT_POLICY,
+            db,
+        )
+    };
+
+    let request = serde_json::from_str(r#"{ "uses": 2 }"#).unwrap();
+    let (code, invite) = crate::invite::create_invite(request, "test_admin", db).unwrap();
+    assert_eq!(invite.uses_remaining, 2);
+
+    let invite_only = crate::config::RegistrationMode::InviteOnly;
+    assert!(register(
+        &format!("test_user_a{}", rand_string),
+        "not_a_code",
+        invite_only
+    )
+    .is_err());
+    // A rejected invite gives the name back.
+    assert!(db
+        .usernames
+        .get(crate::policy::username_key(&format!("test_user_a{}", rand_string)).as_bytes())
+        .unwrap()
+        .is_none());
+    assert!(register(&format!("test_user_b{}", rand_string), &code, invite_only).is_ok());
+    assert!(register(&format!("test_user_c{}", rand_string), &code, invite_only).is_ok());
+    assert!(register(&format!("test_user_d{}", rand_string), &code, invite_only).is_err());
+
+    assert!(register(
+        &format!("test_user_e{}", rand_string),
+        "",
+        crate::config::RegistrationMode::Closed
+    )
+    .is_err());
+
+    // Only admins can create invites over the API.
+    let response = client
+        .post("/api/0/admin/invites")
+        .header(ContentType::JSON)
+        .body("{}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[derive(Deserialize)]
+struct SuspendedResponse {
+    pub reason: String,
+}
+
+#[test]
+fn suspension() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+
+    let list_sessions = || {
+        client
+            .get("/api/0/account/sessions")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch()
+    };
+    assert_eq!(list_sessions().status(), Status::Ok);
+
+    let request = serde_json::from_str(r#"{ "reason": "too many geese" }"#).unwrap();
+    crate::user::suspend_user(&username, request, "test_admin", db).unwrap();
+
+    let response = list_sessions();
+    assert_eq!(response.status(), Status::Forbidden);
+    let body = serde_json::from_str::<SuspendedResponse>(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body.reason, "too many geese");
+
+    let response = client
+        .post("/api/0/login")
+        .header(ContentType::JSON)
+        .body(format!(
+            r#"{{ "username": "{}", "password": "goose_pictures_1" }}"#,
+            username
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    crate::user::reinstate_user(&username, db).unwrap();
+
+    assert_eq!(list_sessions().status(), Status::Ok);
+    assert!(login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .is_some());
+}
+
+#[test]
+fn image_visibility() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let owner = format!("test_user_a{}", rand_string);
+    let other = format!("test_user_b{}", rand_string);
+
+    create_or_do_nothing(&client, &owner, "goose_pictures_1");
+    create_or_do_nothing(&client, &other, "goose_pictures_1");
+    let owner_token = login_get_json(&client, &owner, "goose_pictures_1")
+        .token
+        .unwrap();
+    let other_token = login_get_json(&client, &other, "goose_pictures_1")
+        .token
+        .unwrap();
+
+    for visibility in &["public", "unlisted", "private"] {
+        let image: crate::images::Image = serde_json::from_str(&format!(
+            r#"{{
+                "id": "{}_{}",
+                "imageUrl": "",
+                "username": "{}",
+                "title": "Goose",
+                "tags": [],
+                "description": "",
+                "imageType": "image/jpeg",
+                "width": 1,
+                "height": 1,
+                "datetime": 0,
+                "visibility": "{}"
+            }}"#,
+            visibility, rand_string, owner, visibility
+        ))
+        .unwrap();
+        db.images
+            .insert(format!("{}_{}", visibility, rand_string).as_bytes(), image)
+            .unwrap();
+    }
+
+    let get = |visibility: &str, token: Option<&str>| {
+        let mut request = client.get(format!("/api/0/image/{}_{}", visibility, rand_string));
+        if let Some(token) = token {
+            request = request.header(Header::new("Authorization", format!("Bearer {}", token)));
+        }
+        request.dispatch().status()
+    };
+
+    assert_eq!(get("public", None), Status::Ok);
+    assert_eq!(get("unlisted", None), Status::Ok);
+    assert_eq!(get("private", None), Status::NotFound);
+    assert_eq!(get("private", Some(&other_token)), Status::NotFound);
+    assert_eq!(get("private", Some(&owner_token)), Status::Ok);
+
+    // Uploads with an unknown visibility are rejected, naming the field.
+    let response = client
+        .post("/api/0/upload")
+        .header(Header::new(
+            "Content-Type",
+            "multipart/form-data; boundary=goose",
+        ))
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", owner_token),
+        ))
+        .body("--goose\r\nContent-Disposition: form-data; name=\"visibility\"\r\n\r\nsecret\r\n--goose--\r\n")
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: FieldErrorResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body.field, "visibility");
+}
+
+#[derive(Deserialize)]
+struct ShareCreatedResponse {
+    pub path: String,
+    pub share: ShareResponse,
+}
+
+#[derive(Deserialize)]
+struct ShareResponse {
+    pub id: String,
+}
+
+#[test]
+fn share_links() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+    let image_id = format!("private_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+    let auth_header = Header::new("Authorization", format!("Bearer {}", token));
+
+    let image: crate::images::Image = serde_json::from_str(&format!(
+        r#"{{
+            "id": "{}",
+            "imageUrl": "",
+            "username": "{}",
+            "title": "Goose",
+            "tags": [],
+            "description": "",
+            "imageType": "image/jpeg",
+            "width": 1,
+            "height": 1,
+            "datetime": 0,
+            "visibility": "private"
+        }}"#,
+        image_id, username
+    ))
+    .unwrap();
+    db.images.insert(image_id.as_bytes(), image).unwrap();
+
+    let create_share = |body: &str| {
+        let response = client
+            .post(format!("/api/0/image/{}/shares", image_id))
+            .header(ContentType::JSON)
+            .header(auth_header.clone())
+            .body(body.to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<ShareCreatedResponse>(&response.into_string().unwrap()).unwrap()
+    };
+
+    let limited = create_share(r#"{ "maxDownloads": 1 }"#);
+    assert_eq!(client.get(&limited.path).dispatch().status(), Status::Ok);
+    assert_eq!(
+        client.get(&limited.path).dispatch().status(),
+        Status::NotFound
+    );
+
+    let unlimited = create_share("{}");
+    let tampered = unlimited.path.replace(&image_id, "some_other_image");
+    assert_eq!(client.get(&tampered).dispatch().status(), Status::NotFound);
+    assert_eq!(client.get(&unlimited.path).dispatch().status(), Status::Ok);
+    assert_eq!(client.get(&unlimited.path).dispatch().status(), Status::Ok);
+
+    let response = client
+        .delete(format!(
+            "/api/0/image/{}/shares/{}",
+            image_id, unlimited.share.id
+        ))
+        .header(auth_header.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        client.get(&unlimited.path).dispatch().status(),
+        Status::NotFound
+    );
+
+    // Links stop working while the owner is suspended.
+    let suspended = create_share("{}");
+    let request = serde_json::from_str(r#"{ "reason": "too many geese" }"#).unwrap();
+    crate::user::suspend_user(&username, request, "test_admin", db).unwrap();
+    assert_eq!(
+        client.get(&suspended.path).dispatch().status(),
+        Status::NotFound
+    );
+
+    // Deleting the owner revokes their links.
+    crate::share::delete_user_shares(&username, db).unwrap();
+    assert!(crate::share::list_shares(&image_id, &username, db)
+        .unwrap()
+        .is_empty());
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AlbumResponse {
+    pub id: String,
+    pub cover_image_id: Option<String>,
+    pub image_ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct AlbumMessageResponse {
+    pub album: AlbumResponse,
+}
+
+#[test]
+fn albums() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+    let image_ids: Vec<String> = (0..3)
+        .map(|i| format!("album_image_{}_{}", i, rand_string))
+        .collect();
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+    let auth_header = Header::new("Authorization", format!("Bearer {}", token));
+
+    for image_id in &image_ids {
+        let image: crate::images::Image = serde_json::from_str(&format!(
+            r#"{{
+                "id": "{}",
+                "imageUrl": "",
+                "username": "{}",
+                "title": "Goose",
+                "tags": [],
+                "description": "",
+                "imageType": "image/jpeg",
+                "width": 1,
+                "height": 1,
+                "datetime": 0,
+                "visibility": "public"
+            }}"#,
+            image_id, username
+        ))
+        .unwrap();
+        db.images.insert(image_id.as_bytes(), image).unwrap();
+    }
+
+    let album_request = |request: rocket::local::blocking::LocalRequest, body: String| {
+        let response = request
+            .header(ContentType::JSON)
+            .header(auth_header.clone())
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<AlbumMessageResponse>(&response.into_string().unwrap())
+            .unwrap()
+            .album
+    };
+
+    let album = album_request(
+        client.post("/api/0/albums"),
+        format!(
+            r#"{{ "title": "Geese", "visibility": "private", "coverImageId": "{}" }}"#,
+            image_ids[0]
+        ),
+    );
+    assert_eq!(album.cover_image_id.as_ref(), Some(&image_ids[0]));
+
+    for image_id in &image_ids[1..] {
+        album_request(
+            client.post(format!("/api/0/albums/{}/images", album.id)),
+            format!(r#"{{ "imageId": "{}" }}"#, image_id),
+        );
+    }
+
+    let album = album_request(
+        client.put(format!("/api/0/albums/{}/images", album.id)),
+        format!(
+            r#"{{ "imageIds": ["{}", "{}", "{}"] }}"#,
+            image_ids[2], image_ids[0], image_ids[1]
+        ),
+    );
+    assert_eq!(
+        album.image_ids,
+        vec![
+            image_ids[2].clone(),
+            image_ids[0].clone(),
+            image_ids[1].clone()
+        ]
+    );
+
+    // Private albums are hidden from everyone else.
+    let response = client
+        .get(format!("/api/0/albums/{}", album.id))
+        .header(Header::new("Authorization", "Bearer invalid"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    // Deleting an image removes it from the album, along with the cover.
+    crate::album::remove_image_from_albums(&image_ids[0], db).unwrap();
+    let album = db.albums.get(album.id.as_bytes()).unwrap().unwrap();
+    assert_eq!(album.cover_image_id, None);
+    assert_eq!(
+        album.image_ids,
+        vec![image_ids[2].clone(), image_ids[1].clone()]
+    );
+
+    assert_eq!(crate::album::user_albums(&username, db).unwrap().len(), 1);
+
+    let response = client
+        .delete(format!("/api/0/albums/{}", album.id))
+        .header(auth_header.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(db.albums.get(album.id.as_bytes()).unwrap().is_none());
+    assert!(crate::album::user_albums(&username, db).unwrap().is_empty());
+}
+
+#[test]
+fn marketplace() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let seller = format!("test_seller_{}", rand_string);
+    let buyer = format!("test_buyer_{}", rand_string);
+    let image_id = format!("for_sale_{}", rand_string);
+
+    let auth_header = |username: &str| {
+        create_or_do_nothing(&client, username, "goose_pictures_1");
+        let token = login_get_json(&client, username, "goose_pictures_1")
+            .token
+            .unwrap();
+        Header::new("Authorization", format!("Bearer {}", token))
+    };
+    let seller_header = auth_header(&seller);
+    let buyer_header = auth_header(&buyer);
+
+    let image: crate::images::Image = serde_json::from_str(&format!(
+        r#"{{
+            "id": "{}",
+            "imageUrl": "",
+            "username": "{}",
+            "title": "Goose",
+            "tags": [],
+            "description": "",
+            "imageType": "image/jpeg",
+            "width": 1,
+            "height": 1,
+            "datetime": 0,
+            "visibility": "public"
+        }}"#,
+        image_id, seller
+    ))
+    .unwrap();
+    db.images.insert(image_id.as_bytes(), image).unwrap();
+
+    let list = |body: &'static str| {
+        client
+            .put(format!("/api/0/image/{}/listing", image_id))
+            .header(ContentType::JSON)
+            .header(seller_header.clone())
+            .body(body)
+            .dispatch()
+            .status()
+    };
+
+    // Prices have to fit in a balance.
+    assert_eq!(
+        list(r#"{ "price": 9223372036854775808, "currency": "usd" }"#),
+        Status::BadRequest
+    );
+    assert_eq!(
+        list(r#"{ "price": 500, "currency": "usd", "inventory": 1 }"#),
+        Status::Ok
+    );
+
+    let original = || {
+        client
+            .get(format!("/api/0/image/{}/original", image_id))
+            .header(buyer_header.clone())
+            .dispatch()
+            .status()
+    };
+    let purchase = || {
+        client
+            .post(format!("/api/0/image/{}/purchase", image_id))
+            .header(buyer_header.clone())
+            .dispatch()
+            .status()
+    };
+
+    assert_eq!(original(), Status::Forbidden);
+    assert_eq!(purchase(), Status::PaymentRequired);
+
+    let deposit = serde_json::from_str(&format!(
+        r#"{{ "username": "{}", "currency": "USD", "amount": 700 }}"#,
+        buyer
+    ))
+    .unwrap();
+    crate::market::deposit(deposit, db).unwrap();
+
+    let too_large = serde_json::from_str(&format!(
+        r#"{{ "username": "{}", "currency": "USD", "amount": 9223372036854775807 }}"#,
+        buyer
+    ))
+    .unwrap();
+    assert!(crate::market::deposit(too_large, db).is_err());
+
+    assert_eq!(purchase(), Status::Ok);
+    assert_eq!(purchase(), Status::Conflict);
+    assert_eq!(original(), Status::Ok);
+
+    assert_eq!(
+        crate::market::balances(&buyer, db).unwrap(),
+        vec![("USD".to_string(), 200)]
+    );
+    assert_eq!(
+        crate::market::balances(&seller, db).unwrap(),
+        vec![("USD".to_string(), 500)]
+    );
+    let transactions = crate::market::transactions(&buyer, db).unwrap();
+    assert_eq!(transactions.len(), 2);
+    assert!(transactions
+        .iter()
+        .any(|transaction| transaction.image_id.as_deref() == Some(image_id.as_str())));
+    assert_eq!(crate::market::transactions(&seller, db).unwrap().len(), 1);
+
+    // The only copy has been sold.
+    let image = db.images.get(image_id.as_bytes()).unwrap().unwrap();
+    assert_eq!(image.listing().inventory, Some(0));
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuoteResponse {
+    pub price: u64,
+    pub discount_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PriceResponse {
+    pub quote: QuoteResponse,
+}
+
+#[derive(Deserialize)]
+struct PurchaseResponse {
+    pub quote: QuoteResponse,
+}
+
+#[derive(Deserialize)]
+struct DiscountResponse {
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+struct DiscountCreatedResponse {
+    pub discount: DiscountResponse,
+}
+
+#[test]
+fn discounts() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let seller = format!("test_seller_{}", rand_string);
+    let buyers = [
+        format!("test_buyer_a_{}", rand_string),
+        format!("test_buyer_b_{}", rand_string),
+    ];
+    let image_id = format!("on_sale_{}", rand_string);
+
+    let auth_header = |username: &str| {
+        create_or_do_nothing(&client, username, "goose_pictures_1");
+        let token = login_get_json(&client, username, "goose_pictures_1")
+            .token
+            .unwrap();
+        Header::new("Authorization", format!("Bearer {}", token))
+    };
+    let seller_header = auth_header(&seller);
+    let buyer_headers: Vec<Header> = buyers.iter().map(|buyer| auth_header(buyer)).collect();
+
+    let image: crate::images::Image = serde_json::from_str(&format!(
+        r#"{{
+            "id": "{}",
+            "imageUrl": "",
+            "username": "{}",
+            "title": "Goose",
+            "tags": [],
+            "description": "",
+            "imageType": "image/jpeg",
+            "width": 1,
+            "height": 1,
+            "datetime": 0,
+            "visibility": "public"
+        }}"#,
+        image_id, seller
+    ))
+    .unwrap();
+    db.images.insert(image_id.as_bytes(), image).unwrap();
+    let listing = serde_json::from_str(r#"{ "price": 1000, "currency": "USD" }"#).unwrap();
+    crate::market::set_listing(&image_id, &seller, listing, db).unwrap();
+
+    let create_discount = |body: String| {
+        let response = client
+            .post("/api/0/discounts")
+            .header(ContentType::JSON)
+            .header(seller_header.clone())
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<DiscountCreatedResponse>(&response.into_string().unwrap())
+            .unwrap()
+            .discount
+            .id
+    };
+
+    let percentage = create_discount(format!(
+        r#"{{ "percentOff": 20, "imageId": "{}", "maxUses": 1 }}"#,
+        image_id
+    ));
+    let fixed = create_discount(r#"{ "amountOff": 100, "currency": "usd" }"#.to_string());
+
+    // A discount can't be both a percentage and a fixed amount.
+    let response = client
+        .post("/api/0/discounts")
+        .header(ContentType::JSON)
+        .header(seller_header.clone())
+        .body(r#"{ "percentOff": 20, "amountOff": 100, "currency": "USD" }"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let quote = || {
+        let response = client
+            .get(format!("/api/0/image/{}/price", image_id))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<PriceResponse>(&response.into_string().unwrap())
+            .unwrap()
+            .quote
+    };
+
+    // The best discount applies, until it is used up.
+    let expected = [(800, &percentage), (900, &fixed)];
+    for ((buyer, buyer_header), (price, discount_id)) in
+        buyers.iter().zip(buyer_headers).zip(expected.iter())
+    {
+        let quoted = quote();
+        assert_eq!(quoted.price, *price);
+        assert_eq!(quoted.discount_id.as_ref(), Some(*discount_id));
+
+        let deposit = serde_json::from_str(&format!(
+            r#"{{ "username": "{}", "currency": "USD", "amount": 1000 }}"#,
+            buyer
+        ))
+        .unwrap();
+        crate::market::deposit(deposit, db).unwrap();
+
+        let response = client
+            .post(format!("/api/0/image/{}/purchase", image_id))
+            .header(buyer_header)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let purchase =
+            serde_json::from_str::<PurchaseResponse>(&response.into_string().unwrap()).unwrap();
+        assert_eq!(purchase.quote.price, *price);
+        assert_eq!(purchase.quote.discount_id.as_ref(), Some(*discount_id));
+
+        assert_eq!(
+            crate::market::balances(buyer, db).unwrap(),
+            vec![("USD".to_string(), 1000 - *price as i64)]
+        );
+    }
+
+    assert_eq!(crate::market::discount_uses(&percentage, db).unwrap(), 1);
+    assert_eq!(
+        crate::market::balances(&seller, db).unwrap(),
+        vec![("USD".to_string(), 1700)]
+    );
+
+    // Percentages of the largest prices don't overflow.
+    let listing =
+        serde_json::from_str(r#"{ "price": 9223372036854775807, "currency": "USD" }"#).unwrap();
+    crate::market::set_listing(&image_id, &seller, listing, db).unwrap();
+    assert_eq!(quote().price, 9223372036854775807 - 100);
+}
+
+#[test]
+fn watermarked_previews() {
+    use img_hash::image::{self, DynamicImage, GenericImageView, RgbaImage};
+
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let original = DynamicImage::ImageRgba8(RgbaImage::new(2048, 1024));
+    let watermark =
+        crate::watermark::Watermark::from_config(&crate::config::WatermarkConfig::default())
+            .unwrap();
+    let preview = image::load_from_memory(&watermark.preview_jpeg(&original).unwrap()).unwrap();
+    assert_eq!(preview.dimensions(), (1024, 512));
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let seller = format!("test_seller_{}", rand_string);
+    let buyer = format!("test_buyer_{}", rand_string);
+    let image_id = format!("protected_{}", rand_string);
+
+    let auth_header = |username: &str| {
+        create_or_do_nothing(&client, username, "goose_pictures_1");
+        let token = login_get_json(&client, username, "goose_pictures_1")
+            .token
+            .unwrap();
+        Header::new("Authorization", format!("Bearer {}", token))
+    };
+    let seller_header = auth_header(&seller);
+    let buyer_header = auth_header(&buyer);
+
+    let image: crate::images::Image = serde_json::from_str(&format!(
+        r#"{{
+            "id": "{}",
+            "imageUrl": "https:/bucket.s3.amazonaws.com/original.jpg",
+            "previewUrl": "https:/bucket.s3.amazonaws.com/preview.jpg",
+            "username": "{}",
+            "title": "Goose",
+            "tags": [],
+            "description": "",
+            "imageType": "image/jpeg",
+            "width": 1,
+            "height": 1,
+            "datetime": 0,
+            "visibility": "public",
+            "price": 100,
+            "currency": "USD"
+        }}"#,
+        image_id, seller
+    ))
+    .unwrap();
+    db.images.insert(image_id.as_bytes(), image).unwrap();
+
+    let image_url = |header: Option<&Header<'static>>| {
+        let mut request = client.get(format!("/api/0/image/{}", image_id));
+        if let Some(header) = header {
+            request = request.header(header.clone());
+        }
+        let response = request.dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<serde_json::Value>(&response.into_string().unwrap()).unwrap()
+            ["image"]["imageUrl"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+
+    let original_url = |header: &Header<'static>| {
+        let response = client
+            .get(format!("/api/0/image/{}/original", image_id))
+            .header(header.clone())
+            .dispatch();
+        let status = response.status();
+        let url = serde_json::from_str::<serde_json::Value>(&response.into_string().unwrap())
+            .unwrap()["url"]
+            .as_str()
+            .map(str::to_string);
+        (status, url)
+    };
+
+    // Nobody is given a lasting link to the original, not even its owner.
+    assert!(image_url(None).ends_with("preview.jpg"));
+    assert!(image_url(Some(&buyer_header)).ends_with("preview.jpg"));
+    assert!(image_url(Some(&seller_header)).ends_with("preview.jpg"));
+
+    assert_eq!(original_url(&buyer_header), (Status::Forbidden, None));
+    let (status, url) = original_url(&seller_header);
+    assert_eq!(status, Status::Ok);
+    assert!(url.unwrap().contains("original.jpg"));
+
+    let deposit = serde_json::from_str(&format!(
+        r#"{{ "username": "{}", "currency": "USD", "amount": 100 }}"#,
+        buyer
+    ))
+    .unwrap();
+    crate::market::deposit(deposit, db).unwrap();
+    crate::market::purchase_image(&buyer, &image_id, db).unwrap();
+
+    assert!(image_url(Some(&buyer_header)).ends_with("preview.jpg"));
+    assert_eq!(original_url(&buyer_header).0, Status::Ok);
+
+    let shared_url = || {
+        let response = client
+            .post(format!("/api/0/image/{}/shares", image_id))
+            .header(ContentType::JSON)
+            .header(seller_header.clone())
+            .body("{}")
+            .dispatch();
+        let path = serde_json::from_str::<ShareCreatedResponse>(&response.into_string().unwrap())
+            .unwrap()
+            .path;
+        let response = client.get(path).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<serde_json::Value>(&response.into_string().unwrap()).unwrap()
+            ["image"]["imageUrl"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+
+    // Share links show images for sale as previews, and anything else as the original.
+    assert!(shared_url().ends_with("preview.jpg"));
+    let response = client
+        .put(format!("/api/0/image/{}/listing", image_id))
+        .header(ContentType::JSON)
+        .header(seller_header.clone())
+        .body("{}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(shared_url().ends_with("original.jpg"));
+}
+
+#[derive(Deserialize)]
+struct LikesResponse {
+    pub likes: u64,
+}
+
+#[test]
+fn favorites() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let usernames = [
+        format!("test_user_a_{}", rand_string),
+        format!("test_user_b_{}", rand_string),
+    ];
+    let image_id = format!("liked_{}", rand_string);
+
+    let auth_headers: Vec<_> = usernames
+        .iter()
+        .map(|username| {
+            create_or_do_nothing(&client, username, "goose_pictures_1");
+            let token = login_get_json(&client, username, "goose_pictures_1")
+                .token
+                .unwrap();
+            Header::new("Authorization", format!("Bearer {}", token))
+        })
+        .collect();
+
+    for (image_id, visibility) in &[(&image_id, "public"), (&private_image_id, "private")] {
+        let image: crate::images::Image = serde_json::from_str(&format!(
+            r#"{{
+                "id": "{}",
+                "imageUrl": "",
+                "username": "{}",
+                "title": "Goose",
+                "tags": [],
+                "description": "",
+                "imageType": "image/jpeg",
+                "width": 1,
+                "height": 1,
+                "datetime": 0,
+                "visibility": "{}"
+            }}"#,
+            image_id, usernames[0], visibility
+        ))
+        .unwrap();
+        db.images.insert(image_id.as_bytes(), image).unwrap();
+    }
+
+    let comments_path = format!("/api/0/image/{}/comments", image_id);
+    let create_comment = |header: &Header<'static>, body: String| {
+        let response = client
+            .post(&comments_path)
+            .header(ContentType::JSON)
+            .header(header.clone())
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<CommentCreatedResponse>(&response.into_string().unwrap())
+            .unwrap()
+            .comment
+            .id
+    };
+
+    let first = create_comment(&auth_headers[0], r#"{ "body": "Nice goose" }"#.to_string());
+    let reply = create_comment(
+        &auth_headers[1],
+        format!(r#"{{ "body": "Thanks", "parentId": "{}" }}"#, first),
+    );
+    let honk = create_comment(&auth_headers[1], r#"{ "body": "Honk" }"#.to_string());
+
+    let response = client.get(format!("{}?limit=1", comments_path)).dispatch();
+    let page: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(page["total"], 2);
+    assert_eq!(page["comments"].as_array().unwrap().len(), 1);
+    assert_eq!(page["comments"][0]["replies"][0]["id"], reply.as_str());
+
+    // Only the author can edit a comment.
+    let edit = |header: &Header<'static>| {
+        client
+            .patch(format!("{}/{}", comments_path, reply))
+            .header(ContentType::JSON)
+            .header(header.clone())
+            .body(r#"{ "body": "Thanks!" }"#)
+            .dispatch()
+            .status()
+    };
+    assert_eq!(edit(&auth_headers[0]), Status::Forbidden);
+    assert_eq!(edit(&auth_headers[1]), Status::Ok);
+
+    // Deleting a comment deletes its replies.
+    let response = client
+        .delete(format!("{}/{}", comments_path, first))
+        .header(auth_headers[0].clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.get(&comments_path).dispatch();
+    let page: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["comments"][0]["id"], honk.as_str());
+    assert_eq!(page["comments"][0]["replies"].as_array().unwrap().len(), 0);
+
+    // Replies can only nest so deep.
+    let mut parent = honk;
+    for _ in 0..8 {
+        parent = create_comment(
+            &auth_headers[0],
+            format!(r#"{{ "body": "Honk", "parentId": "{}" }}"#, parent),
+        );
+    }
+    let response = client
+        .post(&comments_path)
+        .header(ContentType::JSON)
+        .header(auth_headers[0].clone())
+        .body(format!(r#"{{ "body": "Honk", "parentId": "{}" }}"#, parent))
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    // Comments on private images are as hidden as the image.
+    let response = client
+        .post(format!("/api/0/image/{}/comments", private_image_id))
+        .header(ContentType::JSON)
+        .header(auth_headers[1].clone())
+        .body(r#"{ "body": "Honk" }"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    crate::comment::remove_image_comments(&image_id, db).unwrap();
+    assert!(db
+        .comments
+        .scan_prefix(format!("{}\0", image_id).as_bytes())
+        .next()
+        .is_none());
+}
+
+#[test]
+fn feed() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let usernames = [
+        format!("test_user_a_{}", rand_string),
+        format!("test_user_b_{}", rand_string),
+        format!("test_user_c_{}", rand_string),
+    ];
+
+    let auth_headers: Vec<_> = usernames
+        .iter()
+        .map(|username| {
+            create_or_do_nothing(&client, username, "goose_pictures_1");
+            let token = login_get_json(&client, username, "goose_pictures_1")
+                .token
+                .unwrap();
+            Header::new("Authorization", format!("Bearer {}", token))
+        })
+        .collect();
+
+    let images = [
+        (
+            format!("feed_b_old_{}", rand_string),
+            &usernames[1],
+            "public",
+            100,
+        ),
+        (
+            format!("feed_c_{}", rand_string),
+            &usernames[2],
+            "public",
+            200,
+        ),
+        (
+            format!("feed_b_private_{}", rand_string),
+            &usernames[1],
+            "private",
+            250,
+        ),
+        (
+            format!("feed_b_new_{}", rand_string),
+            &usernames[1],
+            "public",
+            300,
+        ),
+    ];
+    for (id, username, visibility, datetime) in images.iter() {
+        let image: crate::images::Image = serde_json::from_str(&format!(
+            r#"{{
+                "id": "{}",
+                "imageUrl": "",
+                "username": "{}",
+                "title": "Goose",
+                "tags": [],
+                "description": "",
+                "imageType": "image/jpeg",
+                "width": 1,
+                "height": 1,
+                "datetime": {},
+                "visibility": "{}"
+            }}"#,
+            id, username, datetime, visibility
+        ))
+        .unwrap();
+        crate::images::index_upload(&image, db).unwrap();
+        db.images.insert(id.as_bytes(), image).unwrap();
+    }
+
+    for username in usernames.iter().skip(1) {
+        let response = client
+            .put(format!("/api/0/users/{}/follow", username))
+            .header(auth_headers[0].clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    let response = client
+        .put(format!("/api/0/users/{}/follow", usernames[0]))
+        .header(auth_headers[0].clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let get_feed = |query: &str| {
+        let response = client
+            .get(format!("/api/0/feed?{}", query))
+            .header(auth_headers[0].clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<serde_json::Value>(&response.into_string().unwrap()).unwrap()
+    };
+
+    // Private uploads are left out, and pages pick up where the last one ended.
+    let page = get_feed("limit=2");
+    assert_eq!(page["images"][0]["id"], images[3].0.as_str());
+    assert_eq!(page["images"][1]["id"], images[1].0.as_str());
+    let cursor = page["nextCursor"].as_str().unwrap().to_string();
+
+    let page = get_feed(&format!("limit=2&cursor={}", cursor));
+    assert_eq!(page["images"].as_array().unwrap().len(), 1);
+    assert_eq!(page["images"][0]["id"], images[0].0.as_str());
+    assert!(page["nextCursor"].is_null());
+
+    let response = client
+        .delete(format!("/api/0/users/{}/follow", usernames[1]))
+        .header(auth_headers[0].clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let page = get_feed("limit=10");
+    assert_eq!(page["images"].as_array().unwrap().len(), 1);
+    assert_eq!(page["images"][0]["id"], images[1].0.as_str());
+    assert_eq!(crate::feed::follower_count(&usernames[1], db).unwrap(), 0);
+    assert_eq!(crate::feed::follower_count(&usernames[2], db).unwrap(), 1);
+
+    // Deleting a user unfollows them for everyone who followed them.
+    crate::feed::delete_user_follows(&usernames[2], db).unwrap();
+    assert_eq!(crate::feed::follower_count(&usernames[2], db).unwrap(), 0);
+    assert!(crate::feed::following(&usernames[0], db)
+        .unwrap()
+        .is_empty());
+
+    let response = client.get("/api/0/feed").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn profiles() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+    let public_image_id = format!("profile_public_{}", rand_string);
+    let private_image_id = format!("profile_private_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+    let auth_header = Header::new("Authorization", format!("Bearer {}", token));
+
+    for (id, visibility, datetime) in [
+        (&public_image_id, "public", 100),
+        (&private_image_id, "private", 200),
+    ]
+    .iter()
+    {
+        let image: crate::images::Image = serde_json::from_str(&format!(
+            r#"{{
+                "id": "{}",
+                "imageUrl": "",
+                "username": "{}",
+                "title": "Goose",
+                "tags": [],
+                "description": "",
+                "imageType": "image/jpeg",
+                "width": 1,
+                "height": 1,
+                "datetime": {},
+                "visibility": "{}"
+            }}"#,
+            id, username, datetime, visibility
+        ))
+        .unwrap();
+        crate::images::index_upload(&image, db).unwrap();
+        db.images.insert(id.as_bytes(), image).unwrap();
+    }
+
+    let response = client
+        .patch("/api/0/account/profile")
+        .header(ContentType::JSON)
+        .header(auth_header.clone())
+        .body(format!(
+            r#"{{ "displayName": "Goose", "bio": "Honk", "avatarImageId": "{}" }}"#,
+            private_image_id
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = client
+        .patch("/api/0/account/profile")
+        .header(ContentType::JSON)
+        .header(auth_header.clone())
+        .body(format!(
+            r#"{{ "displayName": "Goose", "bio": "Honk", "avatarImageId": "{}" }}"#,
+            public_image_id
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client.get(format!("/api/0/users/{}", username)).dispatch();
+    let profile: serde_json::Value =
+        serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(profile["profile"]["displayName"], "Goose");
+    assert_eq!(
+        profile["profile"]["avatarImageId"],
+        public_image_id.as_str()
+    );
+    assert!(profile["profile"]["created"].is_number());
+    assert_eq!(profile["profile"]["stats"]["images"], 1);
+
+    // Owners see their private images too, newest first.
+    let response = client
+        .get(format!("/api/0/users/{}/images", username))
+        .header(auth_header.clone())
+        .dispatch();
+    let page: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(page["total"], 2);
+    assert_eq!(page["images"][0]["id"], private_image_id.as_str());
+
+    let response = client
+        .get(format!("/api/0/users/{}/images?offset=0&limit=1", username))
+        .dispatch();
+    let page: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["images"][0]["id"], public_image_id.as_str());
+
+    let response = client
+        .get(format!("/api/0/users/missing_{}", rand_string))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn quotas() {
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let username = format!("test_user_{}", rand_string);
+
+    create_or_do_nothing(&client, &username, "goose_pictures_1");
+    let token = login_get_json(&client, &username, "goose_pictures_1")
+        .token
+        .unwrap();
+    let auth_header = Header::new("Authorization", format!("Bearer {}", token));
+
+    let image: crate::images::Image = serde_json::from_str(&format!(
+        r#"{{
+            "id": "quota_{}",
+            "imageUrl": "",
+            "username": "{}",
+            "title": "Goose",
+            "tags": [],
+            "description": "",
+            "imageType": "image/jpeg",
+            "width": 1,
+            "height": 1,
+            "datetime": 0,
+            "visibility": "public",
+            "size": 1000
+        }}"#,
+        rand_string, username
+    ))
+    .unwrap();
+
+    crate::quota::record_upload(&image, db).unwrap();
+    crate::quota::record_upload(&image, db).unwrap();
+
+    let response = client
+        .get("/api/0/account/usage")
+        .header(auth_header.clone())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let usage: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(usage["usage"]["bytes"], 2000);
+    assert_eq!(usage["usage"]["images"], 2);
+
+    let usage = crate::quota::record_deletion(&image, db).unwrap();
+    assert_eq!(usage.bytes, 1000);
+    assert_eq!(usage.images, 1);
+
+    let limits = crate::config::QuotaLimits {
+        max_bytes: Some(1500),
+        max_images: Some(2),
+    };
+    assert!(crate::quota::check_limits(&usage, 500, &limits).is_ok());
+    assert!(matches!(
+        crate::quota::check_limits(&usage, 501, &limits),
+        Err(crate::quota::QuotaError::StorageExceeded { max_bytes: 1500 })
+    ));
+
+    let usage = crate::quota::Usage {
+        bytes: 0,
+        images: 2,
+    };
+    assert!(matches!(
+        crate::quota::check_limits(&usage, 0, &limits),
+        Err(crate::quota::QuotaError::ImageLimitReached { max_images: 2 })
+    ));
+
+    let response = client.get("/api/0/account/usage").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+/// A JPEG holding only EXIF data: a Canon camera, rotated, at 1/250s and ISO 400, taken 2020-01-02 03:04:05, at
+/// 43.4723, -80.5449.
+fn exif_jpeg() -> Vec<u8> {
+    let entry = |tag: u16, kind: u16, count: u32, value: u32| {
+        let mut entry = vec![];
+        entry.extend(&tag.to_be_bytes());
+        entry.extend(&kind.to_be_bytes());
+        entry.extend(&count.to_be_bytes());
+        entry.extend(&value.to_be_bytes());
+        entry
+    };
+    let rational = |numerator: u32, denominator: u32| {
+        let mut rational = numerator.to_be_bytes().to_vec();
+        rational.extend(&denominator.to_be_bytes());
+        rational
+    };
+
+    // Offsets are from the start of the TIFF header.  IFDs with three entries take 42 bytes, and four entries 54.
+    let mut tiff = b"MM\0\x2a".to_vec();
+    tiff.extend(&8u32.to_be_bytes());
+
+    tiff.extend(&4u16.to_be_bytes());
+    tiff.extend(entry(0x010f, 2, 6, 158)); // Make
+    tiff.extend(entry(0x0112, 3, 1, 6 << 16)); // Orientation
+    tiff.extend(entry(0x8769, 4, 1, 62)); // Exif IFD
+    tiff.extend(entry(0x8825, 4, 1, 104)); // GPS IFD
+    tiff.extend(&0u32.to_be_bytes());
+
+    tiff.extend(&3u16.to_be_bytes());
+    tiff.extend(entry(0x829a, 5, 1, 164)); // ExposureTime
+    tiff.extend(entry(0x8827, 3, 1, 400 << 16)); // PhotographicSensitivity
+    tiff.extend(entry(0x9003, 2, 20, 172)); // DateTimeOriginal
+    tiff.extend(&0u32.to_be_bytes());
+
+    tiff.extend(&4u16.to_be_bytes());
+    tiff.extend(entry(0x0001, 2, 2, u32::from_be_bytes(*b"N\0\0\0"))); // GPSLatitudeRef
+    tiff.extend(entry(0x0002, 5, 3, 192)); // GPSLatitude
+    tiff.extend(entry(0x0003, 2, 2, u32::from_be_bytes(*b"W\0\0\0"))); // GPSLongitudeRef
+    tiff.extend(entry(0x0004, 5, 3, 216)); // GPSLongitude
+    tiff.extend(&0u32.to_be_bytes());
+
+    tiff.extend(b"Canon\0");
+    tiff.extend(rational(1, 250));
+    tiff.extend(b"2020:01:02 03:04:05\0");
+    for (numerator, denominator) in
+        [(43, 1), (28, 1), (2040, 100), (80, 1), (32, 1), (4164, 100)].iter()
+    {
+        tiff.extend(rational(*numerator, *denominator));
+    }
+
+    let mut app1 = b"Exif\0\0".to_vec();
+    app1.extend(tiff);
+
+    let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+    jpeg.extend(&(app1.len() as u16 + 2).to_be_bytes());
+    jpeg.extend(app1);
+    jpeg.extend(&[0xff, 0xd9]);
+    jpeg
+}
+
+#[test]
+fn exif_metadata() {
+    let exif = crate::metadata::Exif::from_bytes(&exif_jpeg()).unwrap();
+    assert_eq!(exif.make.as_deref(), Some("Canon"));
+    assert_eq!(exif.orientation, Some(6));
+    assert!((exif.exposure_time.unwrap() - 1.0 / 250.0).abs() < 1e-9);
+    assert_eq!(exif.iso, Some(400));
+    assert_eq!(exif.captured, Some(1577934245));
+    assert!((exif.latitude.unwrap() - 43.4723).abs() < 1e-4);
+    assert!((exif.longitude.unwrap() + 80.5449).abs() < 1e-4);
+
+    assert!(
+        crate::metadata::Exif::from_bytes(&std::fs::read("./images/test1.jpg").unwrap()).is_none()
+    );
+
+    let image: crate::images::Image = serde_json::from_str(&format!(
+        r#"{{
+            "id": "exif",
+            "imageUrl": "",
+            "username": "username",
+            "title": "Goose",
+            "tags": [],
+            "description": "",
+            "imageType": "image/jpeg",
+            "width": 1,
+            "height": 1,
+            "datetime": 1600000000,
+            "visibility": "public",
+            "exif": {}
+        }}"#,
+        serde_json::to_string(&exif).unwrap()
+    ))
+    .unwrap();
+
+    let filter = crate::images::DateFilter {
+        captured_before: Some(1577934245),
+        uploaded_after: Some(1600000000),
+        ..Default::default()
+    };
+    assert!(filter.matches(&image));
+
+    let filter = crate::images::DateFilter {
+        captured_after: Some(1577934246),
+        ..Default::default()
+    };
+    assert!(!filter.matches(&image));
+
+    // Searches by date only read the indexes.
+    let client = Client::tracked(rocket_from_db(&DATABASE)).expect("Valid rocket instance...");
+    let db = client.rocket().state::<crate::Database>().unwrap();
+
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(15)
+        .map(char::from)
+        .collect();
+    let owner = format!("test_photographer_{}", rand_string);
+    let image_id = format!("located_{}", rand_string);
+    let located = crate::metadata::Exif {
+        latitude: Some(43.4723),
+        longitude: Some(-80.5449),
+        ..exif
+    };
+
+    create_or_do_nothing(&client, &owner, "goose_pictures_1");
+    let token = login_get_json(&client, &owner, "goose_pictures_1")
+        .token
+        .unwrap();
+
+    let image: crate::images::Image = serde_json::from_str(&format!(
+        r#"{{
+            "id": "{}",
+            "imageUrl": "",
+            "username": "{}",
+            "title": "Goose",
+            "tags": [],
+            "description": "",
+            "imageType": "image/jpeg",
+            "width": 1,
+            "height": 1,
+            "datetime": 1600000000,
+            "visibility": "public",
+            "exif": {}
+        }}"#,
+        image_id,
+        owner,
+        serde_json::to_string(&located).unwrap()
+    ))
+    .unwrap();
+    crate::images::index_dates(&image, db).unwrap();
+    db.images.insert(image_id.as_bytes(), image).unwrap();
+
+    let found = |filter: crate::images::DateFilter| {
+        crate::images::images_by_date(&filter, db)
+            .unwrap()
+            .iter()
+            .any(|image| image.id() == image_id)
+    };
+    assert!(found(crate::images::DateFilter {
+        captured_after: Some(1577934245),
+        captured_before: Some(1577934245),
+        ..Default::default()
+    }));
+    assert!(found(crate::images::DateFilter {
+        uploaded_after: Some(1600000000),
+        captured_before: Some(1577934245),
+        ..Default::default()
+    }));
+    assert!(!found(crate::images::DateFilter {
+        captured_after: Some(1577934246),
+        ..Default::default()
+    }));
+
+    // Only the owner is shown where the photo was taken.
+    let exif_for = |header: Option<Header<'static>>| {
+        let mut request = client.get(format!("/api/0/image/{}", image_id));
+        if let Some(header) = header {
+            request = request.header(header);
+        }
+        let response = request.dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<serde_json::Value>(&response.into_string().unwrap()).unwrap()
+            ["image"]["exif"]
+            .clone()
+    };
+
+    let public = exif_for(None);
+    assert_eq!(public["make"], "Canon");
+    assert!(public["latitude"].is_null());
+    assert!(public["longitude"].is_null());
+
+    let own = exif_for(Some(Header::new(
+        "Authorization",
+        format!("Bearer {}", token),
+    )));
+    assert!(own["latitude"].is_number());
+    assert!(own["longitude"].is_number());
+}
+
+#[test]
+fn metadata_stripping() {
+    use crate::{
+        config::MetadataPrivacy,
+        metadata::{strip, Exif},
+    };
+    use img_hash::image::{DynamicImage, ImageFormat, ImageOutputFormat};
+
+    let jpeg = exif_jpeg();
+    let decoded = DynamicImage::new_rgb8(1, 1);
+
+    let (kept, format) = strip(&jpeg, ImageFormat::Jpeg, &decoded, MetadataPrivacy::Keep).unwrap();
+    assert_eq!(kept, jpeg);
+    assert_eq!(format, ImageFormat::Jpeg);
+
+    // Only the coordinates are removed, and the rest of the EXIF data can still be read.
+    let (without_gps, _) = strip(
+        &jpeg,
+        ImageFormat::Jpeg,
+        &decoded,
+        MetadataPrivacy::StripGps,
+    )
+    .unwrap();
+    assert_eq!(without_gps.len(), jpeg.len());
+    let jpeg_without_gps = without_gps;
+    let exif = Exif::from_bytes(&jpeg_without_gps).unwrap();
+    assert_eq!(exif.make.as_deref(), Some("Canon"));
+    assert_eq!(exif.captured, Some(1577934245));
+    assert!(exif.latitude.is_none());
+    assert!(exif.longitude.is_none());
+
+    let (stripped, _) = strip(
+        &jpeg,
+        ImageFormat::Jpeg,
+        &decoded,
+        MetadataPrivacy::StripAll,
+    )
+    .unwrap();
+    assert_eq!(stripped, vec![0xff, 0xd8, 0xff, 0xd9]);
+
+    // Redacting matches what's stripped from the file.
+    let exif = Exif::from_bytes(&jpeg).unwrap();
+    assert!(exif
+        .clone()
+        .redact(MetadataPrivacy::Keep)
+        .latitude
+        .is_some());
+    assert!(exif.redact(MetadataPrivacy::StripGps).latitude.is_none());
+
+    let mut png = vec![];
+    decoded.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    let (reencoded, format) =
+        strip(&png, ImageFormat::Png, &decoded, MetadataPrivacy::StripAll).unwrap();
+    assert!(img_hash::image::load_from_memory(&reencoded).is_ok());
+    assert_eq!(format, ImageFormat::Png);
+
+    // Formats that can't be cleaned in place are converted to PNG.
+    let (converted, format) =
+        strip(&png, ImageFormat::Tiff, &decoded, MetadataPrivacy::StripGps).unwrap();
+    assert_eq!(format, ImageFormat::Png);
+    assert_eq!(
+        img_hash::image::guess_format(&converted).unwrap(),
+        ImageFormat::Png
+    );
+
+    // WebPs keep their image data, with the same EXIF data removed as from JPEGs.
+    let tiff = &jpeg[12..jpeg.len() - 2];
+    let xmp = b"<rdf:Description exif:GPSLatitude=\"43,28.34N\"/>";
+    let chunk = |name: &[u8], payload: &[u8]| {
+        let mut chunk = name.to_vec();
+        chunk.extend(&(payload.len() as u32).to_le_bytes());
+        chunk.extend(payload);
+        if payload.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    };
+    let webp = |chunks: Vec<Vec<u8>>| {
+        let chunks = chunks.concat();
+        let mut webp = b"RIFF".to_vec();
+        webp.extend(&(chunks.len() as u32 + 4).to_le_bytes());
+        webp.extend(b"WEBP");
+        webp.extend(chunks);
+        webp
+    };
+    let extended_header = |flags: u8| chunk(b"VP8X", &[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let image_data = chunk(b"VP8 ", b"frame");
+    let original = webp(vec![
+        extended_header(0x0c),
+        image_data.clone(),
+        chunk(b"EXIF", tiff),
+        chunk(b"XMP ", xmp),
+    ]);
+
+    let (without_gps, format) = strip(
+        &original,
+        ImageFormat::WebP,
+        &decoded,
+        MetadataPrivacy::StripGps,
+    )
+    .unwrap();
+    assert_eq!(format, ImageFormat::WebP);
+    assert_eq!(
+        without_gps,
+        webp(vec![
+            extended_header(0x08),
+            image_data.clone(),
+            chunk(b"EXIF", &jpeg_without_gps[12..jpeg_without_gps.len() - 2]),
+        ])
+    );
+
+    let (stripped, _) = strip(
+        &original,
+        ImageFormat::WebP,
+        &decoded,
+        MetadataPrivacy::StripAll,
+    )
+    .unwrap();
+    assert_eq!(stripped, webp(vec![extended_header(0), image_data]));
+
+    // GIFs keep every frame as is, and only lose their XMP.
+    let header = b"GIF89a\x01\0\x01\0\x80\0\0\0\0\0\xff\xff\xff".to_vec();
+    let frame = b"\x2c\0\0\0\0\x01\0\x01\0\0\x02\x02\x44\x01\0".to_vec();
+    let mut xmp_extension = b"\x21\xff\x0bXMP DataXMP".to_vec();
+    xmp_extension.push(xmp.len() as u8);
+    xmp_extension.extend(&xmp[..]);
+    xmp_extension.push(0);
+    let gif = |blocks: Vec<Vec<u8>>| {
+        [vec![header.clone()], blocks, vec![b"\x3b".to_vec()]]
+            .concat()
+            .concat()
+    };
+    let original = gif(vec![xmp_extension, frame.clone(), frame.clone()]);
+
+    let (kept, format) =
+        strip(&original, ImageFormat::Gif, &decoded, MetadataPrivacy::Keep).unwrap();
+    assert_eq!(kept, original);
+    assert_eq!(format, ImageFormat::Gif);
+
+    let (without_gps, format) = strip(
+        &original,
+        ImageFormat::Gif,
+        &decoded,
+        MetadataPrivacy::StripGps,
+    )
+    .unwrap();
+    assert_eq!(format, ImageFormat::Gif);
+    assert_eq!(without_gps, gif(vec![frame.clone(), frame.clone()]));
+
+    let (stripped, _) = strip(
+        &original,
+        ImageFormat::Gif,
+        &decoded,
+        MetadataPrivacy::StripAll,
+    )
+    .unwrap();
+    assert_eq!(stripped, gif(vec![frame.clone(), frame]));
 }
@@ -0,0 +1,114 @@
+//! Tracks failed login attempts, so that repeated password guesses are slowed down and eventually locked out.
+
+use std::net::IpAddr;
+
+use chrono::Utc;
+use rocket::request::Request;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::LoginThrottleConfig, consts::TRUSTED_PROXIES, Database};
+
+/// The failed login attempts recorded against either a username or a client IP.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LoginAttempts {
+    pub failures: u32,
+    /// Unix timestamp of the latest failure.
+    pub last_failure: i64,
+    /// Unix timestamp until which further attempts are rejected.
+    pub blocked_until: i64,
+}
+
+/// The IP a request came from.  The `X-Real-IP` header is only believed when the request comes from a trusted proxy,
+/// otherwise clients could pick a new IP for every guess.
+pub fn client_ip(req: &Request<'_>) -> Option<IpAddr> {
+    let remote = req.remote().map(|remote| remote.ip());
+
+    match remote {
+        Some(ip) if TRUSTED_PROXIES.contains(&ip) => req.real_ip().or(remote),
+        _ => remote,
+    }
+}
+
+fn attempt_keys(username: &str, ip: Option<IpAddr>) -> Vec<String> {
+    let mut keys = vec![format!("user:{}", username)];
+    if let Some(ip) = ip {
+        keys.push(format!("ip:{}", ip));
+    }
+    keys
+}
+
+/// Returns the number of seconds the caller has to wait before trying to log in again, if they are currently throttled.
+pub fn check_throttle(
+    username: &str,
+    ip: Option<IpAddr>,
+    db: &Database,
+) -> anyhow::Result<Option<i64>> {
+    let now = Utc::now().timestamp();
+    let mut retry_after: Option<i64> = None;
+
+    for key in attempt_keys(username, ip) {
+        if let Some(attempts) = db.login_attempts.get(key.as_bytes())? {
+            if attempts.blocked_until > now {
+                let wait = attempts.blocked_until - now;
+                retry_after = Some(retry_after.map_or(wait, |current| current.max(wait)));
+            }
+        }
+    }
+
+    Ok(retry_after)
+}
+
+/// Records a failed login against both the username and the client IP, blocking further attempts as needed.
+pub fn record_failure(
+    username: &str,
+    ip: Option<IpAddr>,
+    config: &LoginThrottleConfig,
+    db: &Database,
+) -> anyhow::Result<()> {
+    let now = Utc::now().timestamp();
+
+    // Updated atomically, so failures happening at the same time are all counted.
+    for key in attempt_keys(username, ip) {
+        db.login_attempts
+            .update_and_fetch(key.as_bytes(), |attempts| {
+                let mut attempts = attempts.unwrap_or_default();
+
+                // Old failures are forgotten after a quiet period.
+                if now - attempts.last_failure > config.reset_after_seconds as i64 {
+                    attempts = LoginAttempts::default();
+                }
+
+                attempts.failures += 1;
+                attempts.last_failure = now;
+                attempts.blocked_until = now + block_duration(attempts.failures, config);
+
+                Some(attempts)
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Clears the failed logins recorded against a username after a successful login.  Failures recorded against
+/// the IP are left to expire, otherwise logging into one account would reset guesses made against another.
+pub fn clear_failures(username: &str, db: &Database) -> anyhow::Result<()> {
+    db.login_attempts
+        .remove(format!("user:{}", username).as_bytes())?;
+
+    Ok(())
+}
+
+/// How long to block for after a given number of consecutive failures, in seconds.
+fn block_duration(failures: u32, config: &LoginThrottleConfig) -> i64 {
+    if failures >= config.lockout_threshold {
+        config.lockout_seconds as i64
+    } else if failures > config.free_attempts {
+        let exponent = (failures - config.free_attempts - 1).min(30);
+        config
+            .base_delay_seconds
+            .saturating_mul(1 << exponent)
+            .min(config.max_delay_seconds) as i64
+    } else {
+        0
+    }
+}
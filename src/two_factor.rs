@@ -0,0 +1,225 @@
+//! Time-based one-time passwords (RFC 6238) as a second login factor, with single-use recovery codes.
+
+use nanoid::nanoid;
+use reqwest::Url;
+use ring::{
+    constant_time, digest, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{consts, Database};
+
+const ISSUER: &str = "foto";
+const SECRET_LEN: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// A user's TOTP settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactor {
+    pub secret: Vec<u8>,
+    /// Set once a code has been entered for the secret.  Until then, logging in doesn't ask for a code.
+    pub confirmed: bool,
+    /// SHA-256 hashes of the unused recovery codes, in base64.
+    pub recovery_codes: Vec<String>,
+    /// The latest time step a code was accepted for, so that a code can't be used twice.
+    pub last_step: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum TwoFactorError {
+    #[error("Two-factor authentication is already enabled")]
+    AlreadyEnabled,
+    #[error("Two-factor authentication has not been set up")]
+    NotEnrolled,
+    #[error("Invalid two-factor code")]
+    InvalidCode,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorLogin {
+    pub challenge: String,
+    pub code: String,
+}
+
+/// Starts enrolling a user with a new secret, returning the secret in base32 and an `otpauth` URI for it.
+pub fn enroll(username: &str, db: &Database) -> anyhow::Result<(String, String)> {
+    if is_enabled(username, db)? {
+        Err(TwoFactorError::AlreadyEnabled)?
+    }
+
+    let mut secret = vec![0u8; SECRET_LEN];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| anyhow::format_err!("Could not generate TOTP secret."))?;
+
+    let encoded_secret = base32(&secret);
+    let mut uri = Url::parse(&format!("otpauth://totp/{}:{}", ISSUER, username))?;
+    uri.query_pairs_mut()
+        .append_pair("secret", &encoded_secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+
+    db.two_factor.insert(
+        username.as_bytes(),
+        TwoFactor {
+            secret,
+            confirmed: false,
+            recovery_codes: vec![],
+            last_step: 0,
+        },
+    )?;
+
+    Ok((encoded_secret, uri.to_string()))
+}
+
+/// Finishes enrolling a user given a valid code for their new secret, returning their recovery codes.
+pub fn confirm(username: &str, code: &str, db: &Database) -> anyhow::Result<Vec<String>> {
+    let mut two_factor = db
+        .two_factor
+        .get(username.as_bytes())?
+        .ok_or(TwoFactorError::NotEnrolled)?;
+
+    if two_factor.confirmed {
+        Err(TwoFactorError::AlreadyEnabled)?
+    }
+
+    let step = verify_totp(&two_factor.secret, code, chrono::Utc::now().timestamp())
+        .ok_or(TwoFactorError::InvalidCode)?;
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| nanoid!(10, &consts::READABLE_CODE_ALPHABET))
+        .collect();
+
+    two_factor.confirmed = true;
+    two_factor.last_step = step;
+    two_factor.recovery_codes = recovery_codes
+        .iter()
+        .map(|code| hash_recovery_code(code))
+        .collect();
+
+    db.two_factor.insert(username.as_bytes(), two_factor)?;
+
+    Ok(recovery_codes)
+}
+
+/// Whether a user has to enter a code when logging in.
+pub fn is_enabled(username: &str, db: &Database) -> anyhow::Result<bool> {
+    Ok(db
+        .two_factor
+        .get(username.as_bytes())?
+        .map_or(false, |two_factor| two_factor.confirmed))
+}
+
+/// Checks a TOTP or recovery code for a user.  Either is used up when accepted.  The check and the update happen in
+/// one transaction, so the same code can't be accepted by two requests at once.
+pub fn verify_code(username: &str, code: &str, db: &Database) -> anyhow::Result<()> {
+    let key = username.as_bytes().to_vec();
+    let code = code.trim().to_string();
+    let now = chrono::Utc::now().timestamp();
+
+    db.two_factor
+        .transaction(move |tx_db| {
+            let mut two_factor = match tx_db.get(&key)? {
+                Ok(Some(two_factor)) if two_factor.confirmed => two_factor,
+                _ => return Ok(Err(anyhow::Error::from(TwoFactorError::NotEnrolled))),
+            };
+
+            match verify_totp(&two_factor.secret, &code, now) {
+                Some(step) if step > two_factor.last_step => {
+                    two_factor.last_step = step;
+                }
+                _ => {
+                    let hashed_code = hash_recovery_code(&code.to_lowercase());
+                    let before = two_factor.recovery_codes.len();
+                    two_factor
+                        .recovery_codes
+                        .retain(|recovery_code| *recovery_code != hashed_code);
+
+                    if two_factor.recovery_codes.len() == before {
+                        return Ok(Err(anyhow::Error::from(TwoFactorError::InvalidCode)));
+                    }
+                }
+            }
+
+            match tx_db.insert(key.clone(), two_factor)? {
+                Ok(_) => Ok(Ok(())),
+                Err(err) => Ok(Err(anyhow::Error::from(err))),
+            }
+        })
+        .map_err(|err| anyhow::format_err!("Transaction error: {:?}", err))??;
+
+    Ok(())
+}
+
+/// Generates the code for a secret at a given time step.
+pub fn totp_code(secret: &[u8], step: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let digest = tag.as_ref();
+
+    // Dynamic truncation, as per RFC 4226.
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// Checks a code against the time step of a Unix timestamp, allowing one step either side for clock drift.
+/// Returns the step the code matched.
+fn verify_totp(secret: &[u8], code: &str, timestamp: i64) -> Option<u64> {
+    let current_step = (timestamp / STEP_SECONDS) as u64;
+
+    (current_step.saturating_sub(1)..=current_step + 1).find(|&step| {
+        constant_time::verify_slices_are_equal(totp_code(secret, step).as_bytes(), code.as_bytes())
+            .is_ok()
+    })
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    base64::encode(digest::digest(&digest::SHA256, code.as_bytes()))
+}
+
+/// Encodes bytes as unpadded RFC 4648 base32, which is what authenticator apps expect secrets in.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut encoded = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
@@ -1,19 +1,103 @@
 use anyhow::Context;
+use chrono::Utc;
+use nanoid::nanoid;
 use ring::{digest, pbkdf2};
+use rusoto_s3::S3Client;
 use serde::{Deserialize, Serialize};
+use sled_extensions::{bincode::Tree, DbExt};
 use std::num::NonZeroU32;
 use thiserror::Error;
 
-use crate::{auth::create_jwt, Database};
+use crate::{
+    album,
+    auth::{create_challenge_jwt, create_jwt},
+    config::{AccountPolicyConfig, RegistrationMode},
+    consts, discount, favorite, feed, images,
+    invite::{redeem_invite, RegistrationError},
+    oidc,
+    policy::{normalize_username, username_key, validate_password, validate_username, PolicyError},
+    profile, quota,
+    session::{self, Device},
+    share, throttle, two_factor, Database,
+};
 
 static PBKDF2_ALG: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
 const CREDENTIAL_LEN: usize = digest::SHA256_OUTPUT_LEN;
 pub type HashedCredential = [u8; CREDENTIAL_LEN];
+const PASSWORD_RESET_DEFAULT_MINUTES: i64 = 60;
+const PASSWORD_RESET_MAX_MINUTES: i64 = 7 * 24 * 60;
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Credentials {
     username: String,
     password: String,
+    /// Only needed when registering while registration is invite-only.
+    #[serde(default)]
+    invite_code: Option<String>,
+}
+
+impl Credentials {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountDeletion {
+    password: String,
+    /// If true, the user's images are deleted along with the account.  Otherwise, they are given to `reassign_to`.
+    #[serde(default)]
+    delete_images: bool,
+    reassign_to: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordResetRequest {
+    username: String,
+    expires_in_minutes: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordReset {
+    code: String,
+    new_password: String,
+}
+
+/// A single-use code an admin issued to reset a user's password.  Stored under a hash of the code.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetCode {
+    pub username: String,
+    /// Unix timestamp.
+    pub expires: i64,
+    pub issued_by: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SuspensionRequest {
+    reason: String,
+    /// Unix timestamp.  If not set, the user is suspended until reinstated.
+    until: Option<i64>,
+}
+
+/// Why and until when a user is suspended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suspension {
+    pub reason: String,
+    /// Unix timestamp.
+    pub until: Option<i64>,
+    pub suspended_by: String,
 }
 
 /// A simple user database config.
@@ -29,83 +113,510 @@ pub enum VerifyError {
     IncorrectUsernameOrPassword,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Error, Debug)]
+/// An error while trying to change or delete an existing account.
+pub enum AccountError {
+    #[error("Incorrect password")]
+    IncorrectPassword,
+    #[error("Images must either be deleted or reassigned to another user")]
+    NoImageDisposition,
+    #[error("Could not find the user to reassign images to")]
+    UnknownReassignTarget,
+    #[error("Could not find user")]
+    UnknownUser,
+    #[error("Invalid or expired reset code")]
+    InvalidResetCode,
+}
+
+#[derive(Error, Debug, Clone)]
+#[error("This account is suspended: {reason}")]
+/// Returned instead of a token for suspended users.
+pub struct SuspendedError {
+    pub reason: String,
+    pub until: Option<i64>,
+}
+
+/// The result of a correct username and password.
+pub enum LoginResult {
+    Token(String),
+    /// The user has two-factor authentication enabled, and has to exchange this challenge token and a code for a token.
+    TwoFactorChallenge(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
     /// In base64.
     pub password: String,
+    /// Regenerated whenever the user's credentials change.  Tokens are only accepted while they carry the current key.
+    pub token_key: String,
+    pub suspension: Option<Suspension>,
+}
+
+impl User {
+    /// Returns an error if the user is currently suspended.
+    pub fn check_not_suspended(&self) -> Result<(), SuspendedError> {
+        match &self.suspension {
+            Some(suspension)
+                if suspension
+                    .until
+                    .map_or(true, |until| until > Utc::now().timestamp()) =>
+            {
+                Err(SuspendedError {
+                    reason: suspension.reason.clone(),
+                    until: suspension.until,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The layout users were stored in before token keys were added.
+#[derive(Deserialize)]
+struct UserV0 {
+    username: String,
+    password: String,
 }
 
-/// Creates a new user and stores it given a set of [`Credentials`].
+impl From<UserV0> for User {
+    fn from(user: UserV0) -> Self {
+        User {
+            username: user.username,
+            password: user.password,
+            token_key: nanoid!(11),
+            suspension: None,
+        }
+    }
+}
+
+/// Creates a new user and stores it given a set of [`Credentials`], if they follow the account policy and the
+/// registration mode allows it.
 pub fn add_user(
-    credentials: Credentials,
+    mut credentials: Credentials,
+    registration: RegistrationMode,
     config: &UserDataBaseConfig,
+    policy: &AccountPolicyConfig,
     db: &Database,
 ) -> anyhow::Result<()> {
-    let user = db.users.get(&credentials.username)?;
+    if registration == RegistrationMode::Closed {
+        Err(RegistrationError::Closed)?
+    }
 
-    if user.is_some() {
-        Err(anyhow::format_err!("User already exists!"))
-    } else {
+    credentials.username = normalize_username(&credentials.username);
+    validate_username(&credentials.username, policy)?;
+    validate_password(&credentials.password, policy)?;
+
+    // The name is claimed before anything else is stored, so two registrations differing only in case can't both
+    // succeed.
+    let key = username_key(&credentials.username);
+    if db
+        .usernames
+        .compare_and_swap(key.as_bytes(), None, Some(credentials.username.clone()))?
+        .is_err()
+    {
+        Err(PolicyError::UsernameTaken)?
+    }
+
+    // Redeemed last, so a rejected registration doesn't use up the invite.
+    if registration == RegistrationMode::InviteOnly {
+        let invite_code = credentials.invite_code.as_deref().unwrap_or_default();
+        if let Err(err) = redeem_invite(invite_code, db) {
+            db.usernames.remove(key.as_bytes())?;
+            return Err(err);
+        }
+    }
+
+    let user =
         store_credentials(credentials, config, db).context("Failed to store credentials.")?;
-        Ok(())
+    profile::create_profile(&user.username, db)?;
+
+    Ok(())
+}
+
+/// Creates a user who logs in through an external identity provider, and so has no password.  Fails if the name, or
+/// one differing from it only in case, is taken.
+pub fn add_external_user(username: &str, db: &Database) -> anyhow::Result<User> {
+    // Claimed first, so two logins can't both be given the same name.
+    if db
+        .usernames
+        .compare_and_swap(
+            username_key(username).as_bytes(),
+            None,
+            Some(username.to_string()),
+        )?
+        .is_err()
+    {
+        Err(PolicyError::UsernameTaken)?
     }
+
+    let user = User {
+        username: username.to_string(),
+        // Not valid base64, so no password will ever match it.
+        password: "!".to_string(),
+        token_key: nanoid!(11),
+        suspension: None,
+    };
+
+    db.users.insert(user.username.as_bytes(), user.clone())?;
+    profile::create_profile(&user.username, db)?;
+
+    Ok(user)
+}
+
+/// Finds a user by name, ignoring differences in case and normalization.
+pub fn find_user(username: &str, db: &Database) -> anyhow::Result<Option<User>> {
+    if let Some(username) = db.usernames.get(username_key(username).as_bytes())? {
+        return Ok(db.users.get(username.as_bytes())?);
+    }
+
+    // Users whose name differs only in case from an older user's can only be found by their exact name.
+    Ok(db.users.get(username)?)
+}
+
+/// Indexes the names of users who registered before usernames were indexed, so new users can't register a name that
+/// differs from theirs only in case.  If older users already clash, the first one found keeps the name.
+pub fn index_existing_usernames(db: &Database) -> anyhow::Result<()> {
+    for entry in db.users.iter() {
+        let (_, user) = entry?;
+        // Fails harmlessly if the name is already indexed.
+        let _ = db.usernames.compare_and_swap(
+            username_key(&user.username).as_bytes(),
+            None,
+            Some(user.username.clone()),
+        )?;
+    }
+
+    Ok(())
 }
 
-/// Stores a set of [`Credentials`].
+/// Stores a set of [`Credentials`].  If the user already exists, any tokens and sessions previously issued to them
+/// are revoked.
 fn store_credentials(
     credentials: Credentials,
     config: &UserDataBaseConfig,
     db: &Database,
+) -> anyhow::Result<User> {
+    let password = hash_password(&credentials.username, &credentials.password, config);
+    // Changing the password doesn't lift a suspension.
+    let suspension = db
+        .users
+        .get(credentials.username.as_bytes())?
+        .and_then(|user| user.suspension);
+    let user = User {
+        username: credentials.username,
+        password,
+        token_key: nanoid!(11),
+        suspension,
+    };
+
+    db.users.insert(user.username.as_bytes(), user.clone())?;
+    session::revoke_all_sessions(&user.username, db)?;
+
+    Ok(user)
+}
+
+/// Signs a user out everywhere, revoking all of their tokens and sessions.
+pub fn revoke_tokens(username: &str, db: &Database) -> anyhow::Result<()> {
+    let mut user = db
+        .users
+        .get(username)?
+        .ok_or(VerifyError::IncorrectUsernameOrPassword)?;
+    user.token_key = nanoid!(11);
+
+    db.users.insert(user.username.as_bytes(), user.clone())?;
+    session::revoke_all_sessions(&user.username, db)?;
+
+    Ok(())
+}
+
+/// Suspends a user, stopping them from logging in or using any existing tokens until reinstated.
+pub fn suspend_user(
+    username: &str,
+    request: SuspensionRequest,
+    suspended_by: &str,
+    db: &Database,
 ) -> anyhow::Result<()> {
-    let salt = salt(credentials.username.as_str(), config);
-    let mut hashed_credential: HashedCredential = [0u8; CREDENTIAL_LEN];
-    pbkdf2::derive(
-        PBKDF2_ALG,
-        config.pbkdf2_iterations,
-        &salt,
-        credentials.password.as_bytes(),
-        &mut hashed_credential,
-    );
+    let mut user = find_user(username, db)?.ok_or(AccountError::UnknownUser)?;
+    user.suspension = Some(Suspension {
+        reason: request.reason,
+        until: request.until,
+        suspended_by: suspended_by.to_string(),
+    });
 
-    db.users.insert(
-        credentials.username.clone().as_bytes(),
-        User {
-            username: credentials.username,
-            password: base64::encode(hashed_credential),
-        },
-    )?;
+    db.users.insert(user.username.as_bytes(), user)?;
 
     Ok(())
 }
 
+/// Lifts a user's suspension.
+pub fn reinstate_user(username: &str, db: &Database) -> anyhow::Result<()> {
+    let mut user = find_user(username, db)?.ok_or(AccountError::UnknownUser)?;
+    user.suspension = None;
+
+    db.users.insert(user.username.as_bytes(), user)?;
+
+    Ok(())
+}
+
+/// Whether a user exists and is currently suspended.
+pub fn is_suspended(username: &str, db: &Database) -> anyhow::Result<bool> {
+    Ok(matches!(db.users.get(username)?, Some(user) if user.check_not_suspended().is_err()))
+}
+
 /// Verifies a user given a set of [`Credentials`].
 pub fn verify_user(
     credentials: Credentials,
+    device: &Device,
     config: &UserDataBaseConfig,
     db: &Database,
-) -> anyhow::Result<String> {
-    let attempt_pw = &credentials.password;
-    let user = db.users.get(&credentials.username)?;
+) -> anyhow::Result<LoginResult> {
+    let user = find_user(&credentials.username, db)?;
 
     if let Some(user) = user {
-        if let Ok(actual_pw_hash) = base64::decode(user.password.clone()) {
-            let salt = salt(credentials.username.as_str(), config);
+        if check_password(&user, &credentials.password, config) {
+            return start_login(&user, device, db);
+        }
+    }
+
+    // Return an error otherwise.
+    Err(VerifyError::IncorrectUsernameOrPassword)?
+}
+
+/// Logs in a user who has proven who they are, either with a token or, if they have two-factor authentication
+/// enabled, a challenge to exchange along with a code.
+pub fn start_login(user: &User, device: &Device, db: &Database) -> anyhow::Result<LoginResult> {
+    if two_factor::is_enabled(&user.username, db)? {
+        return Ok(LoginResult::TwoFactorChallenge(create_challenge_jwt(user)?));
+    }
+
+    Ok(LoginResult::Token(create_jwt(user, device, db)?))
+}
+
+/// Finishes logging in a user who passed [`verify_user`] with a challenge, given a two-factor code.
+pub fn verify_two_factor(
+    username: &str,
+    code: &str,
+    device: &Device,
+    db: &Database,
+) -> anyhow::Result<String> {
+    let user = db
+        .users
+        .get(username)?
+        .ok_or(VerifyError::IncorrectUsernameOrPassword)?;
+
+    two_factor::verify_code(&user.username, code, db)?;
+
+    create_jwt(&user, device, db)
+}
+
+/// Changes a user's password given their current one, revoking all of their existing tokens.  Returns a new token.
+pub fn change_password(
+    username: &str,
+    change: PasswordChange,
+    device: &Device,
+    config: &UserDataBaseConfig,
+    policy: &AccountPolicyConfig,
+    db: &Database,
+) -> anyhow::Result<String> {
+    let user = db
+        .users
+        .get(username)?
+        .ok_or(VerifyError::IncorrectUsernameOrPassword)?;
+
+    if !check_password(&user, &change.current_password, config) {
+        Err(AccountError::IncorrectPassword)?
+    }
+
+    validate_password(&change.new_password, policy)?;
+
+    let user = store_credentials(
+        Credentials {
+            username: user.username,
+            password: change.new_password,
+            invite_code: None,
+        },
+        config,
+        db,
+    )?;
+
+    create_jwt(&user, device, db)
+}
+
+/// Issues a code that resets a user's password once, returning it and when it expires.
+pub fn create_password_reset(
+    request: PasswordResetRequest,
+    issued_by: &str,
+    db: &Database,
+) -> anyhow::Result<(String, i64)> {
+    let user = find_user(&request.username, db)?.ok_or(AccountError::UnknownUser)?;
+    let now = Utc::now().timestamp();
+
+    // Clean up any codes that expired unused.
+    for entry in db.password_resets.iter() {
+        let (key, reset_code) = entry?;
+        if reset_code.expires < now {
+            db.password_resets.remove(key)?;
+        }
+    }
+
+    let minutes = request
+        .expires_in_minutes
+        .unwrap_or(PASSWORD_RESET_DEFAULT_MINUTES)
+        .max(1)
+        .min(PASSWORD_RESET_MAX_MINUTES);
+    let code = nanoid!(16, &consts::READABLE_CODE_ALPHABET);
+    let expires = now + minutes * 60;
+
+    db.password_resets.insert(
+        hash_reset_code(&code).as_bytes(),
+        PasswordResetCode {
+            username: user.username,
+            expires,
+            issued_by: issued_by.to_string(),
+        },
+    )?;
+
+    Ok((code, expires))
+}
+
+/// Sets a new password given a code from [`create_password_reset`], revoking all of the user's existing tokens.
+pub fn reset_password(
+    reset: PasswordReset,
+    config: &UserDataBaseConfig,
+    policy: &AccountPolicyConfig,
+    db: &Database,
+) -> anyhow::Result<()> {
+    // Check this first, so a rejected password doesn't use up the code.
+    validate_password(&reset.new_password, policy)?;
+
+    let reset_code = db
+        .password_resets
+        .remove(hash_reset_code(&reset.code.trim().to_lowercase()).as_bytes())?
+        .ok_or(AccountError::InvalidResetCode)?;
+
+    if reset_code.expires < Utc::now().timestamp() {
+        Err(AccountError::InvalidResetCode)?
+    }
+
+    let user = db
+        .users
+        .get(reset_code.username.as_bytes())?
+        .ok_or(AccountError::InvalidResetCode)?;
+
+    store_credentials(
+        Credentials {
+            username: user.username,
+            password: reset.new_password,
+            invite_code: None,
+        },
+        config,
+        db,
+    )?;
+
+    Ok(())
+}
+
+/// Deletes a user, and either deletes their images or reassigns them to another user.
+pub async fn delete_user(
+    username: &str,
+    deletion: AccountDeletion,
+    config: &UserDataBaseConfig,
+    db: &Database,
+    s3_client: &S3Client,
+) -> anyhow::Result<()> {
+    let user = db
+        .users
+        .get(username)?
+        .ok_or(VerifyError::IncorrectUsernameOrPassword)?;
+
+    if !check_password(&user, &deletion.password, config) {
+        Err(AccountError::IncorrectPassword)?
+    }
+
+    // Revoked before the images are deleted or reassigned, so links made by this user stop working either way.
+    share::delete_user_shares(username, db)?;
+
+    if deletion.delete_images {
+        images::delete_user_images(username, db, s3_client).await?;
+    } else if let Some(reassign_to) = deletion.reassign_to {
+        match find_user(&reassign_to, db)? {
+            Some(target) if target.username != username => {
+                images::reassign_user_images(username, &target.username, db)?;
+            }
+            _ => Err(AccountError::UnknownReassignTarget)?,
+        }
+    } else {
+        Err(AccountError::NoImageDisposition)?
+    }
+
+    db.users.remove(username)?;
+    db.usernames.remove(username_key(username).as_bytes())?;
+    db.two_factor.remove(username.as_bytes())?;
+    oidc::delete_user_links(username, db)?;
+    throttle::clear_failures(username, db)?;
+    album::delete_user_albums(username, db)?;
+    discount::delete_user_discounts(username, db)?;
+    favorite::delete_user_favorites(username, db)?;
+    feed::delete_user_follows(username, db)?;
+    profile::delete_profile(username, db)?;
+    quota::delete_usage(username, db)?;
+    session::revoke_all_sessions(username, db)?;
+
+    Ok(())
+}
+
+/// Rewrites users stored in the [`UserV0`] layout in the current one.  Users already in the current layout are left
+/// alone.
+pub fn upgrade_v0_users(sled: &sled_extensions::Db, db: &Database) -> anyhow::Result<()> {
+    let legacy_users: Tree<UserV0> = sled.open_bincode_tree("users")?;
+    for key in sled.open_tree("users")?.iter().keys() {
+        let key = key?;
+        if db.users.get(&key).is_err() {
+            if let Some(user) = legacy_users.get(&key)? {
+                db.users.insert(&key, User::from(user))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks whether a password matches the one stored for a [`User`].
+fn check_password(user: &User, password: &str, config: &UserDataBaseConfig) -> bool {
+    match base64::decode(user.password.clone()) {
+        Ok(actual_pw_hash) => {
+            let salt = salt(user.username.as_str(), config);
             pbkdf2::verify(
                 PBKDF2_ALG,
                 config.pbkdf2_iterations,
                 &salt,
-                attempt_pw.as_bytes(),
+                password.as_bytes(),
                 &actual_pw_hash,
             )
-            .map_err(|_| VerifyError::IncorrectUsernameOrPassword)?;
+            .is_ok()
         }
-
-        return Ok(create_jwt(&user.username)?);
+        Err(_) => false,
     }
+}
 
-    // Return an error otherwise.
-    Err(VerifyError::IncorrectUsernameOrPassword)?
+/// Hashes a password, returning it in base64.
+fn hash_password(username: &str, password: &str, config: &UserDataBaseConfig) -> String {
+    let salt = salt(username, config);
+    let mut hashed_credential: HashedCredential = [0u8; CREDENTIAL_LEN];
+    pbkdf2::derive(
+        PBKDF2_ALG,
+        config.pbkdf2_iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hashed_credential,
+    );
+
+    base64::encode(hashed_credential)
+}
+
+fn hash_reset_code(code: &str) -> String {
+    base64::encode(digest::digest(&digest::SHA256, code.as_bytes()))
 }
 
 /// Returns a salt given a username.
@@ -0,0 +1,146 @@
+//! Downscaled, watermarked previews, shown in place of images whose originals only some users are allowed to see.
+
+use anyhow::Context;
+use img_hash::image::{
+    self, imageops::FilterType, DynamicImage, ImageOutputFormat, Rgba, RgbaImage,
+};
+use rusttype::{point, Font, Scale};
+
+use crate::config::{WatermarkConfig, WatermarkPosition};
+
+pub struct Watermark {
+    overlay: Option<Overlay>,
+    position: WatermarkPosition,
+    opacity: f32,
+    max_dimension: u32,
+}
+
+enum Overlay {
+    Text { text: String, font: Font<'static> },
+    Logo(DynamicImage),
+}
+
+impl Watermark {
+    pub fn from_config(config: &WatermarkConfig) -> anyhow::Result<Watermark> {
+        let overlay = match (&config.logo_path, &config.text, &config.font_path) {
+            (Some(logo_path), _, _) => Some(Overlay::Logo(
+                image::open(logo_path).context("Could not read watermark logo.")?,
+            )),
+            (None, Some(text), Some(font_path)) => {
+                let font = std::fs::read(font_path).context("Could not read watermark font.")?;
+                Some(Overlay::Text {
+                    text: text.clone(),
+                    font: Font::try_from_vec(font)
+                        .ok_or_else(|| anyhow::format_err!("Invalid watermark font."))?,
+                })
+            }
+            (None, Some(_), None) => Err(anyhow::format_err!(
+                "A watermark font path is needed to draw watermark text."
+            ))?,
+            (None, None, _) => None,
+        };
+
+        Ok(Watermark {
+            overlay,
+            position: config.position,
+            opacity: config.opacity.max(0.0).min(1.0),
+            max_dimension: config.max_dimension,
+        })
+    }
+
+    /// Downscales an image and draws the watermark over it, encoded as a JPEG.
+    pub fn preview_jpeg(&self, image: &DynamicImage) -> anyhow::Result<Vec<u8>> {
+        let mut preview = image
+            .thumbnail(self.max_dimension, self.max_dimension)
+            .into_rgba8();
+
+        if let Some(overlay) = self.render_overlay(preview.width(), preview.height()) {
+            self.blend(&mut preview, &overlay);
+        }
+
+        let mut bytes = vec![];
+        // JPEGs can't have transparency, so the alpha channel is dropped first.
+        let preview = DynamicImage::ImageRgba8(preview).into_rgb8();
+        DynamicImage::ImageRgb8(preview).write_to(&mut bytes, ImageOutputFormat::Jpeg(85))?;
+
+        Ok(bytes)
+    }
+
+    /// Draws the overlay at a size that suits a preview of the given size.
+    fn render_overlay(&self, width: u32, height: u32) -> Option<RgbaImage> {
+        match self.overlay.as_ref()? {
+            Overlay::Logo(logo) => Some(
+                logo.resize(width / 4, height / 4, FilterType::Triangle)
+                    .into_rgba8(),
+            ),
+            Overlay::Text { text, font } => {
+                let scale = Scale::uniform((width.min(height) as f32 / 12.0).max(12.0));
+                let v_metrics = font.v_metrics(scale);
+                let glyphs: Vec<_> = font
+                    .layout(text, scale, point(0.0, v_metrics.ascent))
+                    .collect();
+
+                let text_width = glyphs
+                    .iter()
+                    .filter_map(|glyph| glyph.pixel_bounding_box())
+                    .map(|bounding_box| bounding_box.max.x)
+                    .max()?;
+                let text_height = (v_metrics.ascent - v_metrics.descent).ceil() as i32;
+                let mut overlay =
+                    RgbaImage::new(text_width.max(1) as u32, text_height.max(1) as u32);
+
+                for glyph in glyphs {
+                    if let Some(bounding_box) = glyph.pixel_bounding_box() {
+                        glyph.draw(|x, y, coverage| {
+                            let x = x as i32 + bounding_box.min.x;
+                            let y = y as i32 + bounding_box.min.y;
+                            if x >= 0
+                                && y >= 0
+                                && (x as u32) < overlay.width()
+                                && (y as u32) < overlay.height()
+                            {
+                                overlay.put_pixel(
+                                    x as u32,
+                                    y as u32,
+                                    Rgba([255, 255, 255, (coverage * 255.0) as u8]),
+                                );
+                            }
+                        });
+                    }
+                }
+
+                Some(overlay)
+            }
+        }
+    }
+
+    /// Draws the overlay onto the preview at the configured position and opacity.
+    fn blend(&self, preview: &mut RgbaImage, overlay: &RgbaImage) {
+        let margin = preview.width().min(preview.height()) / 40;
+        let max_x = preview.width().saturating_sub(overlay.width());
+        let max_y = preview.height().saturating_sub(overlay.height());
+
+        let (left, top) = match self.position {
+            WatermarkPosition::TopLeft => (margin, margin),
+            WatermarkPosition::TopRight => (max_x.saturating_sub(margin), margin),
+            WatermarkPosition::BottomLeft => (margin, max_y.saturating_sub(margin)),
+            WatermarkPosition::BottomRight => {
+                (max_x.saturating_sub(margin), max_y.saturating_sub(margin))
+            }
+            WatermarkPosition::Center => (max_x / 2, max_y / 2),
+        };
+
+        for (x, y, pixel) in overlay.enumerate_pixels() {
+            let (x, y) = (left + x, top + y);
+            if x >= preview.width() || y >= preview.height() {
+                continue;
+            }
+
+            let alpha = pixel[3] as f32 / 255.0 * self.opacity;
+            let target = preview.get_pixel_mut(x, y);
+            for (target, source) in target.0.iter_mut().zip(pixel.0.iter()).take(3) {
+                *target = (*source as f32 * alpha + *target as f32 * (1.0 - alpha)).round() as u8;
+            }
+        }
+    }
+}
@@ -0,0 +1,21 @@
+//! Routes under `/.well-known`, for other services to discover how to work with foto.
+
+use rocket_contrib::json::JsonValue;
+
+use crate::{consts, jwt_keys::JwtKey};
+
+/// Publishes the public keys used to sign JWTs, so other services can verify tokens without sharing a secret.
+#[get("/.well-known/jwks.json")]
+pub fn jwks() -> JsonValue {
+    key_set(consts::JWT_KEYS.values())
+}
+
+/// A JSON Web Key Set of the public keys among `keys`.
+pub fn key_set<'a>(keys: impl IntoIterator<Item = &'a JwtKey>) -> JsonValue {
+    let keys: Vec<JsonValue> = keys
+        .into_iter()
+        .filter_map(|key| key.public_jwk())
+        .collect();
+
+    json!({ "keys": keys })
+}